@@ -5,9 +5,9 @@
 //! LLM Weaver facilitates extended interactions with any LLM, seamlessly handling conversations
 //! that exceed a model's maximum context token limitation.
 //!
-//! [`Loom`] is the core of this library. It prompts the configured LLM and stores the message
-//! history as [`TapestryFragment`] instances. This trait is highly configurable through the
-//! [`Config`] trait to support a wide range of use cases.
+//! [`Loom`](crate::loom::Loom) is the core of this library. It prompts the configured LLM and
+//! stores the message history as [`TapestryFragment`] instances. This trait is highly configurable
+//! through the [`Config`] trait to support a wide range of use cases.
 //!
 //! # Nomenclature
 //!
@@ -27,7 +27,7 @@
 //! # Usage
 //!
 //! You must implement the [`Config`] trait, which defines the necessary types and methods needed by
-//! [`Loom`].
+//! [`Loom`](crate::loom::Loom).
 //!
 //! This library uses Redis as the default storage backend for storing [`TapestryFragment`]. It is
 //! expected that a Redis instance is running and that the following environment variables are set:
@@ -40,22 +40,19 @@
 //! Should there be a need to integrate a distinct storage backend, you have the flexibility to
 //! create a custom handler by implementing the [`TapestryChestHandler`] trait and injecting it
 //! into the [`Config::Chest`] associated type.
-#![feature(async_closure)]
-#![feature(associated_type_defaults)]
-#![feature(more_qualified_paths)]
-#![feature(const_option)]
-#![feature(anonymous_lifetime_in_impl_trait)]
-#![feature(once_cell_try)]
 
 use std::{
 	error::Error,
 	fmt::{Debug, Display},
 	marker::PhantomData,
+	path::PathBuf,
 	str::FromStr,
+	time::Duration,
 };
 
 use async_trait::async_trait;
 pub use bounded_integer::BoundedU8;
+use chrono::{DateTime, Utc};
 use num_traits::{
 	CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, FromPrimitive, SaturatingAdd, SaturatingMul,
 	SaturatingSub, ToPrimitive, Unsigned,
@@ -65,6 +62,8 @@ use tracing::trace;
 
 pub mod architecture;
 pub mod loom;
+pub mod memory;
+pub mod request;
 pub mod storage;
 pub mod types;
 
@@ -74,7 +73,10 @@ mod mock;
 mod tests;
 
 pub use storage::TapestryChestHandler;
-use types::{LoomError, SummaryModelTokens};
+use types::{
+	ContextStrategy, GenrePreset, LoomError, ModelCapabilities, SamplingParameters,
+	SummaryModelTokens, ToolCall, ToolDefinition,
+};
 
 use crate::types::{PromptModelTokens, WrapperRole};
 
@@ -83,8 +85,8 @@ pub type Result<T, U> = std::result::Result<T, LoomError<U>>;
 /// Represents a unique identifier for any arbitrary entity.
 ///
 /// This trait provides a method for generating a standardized key, which can be utilized across
-/// various implementations in the library, such as the [`TapestryChest`] implementation for storing
-/// keys in redis using the `base_key` method.
+/// various implementations in the library, such as the [`TapestryChestHandler`] implementations for
+/// storing keys in redis using the `base_key` method.
 ///
 /// ```ignore
 /// use loom::{TapestryId, Get};
@@ -116,14 +118,41 @@ pub struct LlmConfig<T: Config, L: Llm<T>> {
 	pub params: L::Parameters,
 }
 
+impl<T: Config, L: Llm<T>> LlmConfig<T, L> {
+	/// Ensures the `model` supports every capability required by the `params`.
+	pub fn check_capabilities(&self) -> Result<(), T> {
+		match self
+			.model
+			.capabilities()
+			.first_missing(&self.model.required_capabilities(&self.params))
+		{
+			Some(capability) =>
+				Err(LoomError::UnsupportedCapability { model: self.model.name(), capability }),
+			None => Ok(()),
+		}
+	}
+}
+
 #[async_trait]
 pub trait Llm<T: Config>:
 	Default + Sized + PartialEq + Eq + Clone + Debug + Copy + Send + Sync
 {
-	/// Token to word ratio.
+	/// Number of words per 100 tokens, used to convert tokens to words and back.
+	///
+	/// A word count is the token count multiplied by this ratio, and a token count is the word
+	/// count divided by it. The default is an English average, languages such as Chinese or
+	/// Japanese hold far fewer words per token and should lower it.
 	///
 	/// Defaults to `75%`
 	const TOKEN_WORD_RATIO: BoundedU8<0, 100> = BoundedU8::new(75).unwrap();
+	/// Average number of words in a sentence.
+	///
+	/// Defaults to `20`
+	const WORDS_PER_SENTENCE: u8 = 20;
+	/// Maximum number of stop sequences accepted by the LLM.
+	///
+	/// Defaults to `4`
+	const MAX_STOP_SEQUENCES: usize = 4;
 
 	/// Tokens are an LLM concept which represents pieces of words. For example, each ChatGPT token
 	/// represents roughly 75% of a word.
@@ -160,18 +189,22 @@ pub trait Llm<T: Config>:
 	/// Type representing the prompt request.
 	type Request: Clone + From<ContextMessage<T>> + Display + Send;
 	/// Type representing the response to a prompt.
+	///
+	/// Converts to `None` when the LLM returned no content, e.g. no choice at all after a content
+	/// filter refusal, which fails with [`LoomError::EmptyResponse`] unless it holds a
+	/// [`Llm::tool_call`].
 	type Response: Clone + Into<Option<String>> + Send;
 	/// Type representing the parameters for a prompt.
 	type Parameters: Debug + Clone + Send + Sync;
-	type PromptError: Error;
+	type PromptError: Error + Send + 'static;
 
 	/// The maximum number of tokens that can be processed at once by an LLM model.
 	fn max_context_length(&self) -> Self::Tokens;
 	/// Get the model name.
 	///
 	/// This is used for logging purposes but also can be used to fetch a specific model based on
-	/// `&self`. For example, the model passed to [`Loom::weave`] can be represented as an enum with
-	/// a multitude of variants, each representing a different model.
+	/// `&self`. For example, the model passed to [`Loom::weave`](crate::loom::Loom::weave) can be
+	/// represented as an enum with a multitude of variants, each representing a different model.
 	fn name(&self) -> &'static str;
 	/// Alias for the model.
 	///
@@ -180,6 +213,10 @@ pub trait Llm<T: Config>:
 	/// Calculates the number of tokens in a string.
 	///
 	/// This may vary depending on the type of tokens used by the LLM. In the case of ChatGPT, can be calculated using the [tiktoken-rs](https://github.com/zurawiki/tiktoken-rs#counting-token-length) crate.
+	///
+	/// Make sure the encoding matches the model: `gpt-3.5-turbo` and `gpt-4` use `cl100k_base`,
+	/// not the `p50k_base` of older completion models. Since this function does not take `&self`,
+	/// models with different encodings should be represented by different [`Llm`] types.
 	fn count_tokens(content: &str) -> Result<Self::Tokens, T>;
 	/// Prompt LLM with the supplied messages and parameters.
 	async fn prompt(
@@ -190,6 +227,147 @@ pub trait Llm<T: Config>:
 		params: &Self::Parameters,
 		max_tokens: Self::Tokens,
 	) -> Result<Self::Response, T>;
+	/// Prompt LLM like [`Llm::prompt`], passing each delta of the response to `on_delta` as it
+	/// arrives.
+	///
+	/// The deltas passed before an error are treated as a partial response by
+	/// [`Loom::weave_streaming`](crate::loom::Loom::weave_streaming).
+	///
+	/// Defaults to [`Llm::prompt`], passing the whole response as a single delta.
+	async fn prompt_stream(
+		&self,
+		is_summarizing: bool,
+		prompt_tokens: Self::Tokens,
+		msgs: Vec<Self::Request>,
+		params: &Self::Parameters,
+		max_tokens: Self::Tokens,
+		on_delta: &mut (dyn for<'a> FnMut(&'a str) + Send),
+	) -> Result<Self::Response, T> {
+		let response = self.prompt(is_summarizing, prompt_tokens, msgs, params, max_tokens).await?;
+		if let Some(content) = response.clone().into() {
+			on_delta(&content);
+		}
+		Ok(response)
+	}
+	/// Check that the LLM is reachable with its configured endpoint and credentials through a
+	/// cheap request, e.g. listing the models of its API.
+	///
+	/// Used by [`Loom::check_connection`](crate::loom::Loom::check_connection). Defaults to
+	/// `Ok(())`, checking nothing.
+	async fn check_connection(&self) -> Result<(), T> {
+		Ok(())
+	}
+	/// Whether the response was cut off because it reached the maximum completion tokens.
+	///
+	/// Truncated responses are automatically continued up to [`Config::MAX_CONTINUATIONS`] times.
+	///
+	/// Defaults to `false`
+	fn is_truncated(&self, _response: &Self::Response) -> bool {
+		false
+	}
+	/// Stitch the `continuation` of a truncated response onto the `response`.
+	///
+	/// Should be implemented alongside [`Llm::is_truncated`]. Defaults to returning the
+	/// `continuation`.
+	fn append_response(
+		&self,
+		_response: Self::Response,
+		continuation: Self::Response,
+	) -> Self::Response {
+		continuation
+	}
+	/// Set the stop sequences of the `params` used to prompt the LLM.
+	///
+	/// Called with the [`Config::STOP_SEQUENCES`] followed by the speaker prefixes derived by
+	/// [`Loom::speaker_stop_sequences`](crate::loom::Loom::speaker_stop_sequences) when
+	/// [`Config::STOP_AT_SPEAKERS`] is enabled.
+	///
+	/// Defaults to leaving `params` untouched.
+	fn set_stop_sequences(&self, _params: &mut Self::Parameters, _stop: Vec<String>) {}
+	/// Enable JSON mode on the `params` used to prompt the LLM, constraining responses to valid
+	/// JSON.
+	///
+	/// Only called if the [`ModelCapabilities::json_mode`] of [`Llm::capabilities`] is supported.
+	///
+	/// Defaults to leaving `params` untouched.
+	fn set_json_mode(&self, _params: &mut Self::Parameters) {}
+	/// How long to wait before prompting again if `error` signals a rate limit, e.g. from a
+	/// `Retry-After` header.
+	///
+	/// See [`Config::MAX_RATE_LIMIT_RETRIES`]. Defaults to `None`
+	fn retry_after(&self, _error: &LoomError<T>) -> Option<Duration> {
+		None
+	}
+	/// Whether `error` is a transient failure, such as a network error or a server error, worth
+	/// retrying.
+	///
+	/// See [`Config::MAX_TRANSIENT_RETRIES`]. Defaults to `false`
+	fn is_transient(&self, _error: &LoomError<T>) -> bool {
+		false
+	}
+	/// Set the tools the LLM can call on the `params` used to prompt the LLM.
+	///
+	/// Only called by [`Loom::prompt_with_tools`](crate::loom::Loom::prompt_with_tools) if the
+	/// [`ModelCapabilities::tools`] of [`Llm::capabilities`] is supported.
+	///
+	/// Defaults to leaving `params` untouched.
+	fn set_tools(&self, _params: &mut Self::Parameters, _tools: &[ToolDefinition]) {}
+	/// The tool call requested by a response, if any.
+	///
+	/// Defaults to `None`
+	fn tool_call(&self, _response: &Self::Response) -> Option<ToolCall> {
+		None
+	}
+	/// Set the number of candidate completions `n` requested by the `params` used to prompt the
+	/// LLM.
+	///
+	/// Only called by [`Loom::weave_candidates`](crate::loom::Loom::weave_candidates). Defaults to
+	/// leaving `params` untouched, in which case a single candidate is returned.
+	fn set_candidates(&self, _params: &mut Self::Parameters, _n: u8) {}
+	/// Every candidate completion of a response, e.g. the content of each choice.
+	///
+	/// Defaults to the content of the response.
+	fn candidates(&self, response: &Self::Response) -> Vec<String> {
+		response.clone().into().into_iter().collect()
+	}
+	/// Set the sampling parameters of the `params` used to prompt the LLM.
+	///
+	/// Called with the [`Config::SAMPLING_PARAMETERS`], or else the [`GenrePreset::sampling`] of
+	/// the [`Config::GENRE_PRESET`].
+	///
+	/// Defaults to leaving `params` untouched.
+	fn set_sampling(&self, _params: &mut Self::Parameters, _sampling: SamplingParameters) {}
+	/// Replace the content of a response.
+	///
+	/// Used to strip out of character sentences when [`Config::STAY_IN_CHARACTER`] is enabled.
+	/// Defaults to returning the `response` unchanged.
+	fn set_response_content(&self, response: Self::Response, _content: String) -> Self::Response {
+		response
+	}
+	/// The fingerprint of the backend configuration which generated a response, e.g. OpenAI's
+	/// `system_fingerprint`, to verify the reproducibility of seeded prompts.
+	///
+	/// Defaults to `None`
+	fn system_fingerprint(&self, _response: &Self::Response) -> Option<String> {
+		None
+	}
+	/// Optional features supported by the model.
+	///
+	/// Defaults to none.
+	fn capabilities(&self) -> ModelCapabilities {
+		ModelCapabilities::default()
+	}
+	/// Optional features required by `params`, e.g. an image input, a JSON response format or
+	/// log probabilities, which are then available in the [`Llm::Response`] returned by
+	/// [`Loom::weave`](crate::loom::Loom::weave).
+	///
+	/// Prompts requiring capabilities missing from [`Llm::capabilities`] fail with
+	/// [`LoomError::UnsupportedCapability`] before the LLM is prompted.
+	///
+	/// Defaults to none.
+	fn required_capabilities(&self, _params: &Self::Parameters) -> ModelCapabilities {
+		ModelCapabilities::default()
+	}
 	/// Compute cost of a message based on model.
 	fn compute_cost(&self, prompt_tokens: Self::Tokens, response_tokens: Self::Tokens) -> f64;
 	/// Calculate the upperbound of tokens allowed for the current [`Config::PromptModel`] before a
@@ -200,12 +378,13 @@ pub trait Llm<T: Config>:
 	fn get_max_prompt_token_limit(&self) -> Self::Tokens {
 		let max_context_length = self.max_context_length();
 		let token_threshold = Self::Tokens::from_u8(T::TOKEN_THRESHOLD_PERCENTILE.get()).unwrap();
-		let tokens = match max_context_length.checked_mul(&token_threshold) {
-			Some(tokens) => tokens,
-			None => max_context_length,
-		};
+		let hundred = Self::Tokens::from_u8(100).unwrap();
 
-		tokens.checked_div(&Self::Tokens::from_u8(100).unwrap()).unwrap()
+		match max_context_length.checked_mul(&token_threshold) {
+			Some(tokens) => tokens / hundred,
+			// Divide first to avoid overflowing `Self::Tokens`
+			None => (max_context_length / hundred).saturating_mul(&token_threshold),
+		}
 	}
 	/// Get optional max completion token limit.
 	fn get_max_completion_token_limit(&self) -> Option<Self::Tokens> {
@@ -215,16 +394,27 @@ pub trait Llm<T: Config>:
 	fn ctx_msgs_to_prompt_requests(&self, msgs: &[ContextMessage<T>]) -> Vec<Self::Request> {
 		msgs.iter().map(|m| m.clone().into()).collect()
 	}
-	/// Convert tokens to words.
+	/// Convert tokens to words, multiplying `tokens` by the [`Llm::TOKEN_WORD_RATIO`].
 	///
 	/// In the case of ChatGPT, each token represents roughly 75% of a word.
 	fn convert_tokens_to_words(&self, tokens: Self::Tokens) -> Self::Tokens {
 		tokens.saturating_mul(&Self::Tokens::from_u8(Self::TOKEN_WORD_RATIO.get()).unwrap()) /
 			Self::Tokens::from_u8(100).unwrap()
 	}
+	/// Convert a number of sentences to tokens.
+	///
+	/// Each sentence is assumed to hold [`Llm::WORDS_PER_SENTENCE`] words, which are then
+	/// converted to tokens using [`Llm::TOKEN_WORD_RATIO`]. Saturates at the
+	/// [`Llm::max_context_length`] if the result does not fit in [`Llm::Tokens`].
+	fn convert_sentences_to_tokens(&self, sentences: u64) -> Self::Tokens {
+		let words = sentences.saturating_mul(Self::WORDS_PER_SENTENCE as u64);
+		let tokens = words.saturating_mul(100) / (Self::TOKEN_WORD_RATIO.get().max(1) as u64);
+
+		Self::Tokens::from_u64(tokens).unwrap_or_else(|| self.max_context_length())
+	}
 }
 
-/// A trait consisting of the main configuration needed to implement [`Loom`].
+/// A trait consisting of the main configuration needed to implement [`Loom`](crate::loom::Loom).
 #[async_trait]
 pub trait Config: Debug + Sized + Clone + Default + Send + Sync + 'static {
 	/// Number between 0 and 100. Represents the percentile of the maximum number of tokens allowed
@@ -232,11 +422,264 @@ pub trait Config: Debug + Sized + Clone + Default + Send + Sync + 'static {
 	///
 	/// Defaults to `85%`
 	const TOKEN_THRESHOLD_PERCENTILE: BoundedU8<0, 100> = BoundedU8::new(85).unwrap();
+	/// Maximum number of [`Config::PromptModel`] tokens of a generated summary.
+	///
+	/// Summaries are always bounded by the tokens left above the
+	/// [`Config::TOKEN_THRESHOLD_PERCENTILE`], which this can only lower, e.g. to `500` to keep
+	/// room for the story following a summary. A summary exceeding its bound fails with
+	/// [`LoomError::SummaryExceedsBudget`].
+	///
+	/// Defaults to `None`
+	const SUMMARY_TARGET_TOKENS: Option<u64> = None;
+	/// System prompt sent before the messages to summarize, telling the LLM what the summary
+	/// should preserve.
+	///
+	/// For a story, something like `"Summarize the story so far. Preserve the unresolved plot
+	/// threads and hooks, the state of each character and where they are."` works well.
+	///
+	/// Defaults to `None`, leaving the instructions to the [`Llm::prompt`] of the
+	/// [`Config::SummaryModel`] when `is_summarizing`.
+	const SUMMARY_INSTRUCTIONS: Option<&'static str> = None;
 	/// Ensures that the maximum completion tokens is at least the minimum response length.
 	///
 	/// If the maximum completion tokens is less than the minimum response length, a summary
 	/// will be generated and a new tapestry fragment will be created.
 	const MINIMUM_RESPONSE_LENGTH: u64;
+	/// Whether to reject the messages passed to [`Loom::weave`](crate::loom::Loom::weave) flagged
+	/// by [`Hooks::moderate`].
+	///
+	/// Flagged messages fail with [`LoomError::Moderated`] before the LLM is prompted or anything
+	/// is persisted. Defaults to `false`
+	const MODERATE_MESSAGES: bool = false;
+	/// How a tapestry fragment exceeding the max prompt tokens makes room for new messages.
+	///
+	/// Messages dropped by [`ContextStrategy::SlidingWindow`] are removed from the current
+	/// tapestry fragment instance a whole user/assistant exchange at a time, so the history never
+	/// starts with an orphaned response. Defaults to [`ContextStrategy::Summarize`]
+	const CONTEXT_STRATEGY: ContextStrategy = ContextStrategy::Summarize;
+	/// Number of past messages of a tapestry most relevant to the new messages which are recalled
+	/// from the [`memory::MemoryStore`] and sent alongside the current tapestry fragment.
+	///
+	/// Messages are only remembered and recalled when [`Hooks::embed`] returns an embedding.
+	/// Defaults to `0`, disabling recall.
+	const MEMORY_RECALL_COUNT: usize = 0;
+	/// Target length of a response expressed as a number of sentences.
+	///
+	/// When set, the LLM is instructed to respond in about this many sentences and the maximum
+	/// completion tokens are capped by [`Llm::convert_sentences_to_tokens`]. Ignored when the
+	/// parameters require [`ModelCapabilities::json_mode`], e.g. by
+	/// [`Loom::weave_json`](crate::loom::Loom::weave_json).
+	///
+	/// Defaults to `None`
+	const RESPONSE_SENTENCES: Option<u64> = None;
+	/// Template of the instruction sent along with the [`Config::RESPONSE_SENTENCES`], where `{}`
+	/// is replaced by the number of sentences, e.g. to phrase it in the language of the story.
+	///
+	/// `None` leaves out the instruction, the response length then only being bounded by the
+	/// maximum completion tokens. Defaults to `Some("Respond in about {} sentences.")`
+	const RESPONSE_LENGTH_INSTRUCTION: Option<&'static str> =
+		Some("Respond in about {} sentences.");
+	/// Maximum number of tokens a single message passed to
+	/// [`Loom::weave`](crate::loom::Loom::weave) can hold.
+	///
+	/// Larger messages are split into multiple consecutive messages using
+	/// [`Loom::split_message`](crate::loom::Loom::split_message) before prompting.
+	///
+	/// Defaults to `None`
+	const MAX_MESSAGE_TOKENS: Option<u64> = None;
+	/// Maximum number of times a truncated response is automatically continued.
+	///
+	/// See [`Llm::is_truncated`]. The continued response is saved as a single message.
+	///
+	/// Defaults to `0`, which disables continuations.
+	const MAX_CONTINUATIONS: u8 = 0;
+	/// Minimum interval between two prompts of the same tapestry.
+	///
+	/// Calling [`Loom::weave`](crate::loom::Loom::weave) sooner fails with [`LoomError::Cooldown`].
+	///
+	/// Defaults to `None`
+	const MIN_PROMPT_INTERVAL: Option<Duration> = None;
+	/// Maximum number of completion tokens generated per tapestry each UTC day.
+	///
+	/// Once reached, [`Loom::weave`](crate::loom::Loom::weave) fails with
+	/// [`LoomError::DailyLimitReached`] until the next UTC midnight.
+	///
+	/// Defaults to `None`
+	const MAX_DAILY_COMPLETION_TOKENS: Option<u64> = None;
+	/// How long the result of [`Loom::weave_idempotent`](crate::loom::Loom::weave_idempotent) is
+	/// returned again for the same idempotency key instead of prompting the LLM.
+	///
+	/// Defaults to 60 seconds
+	const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(60);
+	/// Maximum number of turns, i.e. calls to [`Loom::weave`](crate::loom::Loom::weave), of a
+	/// story.
+	///
+	/// Once reached, [`Loom::weave`](crate::loom::Loom::weave) fails with
+	/// [`LoomError::StoryComplete`].
+	///
+	/// Defaults to `None`
+	const MAX_STORY_TURNS: Option<u64> = None;
+	/// Maximum number of tokens of a story, counting the messages and responses of every turn.
+	///
+	/// Once reached, [`Loom::weave`](crate::loom::Loom::weave) fails with
+	/// [`LoomError::StoryComplete`].
+	///
+	/// Defaults to `None`
+	const MAX_STORY_TOKENS: Option<u64> = None;
+	/// Fraction of the [`Config::MAX_STORY_TURNS`] or [`Config::MAX_STORY_TOKENS`] from which the
+	/// LLM is instructed to begin concluding the story.
+	///
+	/// Defaults to `0.8`
+	const STORY_CONCLUSION_THRESHOLD: f64 = 0.8;
+	/// Size in bytes from which content is counted on a blocking thread by
+	/// [`Loom::count_tokens`](crate::loom::Loom::count_tokens).
+	///
+	/// Defaults to `16 KiB`
+	const BLOCKING_TOKEN_COUNT_THRESHOLD: usize = 16 * 1024;
+	/// Maximum number of times a rate limited prompt is retried once the backoff signaled by
+	/// [`Llm::retry_after`] elapsed.
+	///
+	/// Defaults to `0`, which fails rate limited prompts right away.
+	const MAX_RATE_LIMIT_RETRIES: u8 = 0;
+	/// Maximum number of times a prompt failing with an error deemed transient by
+	/// [`Llm::is_transient`] is retried.
+	///
+	/// Retries are delayed by the [`Config::RETRY_BASE_DELAY`], doubled on each attempt. Defaults
+	/// to `0`, which fails prompts right away.
+	const MAX_TRANSIENT_RETRIES: u8 = 0;
+	/// Delay before the first retry of a transient failure.
+	///
+	/// Defaults to 500 milliseconds
+	const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+	/// Maximum time a single prompt of the LLM can take before failing with
+	/// [`LoomError::Timeout`], which is not retried.
+	///
+	/// The new messages are not persisted when a prompt times out, unless part of a streamed
+	/// response was already received. Defaults to 60 seconds, `None` waits indefinitely.
+	const PROMPT_TIMEOUT: Option<Duration> = Some(Duration::from_secs(60));
+	/// Maximum time to wait for the next delta of a response streamed by
+	/// [`Loom::weave_streaming`](crate::loom::Loom::weave_streaming), including the first one.
+	///
+	/// A stream stalling after some deltas were received fails with
+	/// [`LoomError::PartialTimeout`] holding the partial response, which is persisted like that
+	/// of any interrupted stream. Prompts which are not streamed are only bounded by the
+	/// [`Config::PROMPT_TIMEOUT`]. Defaults to `None`, waiting indefinitely.
+	const STREAM_IDLE_TIMEOUT: Option<Duration> = None;
+	/// Whether the response streamed by
+	/// [`Loom::weave_streaming`](crate::loom::Loom::weave_streaming) is saved as a
+	/// [`StreamCheckpoint`] as each delta arrives.
+	///
+	/// A checkpoint left behind by a stream interrupted before its response was persisted, e.g. by
+	/// a crash, is appended to the tapestry before the next weave. Defaults to `false`
+	const CHECKPOINT_STREAMS: bool = false;
+	/// Number of events buffered per subscribed tapestry. See
+	/// [`Loom::subscribe`](crate::loom::Loom::subscribe).
+	///
+	/// Defaults to `64`
+	const EVENT_CHANNEL_CAPACITY: usize = 64;
+	/// Number of messages after which a brief reminder of the summary starting the current
+	/// [`TapestryFragment`] is inserted in the request sent to the LLM. Reminders are never
+	/// persisted.
+	///
+	/// See [`Loom::interleave_memory_refreshers`](crate::loom::Loom::interleave_memory_refreshers).
+	/// Defaults to `None`
+	const MEMORY_REFRESH_INTERVAL: Option<usize> = None;
+	/// Maximum number of words of the summary included in each reminder.
+	///
+	/// Defaults to `50`
+	const MEMORY_REFRESH_WORDS: usize = 50;
+	/// Genre whose recommended sampling parameters and instruction are applied to every prompt.
+	///
+	/// See [`Llm::set_sampling`]. Defaults to `None`
+	const GENRE_PRESET: Option<GenrePreset> = None;
+	/// Sampling parameters applied to every prompt, taking precedence over those of the
+	/// [`Config::GENRE_PRESET`].
+	///
+	/// See [`Llm::set_sampling`]. Defaults to `None`, leaving the parameters passed to
+	/// [`Loom::weave`](crate::loom::Loom::weave) untouched.
+	const SAMPLING_PARAMETERS: Option<SamplingParameters> = None;
+	/// Maximum number of tokens of a response, on top of the prompt token budget left.
+	///
+	/// Defaults to `None`
+	const MAX_RESPONSE_TOKENS: Option<u64> = None;
+	/// Whether to stop the LLM from writing lines on behalf of the speakers of a tapestry.
+	///
+	/// When enabled, the `account_id:` prefix of the most recent speakers is passed to
+	/// [`Llm::set_stop_sequences`], up to [`Llm::MAX_STOP_SEQUENCES`].
+	///
+	/// Defaults to `false`
+	const STOP_AT_SPEAKERS: bool = false;
+	/// Stop sequences always passed to [`Llm::set_stop_sequences`], e.g. `"\nPlayer:"`, taking
+	/// precedence over the speaker prefixes of [`Config::STOP_AT_SPEAKERS`].
+	///
+	/// Defaults to none
+	const STOP_SEQUENCES: &'static [&'static str] = &[];
+	/// Whether to keep the LLM in character.
+	///
+	/// When enabled, the LLM is instructed to never break character and sentences of the response
+	/// containing any of the [`Config::OUT_OF_CHARACTER_PHRASES`] are stripped.
+	///
+	/// Defaults to `false`
+	const STAY_IN_CHARACTER: bool = false;
+	/// Phrases, matched case insensitively, marking a sentence as out of character.
+	const OUT_OF_CHARACTER_PHRASES: &'static [&'static str] = &[
+		"as an ai",
+		"i'm an ai",
+		"i am an ai",
+		"i'm just an ai",
+		"as a language model",
+		"i cannot roleplay",
+	];
+	/// Probability, between `0.0` and `1.0`, of an event drawn from [`Config::RANDOM_EVENTS`] being
+	/// introduced to the story on each [`Loom::weave`](crate::loom::Loom::weave).
+	///
+	/// Defaults to `0.0`
+	const RANDOM_EVENT_PROBABILITY: f64 = 0.0;
+	/// Events the LLM can be instructed to introduce to the story, such as "a sudden storm".
+	const RANDOM_EVENTS: &'static [&'static str] = &[];
+	/// Seed of the random number generator drawing the [`Config::RANDOM_EVENTS`], making the
+	/// events reproducible.
+	///
+	/// Defaults to `None`, seeding from the operating system.
+	const RANDOM_EVENT_SEED: Option<u64> = None;
+	/// Namespace prefixed to every storage key derived from [`TapestryId::base_key`].
+	///
+	/// Isolates deployments sharing a storage backend. See [`storage::storage_key`].
+	///
+	/// Defaults to `None`
+	const STORAGE_NAMESPACE: Option<&'static str> = None;
+	/// Whether tapestry fragments are gzip compressed when saved. See [`storage::encode_fragment`].
+	///
+	/// Fragments saved before enabling compression, or after disabling it, remain readable, so
+	/// existing tapestries migrate as they are saved again.
+	///
+	/// Defaults to `false`
+	const COMPRESS_FRAGMENTS: bool = false;
+	/// Time after the last save of a tapestry at which it expires, so that abandoned tapestries
+	/// are deleted by [`TapestryChestHandler::purge_expired`].
+	///
+	/// Defaults to `None`, tapestries never expiring.
+	const TAPESTRY_TTL: Option<Duration> = None;
+	/// Maximum number of times a response rejected by [`Config::validate_response`] is retried.
+	///
+	/// Defaults to `0`
+	const MAX_VALIDATION_RETRIES: u8 = 0;
+	/// Maximum number of candidate completions requested by
+	/// [`Loom::weave_candidates`](crate::loom::Loom::weave_candidates).
+	///
+	/// Defaults to `4`
+	const MAX_CANDIDATES: u8 = 4;
+	/// Regex patterns and the placeholders replacing their matches in content sent to the LLM.
+	///
+	/// For example, `(r"[\w.+-]+@[\w-]+\.[\w.]+", "[EMAIL]")` redacts email addresses. Redaction
+	/// is only applied to the requests sent to the LLM, the [`TapestryFragment`] instances stored
+	/// in the [`Config::Chest`] keep the original content.
+	///
+	/// The patterns are compiled once by [`Loom::new`](crate::loom::Loom::new), which panics if
+	/// any of them is invalid.
+	///
+	/// Defaults to no patterns, which disables redaction.
+	const REDACTION_PATTERNS: &'static [(&'static str, &'static str)] = &[];
 
 	/// The LLM to use for generating responses to prompts.
 	type PromptModel: Llm<Self>;
@@ -255,19 +698,121 @@ pub trait Config: Debug + Sized + Clone + Default + Send + Sync + 'static {
 	fn convert_prompt_tokens_to_summary_model_tokens(
 		tokens: PromptModelTokens<Self>,
 	) -> SummaryModelTokens<Self>;
+	/// Validate the content of a response, e.g. against a JSON schema.
+	///
+	/// Invalid responses are retried up to [`Config::MAX_VALIDATION_RETRIES`] times, sending the
+	/// returned error to the LLM so it can correct its response.
+	/// [`Loom::weave`](crate::loom::Loom::weave) fails with [`LoomError::SchemaValidation`] if the
+	/// response is still invalid.
+	///
+	/// Defaults to accepting every response.
+	fn validate_response(_content: &str) -> std::result::Result<(), String> {
+		Ok(())
+	}
+	/// Path of a file holding the instructions used by [`Loom::weave`](crate::loom::Loom::weave).
+	///
+	/// When set, the content of the file replaces the `instructions` passed to
+	/// [`Loom::weave`](crate::loom::Loom::weave). The file is read again whenever it changes,
+	/// allowing instructions to be updated without recompiling.
+	///
+	/// Defaults to `None`
+	fn instructions_path() -> Option<PathBuf> {
+		None
+	}
+	/// Score the importance of a message from `0` to `10`.
+	///
+	/// Scores are stored in [`ContextMessage::importance`] and passed to the
+	/// [`Config::SummaryModel`], which is instructed to retain the details of high importance
+	/// messages and compress low importance ones.
+	///
+	/// Defaults to `None`, which leaves messages unscored.
+	fn score_importance(_msg: &ContextMessage<Self>) -> Option<u8> {
+		None
+	}
+	/// Detect the language of a message.
+	///
+	/// The first language detected in a tapestry is stored in [`TapestryFragment::language`] and
+	/// the LLM is instructed to maintain it on every subsequent prompt, regardless of the
+	/// language of later messages.
+	///
+	/// Defaults to `None`, which disables language consistency enforcement.
+	fn detect_language(_content: &str) -> Option<String> {
+		None
+	}
+}
+
+/// Hooks transforming the messages and responses of [`Loom::weave`](crate::loom::Loom::weave), e.g.
+/// to redact secrets, translate messages or append a footer to responses, and analysing them, e.g.
+/// through a moderation or embeddings API, set with
+/// [`Loom::with_hooks`](crate::loom::Loom::with_hooks).
+///
+/// Every hook defaults to leaving the content unchanged, unflagged, unscored and unembedded.
+#[async_trait]
+pub trait Hooks<T: Config>: Debug + Send + Sync {
+	/// Transform the content of each message before it is counted, prompted and stored.
+	async fn before_prompt(&self, _msg: &mut String) {}
+	/// Transform the content of a response before it is stored and returned.
+	///
+	/// Tool calls are not passed to this hook.
+	async fn after_response(&self, _content: &mut String) {}
+	/// Moderate the content of a message, e.g. through a moderation API, returning the categories
+	/// it was flagged for.
+	///
+	/// Used by [`Loom::audit`](crate::loom::Loom::audit) and, when [`Config::MODERATE_MESSAGES`] is
+	/// enabled, by [`Loom::weave`](crate::loom::Loom::weave), which fails with the returned error
+	/// rather than letting messages through unmoderated. Defaults to flagging nothing.
+	async fn moderate(&self, _content: &str) -> Result<Vec<String>, T> {
+		Ok(Vec::new())
+	}
+	/// Score the sentiment of a message, e.g. through a lexicon or a cheap model, from `-1.0` for
+	/// negative to `1.0` for positive.
+	///
+	/// Used by [`Loom::player_sentiment`](crate::loom::Loom::player_sentiment). Defaults to `None`,
+	/// which leaves messages unscored.
+	async fn sentiment(&self, _content: &str) -> Result<Option<f64>, T> {
+		Ok(None)
+	}
+	/// Embed the content of a message, e.g. through an embeddings API, for it to be remembered
+	/// and recalled by similarity when [`Config::MEMORY_RECALL_COUNT`] is set.
+	///
+	/// Defaults to `None`, which leaves messages unembedded.
+	async fn embed(&self, _content: &str) -> Result<Option<Vec<f32>>, T> {
+		Ok(None)
+	}
 }
 
+/// [`Hooks`] leaving every message and response unchanged, used unless
+/// [`Loom::with_hooks`](crate::loom::Loom::with_hooks) is called.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoHooks;
+
+impl<T: Config> Hooks<T> for NoHooks {}
+
 /// Context message that represent a single message in a [`TapestryFragment`] instance.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ContextMessage<T: Config> {
 	pub role: WrapperRole,
 	pub content: String,
 	pub account_id: Option<String>,
 	pub timestamp: String,
+	/// Importance of the message from `0` to `10`, as scored by [`Config::score_importance`].
+	#[serde(default)]
+	pub importance: Option<u8>,
 
 	_phantom: PhantomData<T>,
 }
 
+// Implemented by hand since deriving it would require the `Config` to be `PartialEq` too
+impl<T: Config> PartialEq for ContextMessage<T> {
+	fn eq(&self, other: &Self) -> bool {
+		self.role == other.role &&
+			self.content == other.content &&
+			self.account_id == other.account_id &&
+			self.timestamp == other.timestamp &&
+			self.importance == other.importance
+	}
+}
+
 impl<T: Config> ContextMessage<T> {
 	/// Create a new `ContextMessage` instance.
 	pub fn new(
@@ -276,7 +821,7 @@ impl<T: Config> ContextMessage<T> {
 		account_id: Option<String>,
 		timestamp: String,
 	) -> Self {
-		Self { role, content, account_id, timestamp, _phantom: PhantomData }
+		Self { role, content, account_id, timestamp, importance: None, _phantom: PhantomData }
 	}
 }
 
@@ -284,15 +829,104 @@ impl<T: Config> ContextMessage<T> {
 /// metadata.
 ///
 /// LLM can only hold a limited amount of tokens in a the entire message history/context.
-/// The total number of `context_tokens` is tracked when [`Loom::weave`] is executed and if it
-/// exceeds the maximum number of tokens allowed for the current GPT [`Config::PromptModel`], then a
-/// summary is generated and a new [`TapestryFragment`] instance is created.
+/// The total number of `context_tokens` is tracked when [`Loom::weave`](crate::loom::Loom::weave)
+/// is executed and if it exceeds the maximum number of tokens allowed for the current GPT
+/// [`Config::PromptModel`], then a summary is generated and a new [`TapestryFragment`] instance is
+/// created.
 #[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
 pub struct TapestryFragment<T: Config> {
 	/// Total number of _GPT tokens_ in the `context_messages`.
 	pub context_tokens: <T::PromptModel as Llm<T>>::Tokens,
 	/// List of [`ContextMessage`]s that represents the message history.
 	pub context_messages: Vec<ContextMessage<T>>,
+	/// Language the tapestry is written in, as detected by [`Config::detect_language`].
+	///
+	/// Carried over to new instances when a summary is generated.
+	#[serde(default)]
+	pub language: Option<String>,
+	/// Completion tokens generated for the tapestry on the current UTC day, used to enforce the
+	/// [`Config::MAX_DAILY_COMPLETION_TOKENS`].
+	///
+	/// Carried over to new instances when a summary is generated.
+	#[serde(default)]
+	pub daily_usage: Option<DailyUsage>,
+	/// Account ids of the players taking part in the tapestry, sorted so that equal rosters
+	/// compare and serialize equally regardless of the order the players joined in.
+	///
+	/// Managed by [`Loom::add_player`](crate::loom::Loom::add_player) and
+	/// [`Loom::remove_player`](crate::loom::Loom::remove_player), and extended with the
+	/// `account_id` of the messages passed to [`Loom::weave`](crate::loom::Loom::weave). Carried
+	/// over to new instances when a summary is generated.
+	#[serde(default)]
+	pub players: Vec<String>,
+	/// Length of the story so far, used to enforce the [`Config::MAX_STORY_TURNS`] and
+	/// [`Config::MAX_STORY_TOKENS`].
+	///
+	/// Carried over to new instances when a summary is generated.
+	#[serde(default)]
+	pub story_length: StoryLength,
+	/// When the tapestry expires and is deleted by [`TapestryChestHandler::purge_expired`].
+	///
+	/// Refreshed on every save by [`storage::refresh_expiry`] when the [`Config::TAPESTRY_TTL`] is
+	/// set. `None` never expires.
+	#[serde(default)]
+	pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Metadata of a story stored once per tapestry rather than per [`TapestryFragment`] instance,
+/// e.g. for listing the stories of a user.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct StoryMetadata {
+	pub title: String,
+	#[serde(default)]
+	pub tags: Vec<String>,
+	pub created_at: DateTime<Utc>,
+	/// Time of the last call to [`Loom::weave`](crate::loom::Loom::weave).
+	pub updated_at: DateTime<Utc>,
+}
+
+/// Response streamed so far by [`Loom::weave_streaming`](crate::loom::Loom::weave_streaming)
+/// along with the new messages it responds to, saved as each delta arrives when
+/// [`Config::CHECKPOINT_STREAMS`] is set.
+///
+/// Appended to the tapestry by [`Loom::recover_stream`](crate::loom::Loom::recover_stream) if the
+/// stream was interrupted before the response was persisted, e.g. by a crash.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct StreamCheckpoint<T: Config> {
+	pub msgs: Vec<ContextMessage<T>>,
+	pub response: String,
+}
+
+/// Length of a story across every [`TapestryFragment`] instance.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub struct StoryLength {
+	/// Number of calls to [`Loom::weave`](crate::loom::Loom::weave).
+	pub turns: u64,
+	/// Tokens of the messages and responses of every turn.
+	pub tokens: u64,
+}
+
+impl StoryLength {
+	/// Fraction of the [`Config::MAX_STORY_TURNS`] or [`Config::MAX_STORY_TOKENS`] used, whichever
+	/// is highest.
+	///
+	/// Returns `None` if neither limit is configured.
+	pub fn progress<T: Config>(&self) -> Option<f64> {
+		let turns = T::MAX_STORY_TURNS.map(|max| self.turns as f64 / max as f64);
+		let tokens = T::MAX_STORY_TOKENS.map(|max| self.tokens as f64 / max as f64);
+		match (turns, tokens) {
+			(Some(turns), Some(tokens)) => Some(turns.max(tokens)),
+			(progress, None) | (None, progress) => progress,
+		}
+	}
+}
+
+/// Completion tokens generated on a given UTC day.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
+pub struct DailyUsage {
+	/// UTC day formatted as `YYYY-MM-DD`.
+	pub date: String,
+	pub completion_tokens: u64,
 }
 
 impl<T: Config> TapestryFragment<T> {
@@ -300,6 +934,16 @@ impl<T: Config> TapestryFragment<T> {
 		Self::default()
 	}
 
+	/// Returns the most recent [`ContextMessage`] of `role` along with its index in the
+	/// `context_messages` list.
+	pub fn last_message_by_role(&self, role: &WrapperRole) -> Option<(usize, &ContextMessage<T>)> {
+		self.context_messages
+			.iter()
+			.enumerate()
+			.rev()
+			.find(|(_, msg)| msg.role == *role)
+	}
+
 	/// Add a [`ContextMessage`] to the `context_messages` list.
 	///
 	/// Also increments the `context_tokens` by the number of tokens in the message.
@@ -320,14 +964,12 @@ impl<T: Config> TapestryFragment<T> {
 	///
 	/// Also increments the `context_tokens` by the number of tokens in the message.
 	fn extend_messages(&mut self, msgs: Vec<ContextMessage<T>>) -> Result<(), T> {
-		let total_new_tokens = msgs
-			.iter()
-			.map(|m| T::PromptModel::count_tokens(&m.content).unwrap())
-			.collect::<Vec<_>>();
-
-		let sum: PromptModelTokens<T> = total_new_tokens
-			.iter()
-			.fold(PromptModelTokens::<T>::default(), |acc, x| acc.saturating_add(x));
+		let sum = msgs.iter().try_fold(PromptModelTokens::<T>::default(), |acc, m| {
+			let tokens = T::PromptModel::count_tokens(&m.content)?;
+			acc.checked_add(&tokens).ok_or_else(|| {
+				LoomError::BadConfig("Number of tokens exceeds max tokens for model".to_string())
+			})
+		})?;
 
 		trace!("Extending messages with token sum: {}", sum);
 
@@ -1,14 +1,39 @@
-use std::{collections::VecDeque, marker::PhantomData};
+use std::{
+	collections::{BTreeMap, HashMap, VecDeque},
+	fs,
+	future::Future,
+	hash::{DefaultHasher, Hash, Hasher},
+	marker::PhantomData,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant, SystemTime},
+};
 
-use num_traits::{CheckedAdd, FromPrimitive, SaturatingAdd, SaturatingSub, Zero};
-use tracing::{debug, error, instrument, trace};
+use chrono::{DateTime, Utc};
+use num_traits::{
+	CheckedAdd, FromPrimitive, SaturatingAdd, SaturatingMul, SaturatingSub, ToPrimitive, Zero,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use regex::{NoExpand, Regex};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+	sync::{broadcast, mpsc},
+	time,
+};
+use tracing::{debug, error, instrument, trace, warn};
 
 use crate::{
+	memory::{InMemoryStore, MemoryStore},
+	request::WeaveRequest,
+	storage::storage_key,
 	types::{
-		LoomError, PromptModelRequest, PromptModelTokens, SummaryModelTokens, VecPromptMsgsDeque,
-		WrapperRole, ASSISTANT_ROLE, SYSTEM_ROLE,
+		CharacterSheet, ConsistencyWarning, ContextStrategy, CostEstimate, ExportFormat, LoomError,
+		LoomEvent, ModerationHit, PromptModelRequest, PromptModelResponse, PromptModelTokens,
+		PromptPreview, SummaryModelRequest, SummaryModelTokens, ToolDefinition, ToolReply, Usage,
+		VecPromptMsgsDeque, WrapperRole, ASSISTANT_ROLE, SYSTEM_ROLE, USER_ROLE,
 	},
-	Config, ContextMessage, Llm, LlmConfig, TapestryChestHandler, TapestryFragment, TapestryId,
+	Config, ContextMessage, DailyUsage, Hooks, Llm, LlmConfig, NoHooks, StoryLength, StoryMetadata,
+	StreamCheckpoint, TapestryChestHandler, TapestryFragment, TapestryId,
 };
 
 /// The machine that drives all of the core methods that should be used across any service
@@ -18,13 +43,192 @@ use crate::{
 #[derive(Debug)]
 pub struct Loom<T: Config> {
 	pub chest: T::Chest,
+	/// Time of the last prompt per [`TapestryId::base_key`], used to enforce the
+	/// [`Config::MIN_PROMPT_INTERVAL`].
+	last_prompts: Mutex<HashMap<String, Instant>>,
+	/// Event channels per [`TapestryId::base_key`], created by [`Loom::subscribe`].
+	events: Mutex<HashMap<String, broadcast::Sender<LoomEvent<T>>>>,
+	/// Last read [`Config::instructions_path`] file.
+	instructions: Mutex<Option<InstructionsFile>>,
+	/// Time until which prompts are held back after being rate limited.
+	backoff_until: tokio::sync::Mutex<Option<time::Instant>>,
+	/// Random number generator drawing the [`Config::RANDOM_EVENTS`].
+	rng: Mutex<StdRng>,
+	/// Counters exposed by [`Loom::metrics_snapshot`].
+	metrics: Mutex<Metrics>,
+	/// Results of [`Loom::weave_idempotent`].
+	idempotent_results: Mutex<IdempotentResults<T>>,
+	/// Locks serializing the updates of each tapestry per [`TapestryId::base_key`], removed by
+	/// [`TapestryGuard`] once no update holds or awaits them.
+	tapestry_locks: Mutex<TapestryLocks>,
+	/// Set by [`Loom::with_hooks`].
+	hooks: Box<dyn Hooks<T>>,
+	/// Embedded messages recalled when [`Config::MEMORY_RECALL_COUNT`] is set.
+	memory: Box<dyn MemoryStore<T>>,
+	/// [`Config::REDACTION_PATTERNS`] compiled by [`Loom::new`].
+	redaction_patterns: Vec<(Regex, &'static str)>,
 	_phantom: PhantomData<T>,
 }
 
+/// Prefix of the summary message starting a [`TapestryFragment`] instance created by a summary.
+pub(crate) const SUMMARY_PREFIX: &str = "\n\"\"\"\nSummary\n ";
+
+/// Content of an instructions file along with its modification time and length when read.
+#[derive(Debug)]
+struct InstructionsFile {
+	path: PathBuf,
+	modified: SystemTime,
+	len: u64,
+	content: String,
+}
+
+/// Response, [`TapestryFragment`] instance and whether a summary was generated, as returned by
+/// [`Loom::weave`].
+type WeaveResult<T> = (PromptModelResponse<T>, u64, bool);
+
+/// Prompt assembled by [`Loom::assemble_prompt`], along with the state persisted once the LLM
+/// responded.
+struct AssembledPrompt<T: Config> {
+	req_msgs: VecPromptMsgsDeque<T, T::PromptModel>,
+	prompt_params: <T::PromptModel as Llm<T>>::Parameters,
+	max_prompt_tokens_limit: PromptModelTokens<T>,
+	max_completion_tokens: PromptModelTokens<T>,
+	msgs: Vec<ContextMessage<T>>,
+	msgs_tokens: PromptModelTokens<T>,
+	tapestry_fragment_to_persist: TapestryFragment<T>,
+	was_summary_generated: bool,
+	is_new_instance: bool,
+	language: Option<String>,
+	players: Vec<String>,
+	daily_usage: DailyUsage,
+	story_length: StoryLength,
+	usage: Usage,
+}
+
+/// How [`Loom::assemble_prompt`] weaves the new messages.
+enum AssembleMode<T: Config> {
+	/// Weaves new messages.
+	Weave,
+	/// Previews a weave, neither generating a summary, checking the cooldown nor drawing a random
+	/// event.
+	DryRun,
+	/// Weaves again messages already woven, into the given [`TapestryFragment`] they are removed
+	/// from instead of the current instance. The hooks, moderation, cooldown, random events and
+	/// message splitting are skipped since they applied when the messages were first woven.
+	Regenerate(TapestryFragment<T>),
+}
+
+/// Results of [`Loom::weave_idempotent`] per [`TapestryId::base_key`] and idempotency key, along
+/// with the time they were woven.
+struct IdempotentResults<T: Config>(HashMap<(String, String), (Instant, WeaveResult<T>)>);
+
+impl<T: Config> std::fmt::Debug for IdempotentResults<T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_set().entries(self.0.keys()).finish()
+	}
+}
+
+/// Locks of [`Loom::lock_tapestry`] per [`TapestryId::base_key`].
+type TapestryLocks = HashMap<String, Arc<tokio::sync::Mutex<()>>>;
+
+/// Guard returned by [`Loom::lock_tapestry`], removing the lock of the tapestry from the
+/// [`TapestryLocks`] when dropped unless another update awaits it.
+struct TapestryGuard<'a> {
+	locks: &'a Mutex<TapestryLocks>,
+	key: String,
+	guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for TapestryGuard<'_> {
+	fn drop(&mut self) {
+		// Hold the map while releasing so no update can clone the lock in between
+		let mut locks = self.locks.lock().unwrap();
+		self.guard.take();
+		if locks.get(&self.key).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+			locks.remove(&self.key);
+		}
+	}
+}
+
+/// Counters of the prompts and errors of a [`Loom`].
+#[derive(Debug, Default)]
+struct Metrics {
+	prompts: u64,
+	prompt_tokens: u64,
+	completion_tokens: u64,
+	/// Errors returned by [`Loom::weave`] per [`LoomError::kind`].
+	errors: BTreeMap<&'static str, u64>,
+	/// Weaves in progress.
+	active_weaves: u64,
+}
+
+/// Counts a weave in progress in the [`Metrics`] until dropped, including when the weave is
+/// cancelled.
+struct ActiveWeave<'a>(&'a Mutex<Metrics>);
+
+impl<'a> ActiveWeave<'a> {
+	fn start(metrics: &'a Mutex<Metrics>) -> Self {
+		metrics.lock().unwrap().active_weaves += 1;
+		Self(metrics)
+	}
+}
+
+impl Drop for ActiveWeave<'_> {
+	fn drop(&mut self) {
+		self.0.lock().unwrap().active_weaves -= 1;
+	}
+}
+
+impl<T: Config> Default for Loom<T> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
 impl<T: Config> Loom<T> {
 	/// Creates a new instance of `Loom`.
+	///
+	/// # Panics
+	///
+	/// Panics if any of the [`Config::REDACTION_PATTERNS`] is not a valid regex.
 	pub fn new() -> Self {
-		Self { chest: <T::Chest as TapestryChestHandler<T>>::new(), _phantom: PhantomData }
+		Self {
+			chest: <T::Chest as TapestryChestHandler<T>>::new(),
+			last_prompts: Mutex::new(HashMap::new()),
+			events: Mutex::new(HashMap::new()),
+			instructions: Mutex::new(None),
+			backoff_until: tokio::sync::Mutex::new(None),
+			rng: Mutex::new(match T::RANDOM_EVENT_SEED {
+				Some(seed) => StdRng::seed_from_u64(seed),
+				None => StdRng::from_entropy(),
+			}),
+			metrics: Mutex::new(Metrics::default()),
+			idempotent_results: Mutex::new(IdempotentResults(HashMap::new())),
+			tapestry_locks: Mutex::new(HashMap::new()),
+			hooks: Box::new(NoHooks),
+			memory: Box::new(InMemoryStore::default()),
+			redaction_patterns: T::REDACTION_PATTERNS
+				.iter()
+				.map(|(pattern, placeholder)| match Regex::new(pattern) {
+					Ok(regex) => (regex, *placeholder),
+					Err(e) => panic!("Invalid redaction pattern {}: {}", pattern, e),
+				})
+				.collect(),
+			_phantom: PhantomData,
+		}
+	}
+
+	/// Sets the [`Hooks`] transforming and analysing the messages and responses of every weave.
+	pub fn with_hooks(mut self, hooks: impl Hooks<T> + 'static) -> Self {
+		self.hooks = Box::new(hooks);
+		self
+	}
+
+	/// Sets the [`MemoryStore`] remembering the messages recalled when
+	/// [`Config::MEMORY_RECALL_COUNT`] is set, instead of the default [`InMemoryStore`].
+	pub fn with_memory_store(mut self, memory: impl MemoryStore<T> + 'static) -> Self {
+		self.memory = Box::new(memory);
+		self
 	}
 
 	/// Prompt LLM Weaver for a response for [`TapestryId`].
@@ -36,14 +240,26 @@ impl<T: Config> Loom<T> {
 	/// current [`Config::PromptModel`] or custom max tokens. This threshold is affected by the
 	/// [`Config::TOKEN_THRESHOLD_PERCENTILE`].
 	///
+	/// The new instance starting with the summary is saved along with the messages and response
+	/// in a single write once the LLM responded. A failure in between, including a crash, leaves
+	/// the current instance untouched, so weaving again generates a new summary instead of
+	/// leaving a duplicate summary instance behind.
+	///
 	/// # Parameters
 	///
 	/// - `prompt_llm_config`: The [`Config::PromptModel`] to use for prompting LLM.
 	/// - `summary_llm_config`: The [`Config::SummaryModel`] to use for generating summaries.
 	/// - `tapestry_id`: The [`TapestryId`] to use for storing the [`TapestryFragment`] instance.
 	/// - `instructions`: The instruction message to be used for the current [`TapestryFragment`]
-	///   instance.
+	///   instance. Replaced by the content of the [`Config::instructions_path`] file, if any.
 	/// - `msgs`: The messages to prompt the LLM with.
+	///
+	/// Publishes a [`LoomEvent`] to the subscribers of the [`TapestryId`] for every message
+	/// appended, summary generated and error.
+	///
+	/// Each prompt is bounded by the [`Config::PROMPT_TIMEOUT`]. Weaving can be cancelled by
+	/// dropping the returned future, in which case nothing is persisted since the tapestry
+	/// fragment is only saved once the response is complete.
 	#[instrument(skip(self, instructions, msgs))]
 	pub async fn weave<TID: TapestryId>(
 		&self,
@@ -51,198 +267,3172 @@ impl<T: Config> Loom<T> {
 		summary_llm_config: LlmConfig<T, T::SummaryModel>,
 		tapestry_id: TID,
 		instructions: String,
-		mut msgs: Vec<ContextMessage<T>>,
-	) -> Result<(<<T as Config>::PromptModel as Llm<T>>::Response, u64, bool), LoomError<T>> {
-		let instructions_ctx_msg =
-			Self::build_context_message(SYSTEM_ROLE.into(), instructions, None);
-		let instructions_req_msg: PromptModelRequest<T> = instructions_ctx_msg.clone().into();
-
-		trace!("Fetching current tapestry fragment for ID: {:?}", tapestry_id);
-
-		let current_tapestry_fragment = self
-			.chest
-			.get_tapestry_fragment(tapestry_id.clone(), None)
-			.await?
-			.unwrap_or_default();
-
-		// Get max token limit which cannot be exceeded in a tapestry fragment
-		let max_prompt_tokens_limit = prompt_llm_config.model.get_max_prompt_token_limit();
+		msgs: Vec<ContextMessage<T>>,
+	) -> Result<(PromptModelResponse<T>, u64, bool), LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		self.weave_observed(
+			prompt_llm_config,
+			summary_llm_config,
+			tapestry_id,
+			instructions,
+			msgs,
+			AssembleMode::Weave,
+			None,
+		)
+		.await
+		.map(|(result, _)| result)
+	}
 
-		// Request messages which will be sent as a whole to the LLM
-		let mut req_msgs = VecPromptMsgsDeque::<T, T::PromptModel>::with_capacity(
-			current_tapestry_fragment.context_messages.len() + 1,
-		);
+	/// Starts a [`WeaveRequest`] to [`Loom::weave`] the messages added to it into the
+	/// [`TapestryId`], as an alternative to passing every argument at once.
+	pub fn weave_request<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+	) -> WeaveRequest<'_, T, TID> {
+		WeaveRequest::new(self, prompt_llm_config, summary_llm_config, tapestry_id)
+	}
 
-		// Add instructions as the first message
-		req_msgs.push_front(instructions_req_msg);
+	/// Same as [`Loom::weave`], also returning the [`Usage`] of every prompt made, including
+	/// summaries, continuations and retries.
+	pub async fn weave_with_usage<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+	) -> Result<(PromptModelResponse<T>, u64, bool, Usage), LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		self.weave_observed(
+			prompt_llm_config,
+			summary_llm_config,
+			tapestry_id,
+			instructions,
+			msgs,
+			AssembleMode::Weave,
+			None,
+		)
+		.await
+		.map(|((response, instance, was_summary_generated), usage)| {
+			(response, instance, was_summary_generated, usage)
+		})
+	}
 
-		// Convert and append all tapestry fragment messages to the request messages.
-		let mut ctx_msgs = VecDeque::from(
-			prompt_llm_config
-				.model
-				.ctx_msgs_to_prompt_requests(&current_tapestry_fragment.context_messages),
-		);
-		req_msgs.append(&mut ctx_msgs);
+	/// Same as [`Loom::weave`], except that the response is streamed through
+	/// [`Llm::prompt_stream`], passing each delta to `on_delta` as it arrives.
+	///
+	/// Only the initial response is streamed. The response returned and persisted may differ from
+	/// the streamed deltas once continued, corrected by [`Config::validate_response`] or stripped
+	/// of out of character sentences.
+	///
+	/// If the stream fails after some deltas were received, the partial response is persisted
+	/// along with the new `msgs` before the error is returned. A stream stalling for the
+	/// [`Config::STREAM_IDLE_TIMEOUT`] fails with [`LoomError::PartialTimeout`] holding the partial
+	/// response.
+	///
+	/// When [`Config::CHECKPOINT_STREAMS`] is set, the response is saved as a [`StreamCheckpoint`]
+	/// as it streams, recovered by [`Loom::recover_stream`] if the stream is interrupted before the
+	/// response is persisted.
+	pub async fn weave_streaming<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+		on_delta: &mut (dyn FnMut(&str) + Send),
+	) -> Result<(PromptModelResponse<T>, u64, bool), LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		self.weave_observed(
+			prompt_llm_config,
+			summary_llm_config,
+			tapestry_id,
+			instructions,
+			msgs,
+			AssembleMode::Weave,
+			Some(on_delta),
+		)
+		.await
+		.map(|(result, _)| result)
+	}
 
-		// New messages are not added here yet since we first calculate if the new `msgs` would
-		// have the tapestry fragment exceed the maximum token limit and require a summary
-		// generation resulting in a new tapestry fragment.
-		//
-		// Either we are starting a new tapestry fragment with the instruction and summary messages
-		// or we are continuing the current tapestry fragment.
-		let msgs_tokens = Self::count_tokens_in_messages(msgs.iter());
+	/// Same as [`Loom::weave`], except that the LLM can call any of the `tools` instead of replying
+	/// with text.
+	///
+	/// A requested tool call is persisted as an assistant message holding the JSON serialized
+	/// [`ToolCall`](crate::types::ToolCall), so it survives summaries. Its result is fed back by
+	/// weaving again with a [`TOOL_ROLE`](crate::types::TOOL_ROLE) message.
+	///
+	/// Fails with [`LoomError::UnsupportedCapability`] if the model does not support tools.
+	pub async fn prompt_with_tools<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+		tools: &[ToolDefinition],
+	) -> Result<(ToolReply<T>, u64, bool), LoomError<T>> {
+		let model = prompt_llm_config.model;
+		if !model.capabilities().tools {
+			return Err(LoomError::UnsupportedCapability {
+				model: model.name(),
+				capability: "tools",
+			});
+		}
 
-		trace!(
-			"Total tokens after adding new messages: {:?}, maximum allowed: {:?}",
-			req_msgs.tokens.saturating_add(&msgs_tokens),
-			max_prompt_tokens_limit
-		);
+		let mut params = prompt_llm_config.params;
+		model.set_tools(&mut params, tools);
 
-		// Check if the total number of tokens in the tapestry fragment exceeds the maximum number
-		// of tokens allowed after adding the new messages and the minimum response length.
-		let does_exceeding_max_token_limit = max_prompt_tokens_limit <=
-			req_msgs.tokens.saturating_add(&msgs_tokens).saturating_add(
-				&PromptModelTokens::<T>::from_u64(T::MINIMUM_RESPONSE_LENGTH).unwrap(),
-			);
+		let (response, instance, was_summary_generated) = self
+			.weave(LlmConfig { model, params }, summary_llm_config, tapestry_id, instructions, msgs)
+			.await?;
 
-		let (mut tapestry_fragment_to_persist, was_summary_generated) =
-			if does_exceeding_max_token_limit {
-				trace!("Generating summary as the token limit exceeded");
+		let reply = match model.tool_call(&response) {
+			Some(tool_call) => ToolReply::ToolCall(tool_call),
+			None => ToolReply::Text(response),
+		};
 
-				// Summary generation should not exceed the maximum token limit of the prompt model
-				// since it will be added to the tapestry fragment
-				let summary_max_tokens: PromptModelTokens<T> =
-					prompt_llm_config.model.max_context_length() - max_prompt_tokens_limit;
+		Ok((reply, instance, was_summary_generated))
+	}
 
-				let summary = Self::generate_summary(
-					&summary_llm_config,
-					&current_tapestry_fragment,
-					T::convert_prompt_tokens_to_summary_model_tokens(summary_max_tokens),
-				)
-				.await?;
+	/// Same as [`Loom::weave`], except that JSON mode is enabled and the response is parsed into
+	/// `D`, e.g. to extract the state of the scene.
+	///
+	/// The raw JSON response is persisted like any other response, so that summaries keep its
+	/// context, even if it fails to parse. [`Config::RESPONSE_SENTENCES`] is ignored.
+	///
+	/// Fails with [`LoomError::UnsupportedCapability`] if the model does not support JSON mode,
+	/// and with [`LoomError::ParseFailed`] if the response is not a valid `D`.
+	pub async fn weave_json<TID: TapestryId, D: DeserializeOwned>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+	) -> Result<(D, u64, bool), LoomError<T>> {
+		let model = prompt_llm_config.model;
+		if !model.capabilities().json_mode {
+			return Err(LoomError::UnsupportedCapability {
+				model: model.name(),
+				capability: "JSON mode",
+			});
+		}
 
-				let summary_ctx_msg = Self::build_context_message(
-					SYSTEM_ROLE.into(),
-					format!("\n\"\"\"\nSummary\n {}", summary),
-					None,
-				);
+		let mut params = prompt_llm_config.params;
+		model.set_json_mode(&mut params);
 
-				// Truncate all tapestry fragment messages except for the instructions and add the
-				// summary
-				req_msgs.truncate(1);
-				req_msgs.push_back(summary_ctx_msg.clone().into());
+		let (response, instance, was_summary_generated) = self
+			.weave(LlmConfig { model, params }, summary_llm_config, tapestry_id, instructions, msgs)
+			.await?;
 
-				// Create new tapestry fragment
-				let mut new_tapestry_fragment = TapestryFragment::new();
-				new_tapestry_fragment.push_message(summary_ctx_msg)?;
+		let content = response.into().unwrap_or_default();
+		let parsed =
+			serde_json::from_str(&content).map_err(|e| LoomError::ParseFailed(e.to_string()))?;
 
-				(new_tapestry_fragment, true)
-			} else {
-				(current_tapestry_fragment, false)
-			};
+		Ok((parsed, instance, was_summary_generated))
+	}
 
-		// Add new messages to the request messages
-		req_msgs.extend(msgs.iter().map(|m| m.clone().into()).collect::<Vec<_>>());
+	/// Prompts the LLM for `n` alternative responses to the `msgs`, e.g. to let the user choose
+	/// how the story continues, without persisting anything.
+	///
+	/// The chosen candidate is persisted along with the `msgs` through
+	/// [`Loom::accept_candidate`]. Candidates are neither continued, validated nor stripped of out
+	/// of character sentences. The [`Usage`] returned includes the tokens of every candidate.
+	///
+	/// Fails with [`LoomError::BadConfig`] if `n` is zero or exceeds [`Config::MAX_CANDIDATES`],
+	/// and with [`LoomError::CandidatesExceedContext`] if the current [`TapestryFragment`]
+	/// instance would have to be summarized or truncated to fit the `msgs`.
+	pub async fn weave_candidates<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+		n: u8,
+	) -> Result<(Vec<String>, Usage), LoomError<T>> {
+		if n == 0 || n > T::MAX_CANDIDATES {
+			return Err(LoomError::BadConfig(format!(
+				"Number of candidates must be between 1 and {}",
+				T::MAX_CANDIDATES
+			)));
+		}
 
-		// Tokens available for LLM response which would not exceed maximum token limit
-		let max_completion_tokens = max_prompt_tokens_limit.saturating_sub(&req_msgs.tokens);
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		self.check_cooldown(&tapestry_id)?;
 
-		trace!("Max completion tokens available: {:?}", max_completion_tokens);
+		let AssembledPrompt {
+			req_msgs,
+			mut prompt_params,
+			mut max_completion_tokens,
+			is_new_instance,
+			daily_usage,
+			..
+		} = self
+			.assemble_prompt(
+				&prompt_llm_config,
+				&summary_llm_config,
+				&tapestry_id,
+				instructions,
+				msgs,
+				AssembleMode::DryRun,
+			)
+			.await?;
+		if is_new_instance {
+			debug!("Tapestry {:?} has no room left for candidates", tapestry_id);
+			return Err(LoomError::CandidatesExceedContext);
+		}
 
-		if max_completion_tokens.is_zero() {
-			return Err(LoomError::MaxCompletionTokensIsZero.into());
+		// The daily completion tokens left are shared among the candidates
+		if let Some(max_daily_tokens) = T::MAX_DAILY_COMPLETION_TOKENS {
+			let remaining_tokens = max_daily_tokens.saturating_sub(daily_usage.completion_tokens);
+			if let Some(remaining_tokens) =
+				PromptModelTokens::<T>::from_u64(remaining_tokens / u64::from(n))
+			{
+				max_completion_tokens = max_completion_tokens.min(remaining_tokens);
+			}
+			if max_completion_tokens.is_zero() {
+				return Err(LoomError::MaxCompletionTokensIsZero);
+			}
 		}
 
-		trace!("Prompting LLM with request messages");
+		prompt_llm_config.model.set_candidates(&mut prompt_params, n);
 
-		let response = prompt_llm_config
-			.model
-			.prompt(
+		trace!("Prompting LLM for {} candidates", n);
+
+		let (response, usage) = self
+			.prompt_model(
+				&prompt_llm_config.model,
 				false,
 				req_msgs.tokens,
 				req_msgs.into_vec(),
-				&prompt_llm_config.params,
+				&prompt_params,
 				max_completion_tokens,
+				None,
 			)
-			.await
-			.map_err(|e| {
-				error!("Failed to prompt LLM: {}", e);
-				e
-			})?;
+			.await?;
 
-		// Add LLM response to the tapestry fragment messages to save
-		msgs.push(Self::build_context_message(
-			ASSISTANT_ROLE.into(),
-			response.clone().into().unwrap_or_default(),
+		let mut candidates = prompt_llm_config.model.candidates(&response);
+		for candidate in candidates.iter_mut() {
+			self.hooks.after_response(candidate).await;
+		}
+
+		Ok((candidates, usage))
+	}
+
+	/// Replaces the last assistant response of the current [`TapestryFragment`] instance with a
+	/// new one, as if the messages it replied to were woven again with the same `instructions`.
+	///
+	/// The response is removed along with the messages following the previous response, which
+	/// are then woven again like [`Loom::weave`] so the same token budget applies, without running
+	/// the [`Hooks::before_prompt`], moderation, cooldown, random events or splitting that applied
+	/// when they were first woven. The story length does not grow, while the daily completion
+	/// tokens include both responses.
+	///
+	/// Nothing is saved until the new response is complete, so the current response is kept if
+	/// weaving fails.
+	///
+	/// Fails with [`LoomError::NothingToRegenerate`] if the last message is not an assistant
+	/// message.
+	pub async fn regenerate<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+	) -> Result<(PromptModelResponse<T>, u64, bool), LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		let tapestry_fragment = self
+			.chest
+			.get_tapestry_fragment(tapestry_id.clone(), None)
+			.await?
+			.ok_or(LoomError::NothingToRegenerate)?;
+		let assistant_role = WrapperRole::from(ASSISTANT_ROLE);
+		let Some((response_index, _)) = tapestry_fragment.last_message_by_role(&assistant_role)
+		else {
+			return Err(LoomError::NothingToRegenerate);
+		};
+		if response_index + 1 != tapestry_fragment.context_messages.len() {
+			return Err(LoomError::NothingToRegenerate);
+		}
+
+		// Messages replied to start after the previous response, or the carried over summary
+		let msgs_index = tapestry_fragment.context_messages[..response_index]
+			.iter()
+			.rposition(|m| m.role == assistant_role || m.content.starts_with(SUMMARY_PREFIX))
+			.map_or(0, |i| i + 1);
+		let mut history = tapestry_fragment.context_messages;
+		let removed_msgs = history.split_off(msgs_index);
+		let removed_tokens = Self::count_tokens_in_messages(&removed_msgs).await?;
+
+		let mut regenerated_tapestry_fragment = TapestryFragment {
+			context_tokens: Zero::zero(),
+			context_messages: Vec::new(),
+			story_length: StoryLength {
+				turns: tapestry_fragment.story_length.turns.saturating_sub(1),
+				tokens: tapestry_fragment
+					.story_length
+					.tokens
+					.saturating_sub(removed_tokens.to_u64().unwrap_or_default()),
+			},
+			..tapestry_fragment
+		};
+		regenerated_tapestry_fragment.extend_messages(history)?;
+
+		let mut msgs = removed_msgs;
+		msgs.pop();
+		trace!("Regenerating the response to {} messages", msgs.len());
+
+		self.weave_observed(
+			prompt_llm_config,
+			summary_llm_config,
+			tapestry_id,
+			instructions,
+			msgs,
+			AssembleMode::Regenerate(regenerated_tapestry_fragment),
 			None,
-		));
+		)
+		.await
+		.map(|(result, _)| result)
+	}
 
-		// Add new messages and response to the tapestry fragment which will be persisted in the
-		// database
-		tapestry_fragment_to_persist.extend_messages(msgs)?;
+	/// Prompts the LLM to carry on the story of the current [`TapestryFragment`] instance without
+	/// any new message, e.g. for the narrator to go on with what happens next.
+	///
+	/// Weaves like [`Loom::weave`] with no `msgs`, only the response being appended.
+	///
+	/// Fails with [`LoomError::NothingToContinue`] if the current instance holds no message, rather
+	/// than prompting the LLM without any context.
+	pub async fn continue_story<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+	) -> Result<(PromptModelResponse<T>, u64, bool), LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		let tapestry_fragment: Option<TapestryFragment<T>> =
+			self.chest.get_tapestry_fragment(tapestry_id.clone(), None).await?;
+		if tapestry_fragment.is_none_or(|f| f.context_messages.is_empty()) {
+			return Err(LoomError::NothingToContinue);
+		}
 
-		debug!("Saving tapestry fragment: {:?}", tapestry_fragment_to_persist);
+		trace!("Continuing the story of {:?}", tapestry_id);
 
-		// Save tapestry fragment to database
-		// When summarized, the tapestry_fragment will be saved under a new instance
-		let tapestry_fragment_id = self
-			.chest
-			.save_tapestry_fragment(
+		self.weave_observed(
+			prompt_llm_config,
+			summary_llm_config,
+			tapestry_id,
+			instructions,
+			Vec::new(),
+			AssembleMode::Weave,
+			None,
+		)
+		.await
+		.map(|(result, _)| result)
+	}
+
+	/// Assembles the prompt [`Loom::weave`] would send to the LLM, without prompting it or
+	/// persisting anything.
+	///
+	/// Neither generates a summary, nor checks the [`Config::MIN_PROMPT_INTERVAL`], nor draws one
+	/// of the [`Config::RANDOM_EVENTS`]. Fails with the same errors as [`Loom::weave`] would
+	/// before prompting.
+	pub async fn weave_dry_run<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+	) -> Result<PromptPreview<T>, LoomError<T>> {
+		let assembled_prompt = self
+			.assemble_prompt(
+				&prompt_llm_config,
+				&summary_llm_config,
 				&tapestry_id,
-				tapestry_fragment_to_persist,
-				was_summary_generated,
+				instructions,
+				msgs,
+				AssembleMode::DryRun,
 			)
-			.await
-			.map_err(|e| {
-				error!("Failed to save tapestry fragment: {}", e);
-				e
-			})?;
+			.await?;
 
-		Ok((response, tapestry_fragment_id, was_summary_generated))
+		Ok(PromptPreview {
+			prompt_tokens: assembled_prompt.req_msgs.tokens,
+			msgs: assembled_prompt.req_msgs.into_vec(),
+			max_completion_tokens: assembled_prompt.max_completion_tokens,
+			would_summarize: assembled_prompt.was_summary_generated,
+		})
 	}
 
-	/// Generates the summary of the current [`TapestryFragment`] instance.
+	/// Estimates the cost of the prompt [`Loom::weave`] would send, from the prompt tokens alone up
+	/// to a response of the max completion tokens, without prompting the LLM.
 	///
-	/// Returns the summary message as a string.
-	async fn generate_summary(
-		summary_model_config: &LlmConfig<T, T::SummaryModel>,
-		tapestry_fragment: &TapestryFragment<T>,
-		summary_max_tokens: SummaryModelTokens<T>,
-	) -> Result<String, LoomError<T>> {
-		trace!(
-			"Generating summary with max tokens: {:?}, for tapestry fragment: {:?}",
-			summary_max_tokens,
-			tapestry_fragment
-		);
+	/// The prompt is assembled like [`Loom::weave_dry_run`], so the cost of a summary is left out.
+	pub async fn estimate_cost<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+	) -> Result<CostEstimate, LoomError<T>> {
+		let model = prompt_llm_config.model;
+		let preview = self
+			.weave_dry_run(prompt_llm_config, summary_llm_config, tapestry_id, instructions, msgs)
+			.await?;
 
-		let mut summary_generation_prompt = VecPromptMsgsDeque::<T, T::SummaryModel>::new();
+		Ok(CostEstimate {
+			prompt_tokens: preview.prompt_tokens.to_u64().unwrap_or(u64::MAX),
+			max_completion_tokens: preview.max_completion_tokens.to_u64().unwrap_or(u64::MAX),
+			min_cost: model.compute_cost(preview.prompt_tokens, Zero::zero()),
+			max_cost: model.compute_cost(preview.prompt_tokens, preview.max_completion_tokens),
+			would_summarize: preview.would_summarize,
+		})
+	}
 
-		summary_generation_prompt.extend(
-			summary_model_config
-				.model
-				.ctx_msgs_to_prompt_requests(tapestry_fragment.context_messages.as_slice()),
-		);
+	/// Summarizes the current [`TapestryFragment`] instance into a new one, as [`Loom::weave`] does
+	/// once the max prompt tokens are exceeded, e.g. to close a chapter of the story.
+	///
+	/// The players, language, daily usage and story length are carried over. Returns the summary
+	/// along with the new instance.
+	///
+	/// Fails with [`LoomError::NothingToSummarize`] if the tapestry holds no message.
+	pub async fn summarize<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+	) -> Result<(String, u64), LoomError<T>> {
+		summary_llm_config.check_capabilities()?;
+		let _guard = self.lock_tapestry(&tapestry_id).await;
 
-		let res = summary_model_config
-			.model
-			.prompt(
-				true,
-				summary_generation_prompt.tokens,
-				summary_generation_prompt.into_vec(),
-				&summary_model_config.params,
-				summary_max_tokens,
-			)
-			.await
-			.map_err(|e| {
-				error!("Failed to prompt LLM: {}", e);
-				e
-			})?;
+		let tapestry_fragment = self
+			.chest
+			.get_tapestry_fragment(tapestry_id.clone(), None)
+			.await?
+			.filter(|tapestry_fragment| !tapestry_fragment.context_messages.is_empty())
+			.ok_or(LoomError::NothingToSummarize)?;
 
-		let summary_response_content = res.into();
+		let (summary, mut new_tapestry_fragment, _) = self
+			.summarize_tapestry_fragment(
+				&prompt_llm_config,
+				&summary_llm_config,
+				&tapestry_id,
+				&tapestry_fragment,
+			)
+			.await?;
+		new_tapestry_fragment.language = tapestry_fragment.language;
+		new_tapestry_fragment.players = tapestry_fragment.players;
+		new_tapestry_fragment.daily_usage = tapestry_fragment.daily_usage;
+		new_tapestry_fragment.story_length = tapestry_fragment.story_length;
 
-		trace!("Generated summary: {:?}", summary_response_content);
+		let instance = self
+			.chest
+			.save_tapestry_fragment(&tapestry_id, new_tapestry_fragment, true)
+			.await?;
 
-		Ok(summary_response_content.unwrap_or_default())
+		Ok((summary, instance))
+	}
+
+	/// Weaves the tapestry, recording the outcome in the metrics and publishing errors.
+	#[allow(clippy::too_many_arguments)]
+	async fn weave_observed<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+		mode: AssembleMode<T>,
+		on_delta: Option<&mut (dyn FnMut(&str) + Send)>,
+	) -> Result<(WeaveResult<T>, Usage), LoomError<T>> {
+		let _active = ActiveWeave::start(&self.metrics);
+		let result = self
+			.weave_tapestry(
+				prompt_llm_config,
+				summary_llm_config,
+				tapestry_id.clone(),
+				instructions,
+				msgs,
+				mode,
+				on_delta,
+			)
+			.await;
+
+		if let Err(e) = &result {
+			*self.metrics.lock().unwrap().errors.entry(e.kind()).or_default() += 1;
+			self.publish(&tapestry_id, LoomEvent::Error(e.to_string()));
+		}
+
+		result
+	}
+
+	/// Same as [`Loom::weave`], except that weaving the same `idempotency_key` for the
+	/// [`TapestryId`] again within the [`Config::IDEMPOTENCY_WINDOW`] returns the previous result
+	/// without prompting the LLM or appending any message.
+	///
+	/// Allows clients to safely retry a weave whose outcome is unknown, e.g. after a network
+	/// error. If `idempotency_key` is `None`, it is derived from the content, role and
+	/// `account_id` of the `msgs`. Failed weaves are not remembered.
+	pub async fn weave_idempotent<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+		idempotency_key: Option<String>,
+	) -> Result<WeaveResult<T>, LoomError<T>> {
+		let idempotency_key = idempotency_key.unwrap_or_else(|| {
+			let mut hasher = DefaultHasher::new();
+			for msg in &msgs {
+				String::from(msg.role.clone()).hash(&mut hasher);
+				msg.content.hash(&mut hasher);
+				msg.account_id.hash(&mut hasher);
+			}
+			format!("{:x}", hasher.finish())
+		});
+		let key = (tapestry_id.base_key(), idempotency_key);
+
+		// Held until the result is remembered, so that a concurrent retry waits for it instead
+		// of weaving the same messages again
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		{
+			let results = &mut self.idempotent_results.lock().unwrap().0;
+			results.retain(|_, (woven_at, _)| woven_at.elapsed() < T::IDEMPOTENCY_WINDOW);
+			if let Some((_, result)) = results.get(&key) {
+				debug!("Returning previous result for idempotency key {:?}", key.1);
+				return Ok(result.clone());
+			}
+		}
+
+		let (result, _) = self
+			.weave_observed(
+				prompt_llm_config,
+				summary_llm_config,
+				tapestry_id,
+				instructions,
+				msgs,
+				AssembleMode::Weave,
+				None,
+			)
+			.await?;
+		self.idempotent_results
+			.lock()
+			.unwrap()
+			.0
+			.insert(key, (Instant::now(), result.clone()));
+
+		Ok(result)
+	}
+
+	/// Returns the counters of this `Loom` in the Prometheus text exposition format, to be served
+	/// on a `/metrics` endpoint.
+	///
+	/// Prompts and tokens include every LLM call, summaries included. Errors are those returned
+	/// by [`Loom::weave`], labeled with their [`LoomError::kind`]. Active weaves are those of any
+	/// tapestry still awaiting a response.
+	pub fn metrics_snapshot(&self) -> String {
+		let metrics = self.metrics.lock().unwrap();
+		let mut snapshot = String::new();
+		let mut write_metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+			snapshot.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+			for (labels, value) in samples {
+				snapshot.push_str(&format!("{name}{labels} {value}\n"));
+			}
+		};
+
+		write_metric(
+			"loom_prompts_total",
+			"counter",
+			"Number of prompts sent to the LLMs.",
+			vec![(String::new(), metrics.prompts)],
+		);
+		write_metric(
+			"loom_prompt_tokens_total",
+			"counter",
+			"Number of tokens sent to the LLMs.",
+			vec![(String::new(), metrics.prompt_tokens)],
+		);
+		write_metric(
+			"loom_completion_tokens_total",
+			"counter",
+			"Number of tokens generated by the LLMs.",
+			vec![(String::new(), metrics.completion_tokens)],
+		);
+		write_metric(
+			"loom_errors_total",
+			"counter",
+			"Number of failed weaves by error kind.",
+			metrics
+				.errors
+				.iter()
+				.map(|(kind, count)| (format!("{{kind=\"{kind}\"}}"), *count))
+				.collect(),
+		);
+		write_metric(
+			"loom_active_weaves",
+			"gauge",
+			"Number of weaves in progress.",
+			vec![(String::new(), metrics.active_weaves)],
+		);
+
+		snapshot
+	}
+
+	/// Subscribes to the [`LoomEvent`]s published for the [`TapestryId`].
+	///
+	/// Subscribers lagging behind by more than [`Config::EVENT_CHANNEL_CAPACITY`] events miss the
+	/// oldest ones.
+	pub fn subscribe<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+	) -> broadcast::Receiver<LoomEvent<T>> {
+		self.events
+			.lock()
+			.unwrap()
+			.entry(tapestry_id.base_key())
+			.or_insert_with(|| broadcast::channel(T::EVENT_CHANNEL_CAPACITY).0)
+			.subscribe()
+	}
+
+	/// Publishes `event` to the subscribers of the [`TapestryId`], if any.
+	fn publish<TID: TapestryId>(&self, tapestry_id: &TID, event: LoomEvent<T>) {
+		let mut events = self.events.lock().unwrap();
+		let key = tapestry_id.base_key();
+		if let Some(sender) = events.get(&key) {
+			// Drop the channel once every subscriber is gone
+			if sender.send(event).is_err() {
+				events.remove(&key);
+			}
+		}
+	}
+
+	/// Assembles the prompt of `msgs` woven into the current [`TapestryFragment`] instance,
+	/// generating a summary if needed.
+	///
+	/// See [`AssembleMode`] for the steps skipped by dry runs and regenerations.
+	async fn assemble_prompt<TID: TapestryId>(
+		&self,
+		prompt_llm_config: &LlmConfig<T, T::PromptModel>,
+		summary_llm_config: &LlmConfig<T, T::SummaryModel>,
+		tapestry_id: &TID,
+		instructions: String,
+		mut msgs: Vec<ContextMessage<T>>,
+		mode: AssembleMode<T>,
+	) -> Result<AssembledPrompt<T>, LoomError<T>> {
+		prompt_llm_config.check_capabilities()?;
+
+		let mut usage = Usage::default();
+		summary_llm_config.check_capabilities()?;
+
+		let (dry_run, regenerated_tapestry_fragment) = match mode {
+			AssembleMode::Weave => (false, None),
+			AssembleMode::DryRun => (true, None),
+			AssembleMode::Regenerate(tapestry_fragment) => (false, Some(tapestry_fragment)),
+		};
+		let is_regenerating = regenerated_tapestry_fragment.is_some();
+
+		// Regenerated messages were already transformed, moderated and split when first woven
+		if !is_regenerating {
+			for msg in msgs.iter_mut() {
+				self.hooks.before_prompt(&mut msg.content).await;
+			}
+
+			if T::MODERATE_MESSAGES {
+				let mut categories = Vec::new();
+				for msg in msgs.iter() {
+					for category in self.hooks.moderate(&msg.content).await? {
+						if !categories.contains(&category) {
+							categories.push(category);
+						}
+					}
+				}
+				if !categories.is_empty() {
+					debug!("Messages flagged by moderation: {:?}", categories);
+					return Err(LoomError::Moderated { categories });
+				}
+			}
+
+			if !dry_run {
+				self.check_cooldown(tapestry_id)?;
+			}
+
+			if let Some(max_message_tokens) = T::MAX_MESSAGE_TOKENS {
+				let max_message_tokens = PromptModelTokens::<T>::from_u64(max_message_tokens)
+					.ok_or_else(|| {
+						LoomError::BadConfig("MAX_MESSAGE_TOKENS exceeds model tokens".to_string())
+					})?;
+				msgs = msgs
+					.into_iter()
+					.map(|m| Self::split_message(m, max_message_tokens))
+					.collect::<Result<Vec<_>, _>>()?
+					.into_iter()
+					.flatten()
+					.collect();
+			}
+		}
+
+		let instructions = match T::instructions_path() {
+			Some(path) => self.load_instructions(&path)?,
+			None => instructions,
+		};
+		let instructions_ctx_msg =
+			Self::build_context_message(SYSTEM_ROLE.into(), instructions, None);
+		let instructions_req_msg: PromptModelRequest<T> = instructions_ctx_msg.clone().into();
+
+		trace!("Fetching current tapestry fragment for ID: {:?}", tapestry_id);
+
+		let current_tapestry_fragment = match regenerated_tapestry_fragment {
+			Some(tapestry_fragment) => tapestry_fragment,
+			None => self
+				.chest
+				.get_tapestry_fragment(tapestry_id.clone(), None)
+				.await?
+				.unwrap_or_default(),
+		};
+
+		let daily_usage = Self::check_daily_limit(&current_tapestry_fragment)?;
+
+		let story_length = current_tapestry_fragment.story_length;
+		let story_progress = story_length.progress::<T>();
+		if story_progress.is_some_and(|progress| progress >= 1.0) {
+			debug!("Story {:?} reached its maximum length: {:?}", tapestry_id, story_length);
+			return Err(LoomError::StoryComplete);
+		}
+
+		let mut players = current_tapestry_fragment.players.clone();
+		for account_id in msgs.iter().filter_map(|m| m.account_id.as_ref()) {
+			Self::join_players(&mut players, account_id);
+		}
+
+		// Language is pinned to the first one detected in the tapestry
+		let language = current_tapestry_fragment
+			.language
+			.clone()
+			.or_else(|| msgs.iter().find_map(|m| T::detect_language(&m.content)));
+
+		let mut prompt_params = prompt_llm_config.params.clone();
+		if let Some(sampling) =
+			T::SAMPLING_PARAMETERS.or_else(|| T::GENRE_PRESET.map(|genre| genre.sampling()))
+		{
+			if sampling.seed.is_some() && !prompt_llm_config.model.capabilities().seed {
+				return Err(LoomError::UnsupportedCapability {
+					model: prompt_llm_config.model.name(),
+					capability: "seed",
+				});
+			}
+			prompt_llm_config.model.set_sampling(&mut prompt_params, sampling);
+		}
+		if T::STOP_AT_SPEAKERS || !T::STOP_SEQUENCES.is_empty() {
+			let mut stop: Vec<String> = T::STOP_SEQUENCES.iter().map(|s| s.to_string()).collect();
+			if T::STOP_AT_SPEAKERS {
+				let speaker_msgs =
+					[current_tapestry_fragment.context_messages.as_slice(), msgs.as_slice()]
+						.concat();
+				for prefix in Self::speaker_stop_sequences(&speaker_msgs) {
+					if !stop.contains(&prefix) {
+						stop.push(prefix);
+					}
+				}
+			}
+			stop.truncate(T::PromptModel::MAX_STOP_SEQUENCES);
+			prompt_llm_config.model.set_stop_sequences(&mut prompt_params, stop);
+		}
+
+		// Get max token limit which cannot be exceeded in a tapestry fragment
+		let max_prompt_tokens_limit = prompt_llm_config.model.get_max_prompt_token_limit();
+
+		// Request messages which will be sent as a whole to the LLM
+		let mut req_msgs = VecPromptMsgsDeque::<T, T::PromptModel>::with_capacity(
+			current_tapestry_fragment.context_messages.len() + 1,
+		);
+
+		// Add instructions as the first message
+		req_msgs.push_front(instructions_req_msg);
+		let instructions_tokens = req_msgs.tokens;
+
+		// Convert and append all tapestry fragment messages to the request messages.
+		req_msgs.append(&mut self.history_requests(
+			&prompt_llm_config.model,
+			&current_tapestry_fragment.context_messages,
+		));
+
+		// New messages are not added here yet since we first calculate if the new `msgs` would
+		// have the tapestry fragment exceed the maximum token limit and require a summary
+		// generation resulting in a new tapestry fragment.
+		//
+		// Either we are starting a new tapestry fragment with the instruction and summary messages
+		// or we are continuing the current tapestry fragment.
+		let msgs_tokens = Self::count_tokens_in_messages(&msgs).await?;
+
+		// Summarizing cannot make room for new messages which do not fit alongside the
+		// instructions alone
+		if max_prompt_tokens_limit <= instructions_tokens.saturating_add(&msgs_tokens) {
+			debug!("New messages of {:?} tokens exceed the max prompt tokens", msgs_tokens);
+			return Err(LoomError::MessagesExceedContext);
+		}
+
+		trace!(
+			"Total tokens after adding new messages: {:?}, maximum allowed: {:?}",
+			req_msgs.tokens.saturating_add(&msgs_tokens),
+			max_prompt_tokens_limit
+		);
+
+		// Check if the total number of tokens in the tapestry fragment exceeds the maximum number
+		// of tokens allowed after adding the new messages and the minimum response length.
+		let exceeds_max_token_limit = |req_msgs_tokens: PromptModelTokens<T>| {
+			max_prompt_tokens_limit <=
+				req_msgs_tokens.saturating_add(&msgs_tokens).saturating_add(
+					&PromptModelTokens::<T>::from_u64(T::MINIMUM_RESPONSE_LENGTH).unwrap(),
+				)
+		};
+
+		let (tapestry_fragment_to_persist, was_summary_generated, is_new_instance) =
+			if !exceeds_max_token_limit(req_msgs.tokens) {
+				(current_tapestry_fragment, false, false)
+			} else if let ContextStrategy::SlidingWindow { keep_last } = T::CONTEXT_STRATEGY {
+				// Whole turns are dropped, oldest first, so that no response is kept without the
+				// messages it replied to. The summary starting the instance is always kept, as
+				// are the most recent messages, new messages being the most recent
+				let kept = keep_last.saturating_sub(msgs.len());
+				let assistant_role = WrapperRole::from(ASSISTANT_ROLE);
+				let window = self.redact_messages(&current_tapestry_fragment.context_messages);
+				let summary_len = usize::from(
+					window.first().is_some_and(|m| m.content.starts_with(SUMMARY_PREFIX)),
+				);
+
+				// Each message is counted once, the window being recounted from the messages kept
+				let model = &prompt_llm_config.model;
+				let costs = window
+					.iter()
+					.map(|m| Self::request_token_cost(model, m))
+					.collect::<Result<Vec<_>, _>>()?;
+				let refresher = Self::memory_refresher(&window)
+					.map(|(interval, refresher)| {
+						Self::request_token_cost(model, &refresher).map(|tokens| (interval, tokens))
+					})
+					.transpose()?;
+				let sum = |costs: &[PromptModelTokens<T>]| {
+					costs.iter().fold(PromptModelTokens::<T>::zero(), |total, tokens| {
+						total.saturating_add(tokens)
+					})
+				};
+				let summary_tokens =
+					instructions_tokens.saturating_add(&sum(&costs[..summary_len]));
+				let window_tokens = |start: usize, kept_tokens: PromptModelTokens<T>| {
+					let tokens = summary_tokens.saturating_add(&kept_tokens);
+					match refresher {
+						Some((interval, refresher_tokens)) => {
+							let len = summary_len + window.len() - start;
+							let refreshers = len.saturating_sub(2) / interval;
+							tokens.saturating_add(&refresher_tokens.saturating_mul(
+								&PromptModelTokens::<T>::from_usize(refreshers).unwrap_or_default(),
+							))
+						},
+						None => tokens,
+					}
+				};
+
+				let mut start = summary_len;
+				let mut kept_tokens = sum(&costs[start..]);
+				while exceeds_max_token_limit(window_tokens(start, kept_tokens)) {
+					// A turn runs up to the next response, or is a single message if none follows
+					let turn_end = window[start..]
+						.iter()
+						.position(|m| m.role == assistant_role)
+						.map_or(start + 1, |i| start + i + 1);
+					if turn_end > window.len() || window.len() - turn_end < kept {
+						return Err(LoomError::SlidingWindowExceedsContext { keep_last });
+					}
+					kept_tokens = kept_tokens.saturating_sub(&sum(&costs[start..turn_end]));
+					start = turn_end;
+				}
+
+				let mut context_messages = current_tapestry_fragment.context_messages;
+				context_messages.drain(summary_len..start);
+				trace!("Sliding window kept the last {} messages", context_messages.len());
+
+				req_msgs.truncate(1);
+				req_msgs.append(&mut self.history_requests(model, &context_messages));
+
+				let mut windowed_tapestry_fragment = TapestryFragment {
+					context_tokens: Zero::zero(),
+					context_messages: Vec::new(),
+					..current_tapestry_fragment
+				};
+				windowed_tapestry_fragment.extend_messages(context_messages)?;
+
+				(windowed_tapestry_fragment, false, false)
+			} else if T::CONTEXT_STRATEGY == ContextStrategy::Truncate {
+				trace!("Starting a new tapestry fragment as the token limit exceeded");
+
+				req_msgs.truncate(1);
+
+				(TapestryFragment::new(), false, true)
+			} else if dry_run {
+				// The summary is left out rather than generated
+				req_msgs.truncate(1);
+
+				(TapestryFragment::new(), true, true)
+			} else {
+				trace!("Generating summary as the token limit exceeded");
+
+				let (_, new_tapestry_fragment, summary_usage) = self
+					.summarize_tapestry_fragment(
+						prompt_llm_config,
+						summary_llm_config,
+						tapestry_id,
+						&current_tapestry_fragment,
+					)
+					.await?;
+				usage += summary_usage;
+
+				// Truncate all tapestry fragment messages except for the instructions and add the
+				// summary
+				req_msgs.truncate(1);
+				req_msgs.extend(
+					new_tapestry_fragment
+						.context_messages
+						.iter()
+						.map(|m| m.clone().into())
+						.collect(),
+				);
+
+				(new_tapestry_fragment, true, true)
+			};
+
+		// Add the past messages most relevant to the new ones, which may have been summarized
+		// away, as long as they leave room for the minimum response length
+		if let Some(recalled) = self
+			.recall(tapestry_id, &msgs, &tapestry_fragment_to_persist.context_messages)
+			.await?
+		{
+			let recalled_tokens = Self::count_tokens(recalled.content.clone()).await?;
+			if exceeds_max_token_limit(req_msgs.tokens.saturating_add(&recalled_tokens)) {
+				debug!("Recalled messages of {:?} tokens exceed the context", recalled_tokens);
+			} else {
+				req_msgs.push_back(recalled.into());
+			}
+		}
+
+		// Add new messages to the request messages, redacted messages are only sent to the LLM
+		// while `msgs` are persisted as is
+		req_msgs
+			.extend(Self::prompt_requests(&prompt_llm_config.model, &self.redact_messages(&msgs)));
+
+		// Add the language and response length instructions, which are sent to the LLM but never
+		// persisted
+		if let Some(language) = &language {
+			req_msgs.push_back(
+				Self::build_context_message(
+					SYSTEM_ROLE.into(),
+					format!("Maintain {} throughout your response.", language),
+					None,
+				)
+				.into(),
+			);
+		}
+
+		if let Some(genre) = T::GENRE_PRESET {
+			req_msgs.push_back(
+				Self::build_context_message(
+					SYSTEM_ROLE.into(),
+					genre.directive().to_string(),
+					None,
+				)
+				.into(),
+			);
+		}
+
+		if let Some(event) =
+			(!dry_run && !is_regenerating).then(|| self.draw_random_event()).flatten()
+		{
+			trace!("Introducing random event: {}", event);
+			req_msgs.push_back(
+				Self::build_context_message(
+					SYSTEM_ROLE.into(),
+					format!("Introduce the following event to the story: {}.", event),
+					None,
+				)
+				.into(),
+			);
+		}
+
+		if T::STAY_IN_CHARACTER {
+			req_msgs.push_back(
+				Self::build_context_message(
+					SYSTEM_ROLE.into(),
+					"Stay in character. Never refer to yourself as an AI or add commentary outside \
+					 of the story."
+						.to_string(),
+					None,
+				)
+				.into(),
+			);
+		}
+
+		if story_progress.is_some_and(|progress| progress >= T::STORY_CONCLUSION_THRESHOLD) {
+			req_msgs.push_back(
+				Self::build_context_message(
+					SYSTEM_ROLE.into(),
+					"The story is nearing its end. Begin wrapping up its plot lines towards a \
+					 conclusion."
+						.to_string(),
+					None,
+				)
+				.into(),
+			);
+		}
+
+		// Length limits conflict with structured output
+		let response_sentences = T::RESPONSE_SENTENCES
+			.filter(|_| !prompt_llm_config.model.required_capabilities(&prompt_params).json_mode);
+
+		if let (Some(sentences), Some(instruction)) =
+			(response_sentences, T::RESPONSE_LENGTH_INSTRUCTION)
+		{
+			req_msgs.push_back(
+				Self::build_context_message(
+					SYSTEM_ROLE.into(),
+					instruction.replace("{}", &sentences.to_string()),
+					None,
+				)
+				.into(),
+			);
+		}
+
+		// Tokens available for LLM response which would not exceed maximum token limit
+		let mut max_completion_tokens = max_prompt_tokens_limit.saturating_sub(&req_msgs.tokens);
+
+		if let Some(sentences) = response_sentences {
+			max_completion_tokens = max_completion_tokens
+				.min(prompt_llm_config.model.convert_sentences_to_tokens(sentences));
+		}
+
+		if let Some(max_response_tokens) =
+			T::MAX_RESPONSE_TOKENS.and_then(PromptModelTokens::<T>::from_u64)
+		{
+			max_completion_tokens = max_completion_tokens.min(max_response_tokens);
+		}
+
+		if let Some(max_daily_tokens) = T::MAX_DAILY_COMPLETION_TOKENS {
+			let remaining_tokens = PromptModelTokens::<T>::from_u64(
+				max_daily_tokens.saturating_sub(daily_usage.completion_tokens),
+			)
+			.unwrap_or(max_completion_tokens);
+			max_completion_tokens = max_completion_tokens.min(remaining_tokens);
+		}
+
+		trace!("Max completion tokens available: {:?}", max_completion_tokens);
+
+		if max_completion_tokens.is_zero() {
+			return Err(LoomError::MaxCompletionTokensIsZero);
+		}
+
+		Ok(AssembledPrompt {
+			req_msgs,
+			prompt_params,
+			max_prompt_tokens_limit,
+			max_completion_tokens,
+			msgs,
+			msgs_tokens,
+			tapestry_fragment_to_persist,
+			was_summary_generated,
+			is_new_instance,
+			language,
+			players,
+			daily_usage,
+			story_length,
+			usage,
+		})
+	}
+
+	#[allow(clippy::too_many_arguments)]
+	async fn weave_tapestry<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+		instructions: String,
+		msgs: Vec<ContextMessage<T>>,
+		mode: AssembleMode<T>,
+		on_delta: Option<&mut (dyn FnMut(&str) + Send)>,
+	) -> Result<(WeaveResult<T>, Usage), LoomError<T>> {
+		if T::CHECKPOINT_STREAMS && matches!(mode, AssembleMode::Weave) {
+			self.finalize_stream_checkpoint(&tapestry_id).await?;
+		}
+
+		let AssembledPrompt {
+			req_msgs,
+			prompt_params,
+			max_prompt_tokens_limit,
+			max_completion_tokens,
+			mut msgs,
+			msgs_tokens,
+			mut tapestry_fragment_to_persist,
+			was_summary_generated,
+			is_new_instance,
+			language,
+			players,
+			mut daily_usage,
+			mut story_length,
+			mut usage,
+		} = self
+			.assemble_prompt(
+				&prompt_llm_config,
+				&summary_llm_config,
+				&tapestry_id,
+				instructions,
+				msgs,
+				mode,
+			)
+			.await?;
+
+		trace!("Prompting LLM with request messages");
+
+		// Deltas streamed so far, persisted as a partial response if the stream fails
+		let mut partial_response = String::new();
+		let checkpointed = T::CHECKPOINT_STREAMS && on_delta.is_some();
+		let result = match on_delta {
+			Some(on_delta) => {
+				let (checkpoint_tx, checkpoint_rx) = mpsc::unbounded_channel();
+				let prompt = async {
+					// Dropped once the stream ends, ending the checkpoints
+					let checkpoint_tx = T::CHECKPOINT_STREAMS.then_some(checkpoint_tx);
+					let mut accumulate = |delta: &str| {
+						partial_response.push_str(delta);
+						if let Some(checkpoint_tx) = &checkpoint_tx {
+							let _ = checkpoint_tx.send(delta.to_string());
+						}
+						on_delta(delta);
+					};
+					self.prompt_model(
+						&prompt_llm_config.model,
+						false,
+						req_msgs.tokens,
+						req_msgs.inner.iter().cloned().collect(),
+						&prompt_params,
+						max_completion_tokens,
+						Some(&mut accumulate),
+					)
+					.await
+				};
+				let (result, ()) = tokio::join!(
+					prompt,
+					self.checkpoint_stream(&tapestry_id, &msgs, checkpoint_rx)
+				);
+				result
+			},
+			None =>
+				self.prompt_model(
+					&prompt_llm_config.model,
+					false,
+					req_msgs.tokens,
+					req_msgs.inner.iter().cloned().collect(),
+					&prompt_params,
+					max_completion_tokens,
+					None,
+				)
+				.await,
+		};
+		let response = match result {
+			Ok((response, prompt_usage)) => {
+				usage += prompt_usage;
+				response
+			},
+			Err(e) if partial_response.is_empty() => return Err(e),
+			Err(e) => {
+				debug!("Saving partial response of interrupted stream");
+
+				let partial_tokens = Self::count_tokens(partial_response.clone()).await?;
+				let e = match e {
+					LoomError::Timeout { .. } =>
+						LoomError::PartialTimeout(partial_response.clone()),
+					e => e,
+				};
+				msgs.push(Self::build_context_message(
+					ASSISTANT_ROLE.into(),
+					partial_response,
+					None,
+				));
+				let appended_msgs = msgs.clone();
+				tapestry_fragment_to_persist.extend_messages(msgs)?;
+				tapestry_fragment_to_persist.language = language;
+				tapestry_fragment_to_persist.players = players;
+				daily_usage.completion_tokens = daily_usage
+					.completion_tokens
+					.saturating_add(partial_tokens.to_u64().unwrap_or(u64::MAX));
+				tapestry_fragment_to_persist.daily_usage = Some(daily_usage);
+				story_length.turns += 1;
+				story_length.tokens = story_length.tokens.saturating_add(
+					msgs_tokens.saturating_add(&partial_tokens).to_u64().unwrap_or(u64::MAX),
+				);
+				tapestry_fragment_to_persist.story_length = story_length;
+
+				self.chest
+					.save_tapestry_fragment(
+						&tapestry_id,
+						tapestry_fragment_to_persist,
+						is_new_instance,
+					)
+					.await?;
+				if checkpointed {
+					self.chest.delete_stream_checkpoint(tapestry_id.clone()).await?;
+				}
+				self.remember(&tapestry_id, &appended_msgs).await?;
+				self.touch_story_metadata(&tapestry_id).await?;
+				for msg in appended_msgs {
+					self.publish(&tapestry_id, LoomEvent::MessageAppended(msg));
+				}
+
+				return Err(e);
+			},
+		};
+
+		let response = match self
+			.finish_response(
+				&prompt_llm_config,
+				&req_msgs,
+				&prompt_params,
+				max_prompt_tokens_limit,
+				response,
+				&mut usage,
+			)
+			.await
+		{
+			Ok(response) => response,
+			Err(e) => {
+				// The streamed response was rejected, so it must not be recovered by the next weave
+				if checkpointed {
+					self.chest.delete_stream_checkpoint(tapestry_id.clone()).await?;
+				}
+				return Err(e);
+			},
+		};
+
+		let response_content = match prompt_llm_config.model.tool_call(&response) {
+			Some(tool_call) => serde_json::to_string(&tool_call)
+				.map_err(|e| LoomError::UnknownError(e.to_string()))?,
+			None => response.clone().into().unwrap_or_default(),
+		};
+		let response_tokens = Self::count_tokens(response_content.clone()).await?;
+		daily_usage.completion_tokens = daily_usage
+			.completion_tokens
+			.saturating_add(response_tokens.to_u64().unwrap_or(u64::MAX));
+		story_length.turns += 1;
+		story_length.tokens = story_length.tokens.saturating_add(
+			msgs_tokens.saturating_add(&response_tokens).to_u64().unwrap_or(u64::MAX),
+		);
+
+		// Add LLM response to the tapestry fragment messages to save
+		msgs.push(Self::build_context_message(ASSISTANT_ROLE.into(), response_content, None));
+
+		for msg in msgs.iter_mut() {
+			msg.importance = msg.importance.or_else(|| T::score_importance(msg));
+		}
+
+		// Add new messages and response to the tapestry fragment which will be persisted in the
+		// database
+		let appended_msgs = msgs.clone();
+		tapestry_fragment_to_persist.extend_messages(msgs)?;
+		tapestry_fragment_to_persist.language = language;
+		tapestry_fragment_to_persist.players = players;
+		tapestry_fragment_to_persist.daily_usage = Some(daily_usage);
+		tapestry_fragment_to_persist.story_length = story_length;
+
+		debug!("Saving tapestry fragment: {:?}", tapestry_fragment_to_persist);
+
+		// Save tapestry fragment to database
+		// When summarized or truncated, the tapestry_fragment will be saved under a new instance
+		let tapestry_fragment_id = self
+			.chest
+			.save_tapestry_fragment(&tapestry_id, tapestry_fragment_to_persist, is_new_instance)
+			.await
+			.map_err(|e| {
+				error!("Failed to save tapestry fragment: {}", e);
+				e
+			})?;
+		if checkpointed {
+			self.chest.delete_stream_checkpoint(tapestry_id.clone()).await?;
+		}
+		self.remember(&tapestry_id, &appended_msgs).await?;
+		self.touch_story_metadata(&tapestry_id).await?;
+
+		for msg in appended_msgs {
+			self.publish(&tapestry_id, LoomEvent::MessageAppended(msg));
+		}
+
+		Ok(((response, tapestry_fragment_id, was_summary_generated), usage))
+	}
+
+	/// Completes the `response` to the `req_msgs`, continuing it while truncated and asking for a
+	/// corrected one while invalid, then applies [`Config::STAY_IN_CHARACTER`] and
+	/// [`Hooks::after_response`] to it.
+	async fn finish_response(
+		&self,
+		prompt_llm_config: &LlmConfig<T, T::PromptModel>,
+		req_msgs: &VecPromptMsgsDeque<T, T::PromptModel>,
+		prompt_params: &<T::PromptModel as Llm<T>>::Parameters,
+		max_prompt_tokens_limit: PromptModelTokens<T>,
+		mut response: <T::PromptModel as Llm<T>>::Response,
+		usage: &mut Usage,
+	) -> Result<<T::PromptModel as Llm<T>>::Response, LoomError<T>> {
+		// Continue truncated responses by sending the response so far followed by a continuation
+		// request, neither of which are persisted
+		let mut continuations = 0;
+		while continuations < T::MAX_CONTINUATIONS &&
+			prompt_llm_config.model.is_truncated(&response)
+		{
+			continuations += 1;
+
+			let mut continuation_req_msgs = VecPromptMsgsDeque::<T, T::PromptModel>::new();
+			continuation_req_msgs.extend(req_msgs.inner.iter().cloned().collect());
+			continuation_req_msgs.push_back(
+				Self::build_context_message(
+					ASSISTANT_ROLE.into(),
+					response.clone().into().unwrap_or_default(),
+					None,
+				)
+				.into(),
+			);
+			continuation_req_msgs.push_back(
+				Self::build_context_message(
+					USER_ROLE.into(),
+					"Continue exactly where you left off.".to_string(),
+					None,
+				)
+				.into(),
+			);
+
+			let max_continuation_tokens =
+				max_prompt_tokens_limit.saturating_sub(&continuation_req_msgs.tokens);
+			if max_continuation_tokens.is_zero() {
+				debug!("No tokens left to continue truncated response");
+				break;
+			}
+
+			trace!("Continuing truncated response, attempt {}", continuations);
+
+			let (continuation, continuation_usage) = self
+				.prompt_model(
+					&prompt_llm_config.model,
+					false,
+					continuation_req_msgs.tokens,
+					continuation_req_msgs.into_vec(),
+					prompt_params,
+					max_continuation_tokens,
+					None,
+				)
+				.await?;
+			*usage += continuation_usage;
+			response = prompt_llm_config.model.append_response(response, continuation);
+		}
+
+		// Ask for a corrected response, without persisting the invalid ones, until it is valid
+		let mut validation_retries = 0;
+		while let Err(validation_error) =
+			T::validate_response(&response.clone().into().unwrap_or_default())
+		{
+			if validation_retries == T::MAX_VALIDATION_RETRIES {
+				return Err(LoomError::SchemaValidation(validation_error));
+			}
+			validation_retries += 1;
+
+			trace!(
+				"Retrying invalid response, attempt {}: {}",
+				validation_retries,
+				validation_error
+			);
+
+			let mut retry_req_msgs = VecPromptMsgsDeque::<T, T::PromptModel>::new();
+			retry_req_msgs.extend(req_msgs.inner.iter().cloned().collect());
+			retry_req_msgs.push_back(
+				Self::build_context_message(
+					ASSISTANT_ROLE.into(),
+					response.clone().into().unwrap_or_default(),
+					None,
+				)
+				.into(),
+			);
+			retry_req_msgs.push_back(
+				Self::build_context_message(
+					USER_ROLE.into(),
+					format!(
+						"Your response is invalid: {}. Respond again with a corrected response \
+						 only.",
+						validation_error
+					),
+					None,
+				)
+				.into(),
+			);
+
+			let max_retry_tokens = max_prompt_tokens_limit.saturating_sub(&retry_req_msgs.tokens);
+			if max_retry_tokens.is_zero() {
+				return Err(LoomError::SchemaValidation(validation_error));
+			}
+
+			let (retry_response, retry_usage) = self
+				.prompt_model(
+					&prompt_llm_config.model,
+					false,
+					retry_req_msgs.tokens,
+					retry_req_msgs.into_vec(),
+					prompt_params,
+					max_retry_tokens,
+					None,
+				)
+				.await?;
+			*usage += retry_usage;
+			response = retry_response;
+		}
+
+		if T::STAY_IN_CHARACTER {
+			let content = response.clone().into().unwrap_or_default();
+			let stripped = Self::strip_out_of_character(&content);
+			if stripped != content {
+				debug!("Stripped out of character sentences from response");
+				response = prompt_llm_config.model.set_response_content(response, stripped);
+			}
+		}
+
+		if prompt_llm_config.model.tool_call(&response).is_none() {
+			let content = response.clone().into().unwrap_or_default();
+			let mut transformed = content.clone();
+			self.hooks.after_response(&mut transformed).await;
+			if transformed != content {
+				response = prompt_llm_config.model.set_response_content(response, transformed);
+			}
+		}
+
+		Ok(response)
+	}
+
+	/// Saves the response streamed so far along with the new `msgs` as the [`StreamCheckpoint`] of
+	/// the [`TapestryId`] as each of the `deltas` is received, until the stream ends.
+	///
+	/// Failing to save a checkpoint does not interrupt the stream.
+	async fn checkpoint_stream<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+		msgs: &[ContextMessage<T>],
+		mut deltas: mpsc::UnboundedReceiver<String>,
+	) {
+		let mut checkpoint = StreamCheckpoint { msgs: msgs.to_vec(), response: String::new() };
+		while let Some(delta) = deltas.recv().await {
+			checkpoint.response.push_str(&delta);
+			// Deltas received while the previous checkpoint was saved are saved at once
+			while let Ok(delta) = deltas.try_recv() {
+				checkpoint.response.push_str(&delta);
+			}
+			if let Err(e) =
+				self.chest.save_stream_checkpoint(tapestry_id.clone(), checkpoint.clone()).await
+			{
+				warn!("Failed to checkpoint streamed response: {}", e);
+			}
+		}
+	}
+
+	/// Appends the [`StreamCheckpoint`] of the [`TapestryId`] to the current [`TapestryFragment`]
+	/// instance and deletes it, returning the partial response recovered if there was one.
+	async fn finalize_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+	) -> Result<Option<String>, LoomError<T>> {
+		let Some(checkpoint) = self.chest.get_stream_checkpoint(tapestry_id.clone()).await? else {
+			return Ok(None);
+		};
+
+		debug!("Recovering interrupted stream of {:?}", tapestry_id);
+		self.append_response(tapestry_id, checkpoint.msgs, checkpoint.response.clone())
+			.await?;
+		self.chest.delete_stream_checkpoint(tapestry_id.clone()).await?;
+
+		Ok(Some(checkpoint.response))
+	}
+
+	/// Sets the [`StoryMetadata::updated_at`] of the [`TapestryId`] to now.
+	async fn touch_story_metadata<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+	) -> Result<(), LoomError<T>> {
+		let metadata = StoryMetadata {
+			updated_at: Utc::now(),
+			..self.story_metadata(tapestry_id.clone()).await?
+		};
+		self.chest.save_story_metadata(tapestry_id.clone(), metadata).await
+	}
+
+	/// Remembers `msgs` in the [`MemoryStore`] when [`Config::MEMORY_RECALL_COUNT`] is set, each
+	/// message [`Hooks::embed`] returns an embedding for.
+	async fn remember<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+		msgs: &[ContextMessage<T>],
+	) -> Result<(), LoomError<T>> {
+		if T::MEMORY_RECALL_COUNT == 0 {
+			return Ok(());
+		}
+
+		let key = storage_key::<T, _>(tapestry_id);
+		for msg in msgs {
+			if let Some(embedding) = self.hooks.embed(&msg.content).await? {
+				self.memory.insert(&key, embedding, msg.clone()).await?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Forgets `msgs` remembered in the [`MemoryStore`], e.g. once they are deleted or edited.
+	async fn forget<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+		msgs: &[ContextMessage<T>],
+	) -> Result<(), LoomError<T>> {
+		if T::MEMORY_RECALL_COUNT == 0 {
+			return Ok(());
+		}
+
+		let key = storage_key::<T, _>(tapestry_id);
+		for msg in msgs {
+			self.memory.remove(&key, msg).await?;
+		}
+
+		Ok(())
+	}
+
+	/// Recalls the [`Config::MEMORY_RECALL_COUNT`] remembered messages most relevant to `msgs`
+	/// which are not part of the current `window`, as a single system message.
+	async fn recall<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+		msgs: &[ContextMessage<T>],
+		window: &[ContextMessage<T>],
+	) -> Result<Option<ContextMessage<T>>, LoomError<T>> {
+		if T::MEMORY_RECALL_COUNT == 0 || msgs.is_empty() {
+			return Ok(None);
+		}
+
+		let query = msgs.iter().map(|m| m.content.as_str()).collect::<Vec<_>>().join("\n");
+		let Some(embedding) = self.hooks.embed(&query).await? else {
+			return Ok(None);
+		};
+
+		let in_window = |msg: &ContextMessage<T>| {
+			window.iter().any(|m| m.content == msg.content && m.timestamp == msg.timestamp)
+		};
+		let recalled: Vec<_> = self
+			.memory
+			.search(
+				&storage_key::<T, _>(tapestry_id),
+				&embedding,
+				T::MEMORY_RECALL_COUNT.saturating_add(window.len()),
+			)
+			.await?
+			.into_iter()
+			.filter(|m| !in_window(m))
+			.take(T::MEMORY_RECALL_COUNT)
+			.map(|m| m.content)
+			.collect();
+		if recalled.is_empty() {
+			return Ok(None);
+		}
+
+		trace!("Recalled {} messages", recalled.len());
+
+		Ok(Some(Self::build_context_message(
+			SYSTEM_ROLE.into(),
+			format!("Relevant earlier events of the story:\n{}", recalled.join("\n")),
+			None,
+		)))
+	}
+
+	/// Reads the instructions file at `path`.
+	///
+	/// The content is cached and only read again once the modification time or length of the
+	/// file changes.
+	fn load_instructions(&self, path: &Path) -> Result<String, LoomError<T>> {
+		let read_error = |e: std::io::Error| {
+			LoomError::BadConfig(format!(
+				"Failed to read instructions file {}: {}",
+				path.display(),
+				e
+			))
+		};
+
+		let metadata = fs::metadata(path).map_err(read_error)?;
+		let modified = metadata.modified().map_err(read_error)?;
+
+		let mut instructions = self.instructions.lock().unwrap();
+		match instructions.as_ref() {
+			Some(file)
+				if file.path == path && file.modified == modified && file.len == metadata.len() =>
+				Ok(file.content.clone()),
+			_ => {
+				trace!("Reading instructions file {}", path.display());
+
+				let content = fs::read_to_string(path).map_err(read_error)?;
+				*instructions = Some(InstructionsFile {
+					path: path.to_path_buf(),
+					modified,
+					len: metadata.len(),
+					content: content.clone(),
+				});
+				Ok(content)
+			},
+		}
+	}
+
+	/// Waits for the other updates of `tapestry_id` in this [`Loom`] to complete, preventing
+	/// concurrent updates from overwriting each other until the returned guard is dropped.
+	///
+	/// Updates from other processes sharing the same storage are not serialized.
+	async fn lock_tapestry<TID: TapestryId>(&self, tapestry_id: &TID) -> TapestryGuard<'_> {
+		let key = tapestry_id.base_key();
+		let lock = self.tapestry_locks.lock().unwrap().entry(key.clone()).or_default().clone();
+
+		TapestryGuard { locks: &self.tapestry_locks, key, guard: Some(lock.lock_owned().await) }
+	}
+
+	/// Number of tapestries with a lock held or awaited.
+	#[cfg(test)]
+	pub(crate) fn tapestry_lock_count(&self) -> usize {
+		self.tapestry_locks.lock().unwrap().len()
+	}
+
+	/// Records a prompt for `tapestry_id` unless it was prompted less than
+	/// [`Config::MIN_PROMPT_INTERVAL`] ago, in which case [`LoomError::Cooldown`] is returned.
+	fn check_cooldown<TID: TapestryId>(&self, tapestry_id: &TID) -> Result<(), LoomError<T>> {
+		let Some(min_interval) = T::MIN_PROMPT_INTERVAL else {
+			return Ok(());
+		};
+
+		let mut last_prompts = self.last_prompts.lock().unwrap();
+		let now = Instant::now();
+		if let Some(last_prompt) = last_prompts.get(&tapestry_id.base_key()) {
+			let elapsed = now.duration_since(*last_prompt);
+			if elapsed < min_interval {
+				debug!("Tapestry {:?} prompted {:?} after the last prompt", tapestry_id, elapsed);
+				return Err(LoomError::Cooldown { retry_after: min_interval - elapsed });
+			}
+		}
+		// Forget the tapestries whose cooldown is over, which would not be held back anymore
+		last_prompts.retain(|_, last_prompt| now.duration_since(*last_prompt) < min_interval);
+		last_prompts.insert(tapestry_id.base_key(), now);
+
+		Ok(())
+	}
+
+	/// Draws one of the [`Config::RANDOM_EVENTS`] with a [`Config::RANDOM_EVENT_PROBABILITY`]
+	/// chance.
+	fn draw_random_event(&self) -> Option<&'static str> {
+		if T::RANDOM_EVENTS.is_empty() || T::RANDOM_EVENT_PROBABILITY <= 0.0 {
+			return None;
+		}
+
+		let mut rng = self.rng.lock().unwrap();
+		if !rng.gen_bool(T::RANDOM_EVENT_PROBABILITY.min(1.0)) {
+			return None;
+		}
+		T::RANDOM_EVENTS.choose(&mut *rng).copied()
+	}
+
+	/// Returns the [`DailyUsage`] of the current UTC day, or [`LoomError::DailyLimitReached`] if
+	/// the [`Config::MAX_DAILY_COMPLETION_TOKENS`] was reached.
+	///
+	/// The usage recorded in `tapestry_fragment` is reset when it is from a previous day.
+	fn check_daily_limit(
+		tapestry_fragment: &TapestryFragment<T>,
+	) -> Result<DailyUsage, LoomError<T>> {
+		let now = chrono::Utc::now();
+		let today = now.date_naive().to_string();
+		let daily_usage = match &tapestry_fragment.daily_usage {
+			Some(usage) if usage.date == today => usage.clone(),
+			_ => DailyUsage { date: today, completion_tokens: 0 },
+		};
+
+		if let Some(max_daily_tokens) = T::MAX_DAILY_COMPLETION_TOKENS {
+			if daily_usage.completion_tokens >= max_daily_tokens {
+				let next_midnight = now
+					.date_naive()
+					.succ_opt()
+					.and_then(|d| d.and_hms_opt(0, 0, 0))
+					.map(|d| d.and_utc());
+				let retry_after = next_midnight
+					.and_then(|midnight| (midnight - now).to_std().ok())
+					.unwrap_or_default();
+				debug!("Daily completion token limit of {} reached", max_daily_tokens);
+				return Err(LoomError::DailyLimitReached { retry_after });
+			}
+		}
+
+		Ok(daily_usage)
+	}
+
+	/// Appends an assistant message to the current [`TapestryFragment`] instance without prompting
+	/// the LLM.
+	///
+	/// Useful for scripted narration, which becomes part of the message history sent to the LLM
+	/// on the next [`Loom::weave`]. Returns the instance the message was saved to.
+	pub async fn inject_narration<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		content: String,
+	) -> Result<u64, LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		let mut tapestry_fragment = self
+			.chest
+			.get_tapestry_fragment(tapestry_id.clone(), None)
+			.await?
+			.unwrap_or_default();
+		let msg = Self::build_context_message(ASSISTANT_ROLE.into(), content, None);
+		tapestry_fragment.push_message(msg.clone())?;
+
+		let instance = self
+			.chest
+			.save_tapestry_fragment(&tapestry_id, tapestry_fragment, false)
+			.await?;
+		self.remember(&tapestry_id, std::slice::from_ref(&msg)).await?;
+		self.touch_story_metadata(&tapestry_id).await?;
+
+		self.publish(&tapestry_id, LoomEvent::MessageAppended(msg));
+
+		Ok(instance)
+	}
+
+	/// Appends the `msgs` along with the `candidate` chosen among the ones returned by
+	/// [`Loom::weave_candidates`] to the current [`TapestryFragment`] instance.
+	///
+	/// The story length, players and daily usage are updated as [`Loom::weave`] would. Returns
+	/// the instance the messages were saved to.
+	pub async fn accept_candidate<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		msgs: Vec<ContextMessage<T>>,
+		candidate: String,
+	) -> Result<u64, LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		self.append_response(&tapestry_id, msgs, candidate).await
+	}
+
+	/// Appends the response of a stream interrupted before it was persisted, e.g. by a crash,
+	/// along with the messages it responds to, from the [`StreamCheckpoint`] saved when
+	/// [`Config::CHECKPOINT_STREAMS`] is set.
+	///
+	/// Weaving recovers the stream first on its own, so this is only needed to recover it before
+	/// the next weave. Returns the partial response recovered, if any.
+	pub async fn recover_stream<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Option<String>, LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		self.finalize_stream_checkpoint(&tapestry_id).await
+	}
+
+	/// Appends the `msgs` along with the `response` already generated for them to the current
+	/// [`TapestryFragment`] instance, updating the story length, players and daily usage.
+	async fn append_response<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+		mut msgs: Vec<ContextMessage<T>>,
+		response: String,
+	) -> Result<u64, LoomError<T>> {
+		let mut tapestry_fragment = self
+			.chest
+			.get_tapestry_fragment(tapestry_id.clone(), None)
+			.await?
+			.unwrap_or_default();
+
+		for account_id in msgs.iter().filter_map(|m| m.account_id.as_ref()) {
+			Self::join_players(&mut tapestry_fragment.players, account_id);
+		}
+
+		let response_tokens = Self::count_tokens(response.clone()).await?;
+		// The response was already generated, so the daily limit is not enforced here
+		let today = chrono::Utc::now().date_naive().to_string();
+		let mut daily_usage = match tapestry_fragment.daily_usage.take() {
+			Some(usage) if usage.date == today => usage,
+			_ => DailyUsage { date: today, completion_tokens: 0 },
+		};
+		daily_usage.completion_tokens = daily_usage
+			.completion_tokens
+			.saturating_add(response_tokens.to_u64().unwrap_or(u64::MAX));
+		tapestry_fragment.daily_usage = Some(daily_usage);
+
+		msgs.push(Self::build_context_message(ASSISTANT_ROLE.into(), response, None));
+		for msg in msgs.iter_mut() {
+			msg.importance = msg.importance.or_else(|| T::score_importance(msg));
+		}
+		let msgs_tokens = Self::count_tokens_in_messages(&msgs).await?;
+		tapestry_fragment.story_length.turns += 1;
+		tapestry_fragment.story_length.tokens = tapestry_fragment
+			.story_length
+			.tokens
+			.saturating_add(msgs_tokens.to_u64().unwrap_or(u64::MAX));
+
+		let appended_msgs = msgs.clone();
+		tapestry_fragment.extend_messages(msgs)?;
+
+		let instance =
+			self.chest.save_tapestry_fragment(tapestry_id, tapestry_fragment, false).await?;
+		self.remember(tapestry_id, &appended_msgs).await?;
+		self.touch_story_metadata(tapestry_id).await?;
+
+		for msg in appended_msgs {
+			self.publish(tapestry_id, LoomEvent::MessageAppended(msg));
+		}
+
+		Ok(instance)
+	}
+
+	/// Deletes every [`TapestryFragment`] instance and the metadata stored for `tapestry_id`.
+	///
+	/// Deleting a tapestry that does not exist is a successful no-op.
+	pub async fn delete_tapestry<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<(), LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		let key = storage_key::<T, _>(&tapestry_id);
+		self.chest.delete_tapestry(tapestry_id).await?;
+		self.memory.delete(&key).await?;
+
+		Ok(())
+	}
+
+	/// Deletes every tapestry that has not been saved for the [`Config::TAPESTRY_TTL`], returning
+	/// how many were deleted.
+	///
+	/// Nothing expires unless the TTL is set, so this is meant to be run periodically by
+	/// applications that set it.
+	pub async fn purge_expired(&self) -> Result<u64, LoomError<T>> {
+		self.chest.purge_expired().await
+	}
+
+	/// Retrieves the [`StoryMetadata`] of the [`TapestryId`].
+	///
+	/// Tapestries without stored metadata, e.g. woven before it was introduced, get an empty
+	/// title and timestamps derived from their first and last messages.
+	pub async fn story_metadata<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<StoryMetadata, LoomError<T>> {
+		if let Some(metadata) = self.chest.get_story_metadata(tapestry_id.clone()).await? {
+			return Ok(metadata);
+		}
+
+		let first_tapestry_fragment: Option<TapestryFragment<T>> =
+			self.chest.get_tapestry_fragment(tapestry_id.clone(), Some(1)).await?;
+		let last_tapestry_fragment: Option<TapestryFragment<T>> =
+			self.chest.get_tapestry_fragment(tapestry_id, None).await?;
+		let timestamp = |msg: Option<&ContextMessage<T>>| {
+			msg.and_then(|m| DateTime::parse_from_rfc3339(&m.timestamp).ok())
+				.map(|timestamp| timestamp.with_timezone(&Utc))
+		};
+
+		let created_at =
+			timestamp(first_tapestry_fragment.as_ref().and_then(|f| f.context_messages.first()))
+				.unwrap_or_else(Utc::now);
+		let updated_at =
+			timestamp(last_tapestry_fragment.as_ref().and_then(|f| f.context_messages.last()))
+				.unwrap_or(created_at);
+
+		Ok(StoryMetadata { title: String::new(), tags: Vec::new(), created_at, updated_at })
+	}
+
+	/// Sets the title of the [`StoryMetadata`] of the [`TapestryId`].
+	pub async fn set_title<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		title: impl Into<String>,
+	) -> Result<(), LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		let metadata = StoryMetadata {
+			title: title.into(),
+			..self.story_metadata(tapestry_id.clone()).await?
+		};
+		self.chest.save_story_metadata(tapestry_id, metadata).await
+	}
+
+	/// Restarts the story of the [`TapestryId`] with the same players, e.g. for a new campaign
+	/// with the same party.
+	///
+	/// Every [`TapestryFragment`] instance and the metadata are replaced by a single empty
+	/// instance holding the players of the current one. The daily usage is carried over as well so
+	/// clearing a story does not reset the [`Config::MAX_DAILY_COMPLETION_TOKENS`], and so is the
+	/// [`StoryMetadata`]. The remembered messages are forgotten. Returns the instance saved, which
+	/// is always `1`.
+	///
+	/// The story is restored as it was if the replacement fails to be saved.
+	pub async fn clear<TID: TapestryId>(&self, tapestry_id: TID) -> Result<u64, LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		let tapestry_fragment = self
+			.chest
+			.get_tapestry_fragment(tapestry_id.clone(), None)
+			.await?
+			.unwrap_or_default();
+		let story_metadata = self.chest.get_story_metadata(tapestry_id.clone()).await?;
+
+		let cleared_tapestry_fragment = TapestryFragment {
+			players: tapestry_fragment.players,
+			daily_usage: tapestry_fragment.daily_usage,
+			..TapestryFragment::new()
+		};
+
+		debug!("Clearing tapestry {:?}", tapestry_id);
+
+		let instance = self
+			.replace_tapestry(&tapestry_id, vec![cleared_tapestry_fragment], story_metadata)
+			.await?;
+		self.memory.delete(&storage_key::<T, _>(&tapestry_id)).await?;
+
+		Ok(instance)
+	}
+
+	/// Replaces every [`TapestryFragment`] instance of the [`TapestryId`] by `tapestry_fragments`,
+	/// saved in order, along with its [`StoryMetadata`]. Its other metadata is deleted.
+	///
+	/// The [`Config::Chest`] cannot write several keys at once, so the current instances and
+	/// metadata are read first and written back if any write fails, in which case the error of
+	/// the failed write is returned. Returns the last instance saved.
+	async fn replace_tapestry<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+		tapestry_fragments: Vec<TapestryFragment<T>>,
+		story_metadata: Option<StoryMetadata>,
+	) -> Result<u64, LoomError<T>> {
+		let instances = self.chest.get_instance_index(tapestry_id.clone()).await?.unwrap_or(0);
+		let mut previous_tapestry_fragments = Vec::with_capacity(instances as usize);
+		for instance in 1..=instances as u64 {
+			previous_tapestry_fragments.extend(
+				self.chest.get_tapestry_fragment(tapestry_id.clone(), Some(instance)).await?,
+			);
+		}
+		let previous_metadata: Option<serde_json::Value> =
+			self.chest.get_tapestry_metadata(tapestry_id.clone()).await?;
+		let previous_story_metadata = self.chest.get_story_metadata(tapestry_id.clone()).await?;
+
+		match self.write_tapestry(tapestry_id, tapestry_fragments, None, story_metadata).await {
+			Ok(instance) => Ok(instance),
+			Err(e) => {
+				error!("Failed to replace tapestry {:?}, restoring it: {}", tapestry_id, e);
+				if let Err(restore_error) = self
+					.write_tapestry(
+						tapestry_id,
+						previous_tapestry_fragments,
+						previous_metadata,
+						previous_story_metadata,
+					)
+					.await
+				{
+					error!("Failed to restore tapestry {:?}: {}", tapestry_id, restore_error);
+				}
+				Err(e)
+			},
+		}
+	}
+
+	/// Deletes the [`TapestryId`], then saves `tapestry_fragments` in order along with the
+	/// metadata. Returns the last instance saved.
+	async fn write_tapestry<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+		tapestry_fragments: Vec<TapestryFragment<T>>,
+		metadata: Option<serde_json::Value>,
+		story_metadata: Option<StoryMetadata>,
+	) -> Result<u64, LoomError<T>> {
+		self.chest.delete_tapestry(tapestry_id.clone()).await?;
+
+		let mut instance = 0;
+		for tapestry_fragment in tapestry_fragments {
+			instance =
+				self.chest.save_tapestry_fragment(tapestry_id, tapestry_fragment, true).await?;
+		}
+		if let Some(metadata) = metadata {
+			self.chest.save_tapestry_metadata(tapestry_id.clone(), metadata).await?;
+		}
+		if let Some(story_metadata) = story_metadata {
+			self.chest.save_story_metadata(tapestry_id.clone(), story_metadata).await?;
+		}
+
+		Ok(instance)
+	}
+
+	/// Adds `account_id` to the [`TapestryFragment::players`] of the current instance, unless
+	/// already present.
+	///
+	/// Returns the instance the players were saved to.
+	pub async fn add_player<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		account_id: String,
+	) -> Result<u64, LoomError<T>> {
+		self.update_players(tapestry_id, |players| Self::join_players(players, &account_id))
+			.await
+	}
+
+	/// Removes `account_id` from the [`TapestryFragment::players`] of the current instance, if
+	/// present.
+	///
+	/// Returns the instance the players were saved to.
+	pub async fn remove_player<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		account_id: String,
+	) -> Result<u64, LoomError<T>> {
+		self.update_players(tapestry_id, |players| players.retain(|p| *p != account_id))
+			.await
+	}
+
+	/// Removes the message at `index` of the current [`TapestryFragment`] instance, e.g. to let a
+	/// player retract it.
+	///
+	/// With `cascade`, deleting a user message also deletes the assistant message replying to it,
+	/// if any. Returns the instance the messages were saved to.
+	///
+	/// Fails with [`LoomError::MessageNotFound`] if there is no message at `index`.
+	pub async fn delete_message<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		index: usize,
+		cascade: bool,
+	) -> Result<u64, LoomError<T>> {
+		self.update_messages(tapestry_id, |msgs| {
+			let msg = msgs.get(index).ok_or(LoomError::MessageNotFound { index })?;
+			let reply_index = index + 1;
+			let delete_reply = cascade &&
+				msg.role == WrapperRole::from(USER_ROLE) &&
+				msgs.get(reply_index)
+					.is_some_and(|reply| reply.role == WrapperRole::from(ASSISTANT_ROLE));
+
+			if delete_reply {
+				msgs.drain(index..=reply_index);
+			} else {
+				msgs.remove(index);
+			}
+			Ok(())
+		})
+		.await
+	}
+
+	/// Replaces the content of the message at `index` of the current [`TapestryFragment`]
+	/// instance, e.g. to let a player fix a typo.
+	///
+	/// Returns the instance the messages were saved to.
+	///
+	/// Fails with [`LoomError::MessageNotFound`] if there is no message at `index`, and with
+	/// [`LoomError::NotMessageAuthor`] if the message was not sent by `account_id`.
+	pub async fn edit_message<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		index: usize,
+		account_id: &str,
+		content: String,
+	) -> Result<u64, LoomError<T>> {
+		self.update_messages(tapestry_id, |msgs| {
+			let msg = msgs.get_mut(index).ok_or(LoomError::MessageNotFound { index })?;
+			if msg.account_id.as_deref() != Some(account_id) {
+				return Err(LoomError::NotMessageAuthor { index });
+			}
+
+			msg.content = content;
+			Ok(())
+		})
+		.await
+	}
+
+	/// Applies `update` to the messages of the current [`TapestryFragment`] instance and saves it
+	/// with its `context_tokens` recounted.
+	async fn update_messages<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		update: impl FnOnce(&mut Vec<ContextMessage<T>>) -> Result<(), LoomError<T>>,
+	) -> Result<u64, LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		let mut tapestry_fragment = self
+			.chest
+			.get_tapestry_fragment(tapestry_id.clone(), None)
+			.await?
+			.unwrap_or_default();
+
+		let previous_msgs = std::mem::take(&mut tapestry_fragment.context_messages);
+		let mut msgs = previous_msgs.clone();
+		update(&mut msgs)?;
+		// Deleted and edited messages must no longer be recalled, while edits are recalled anew
+		let removed_msgs: Vec<_> =
+			previous_msgs.iter().filter(|m| !msgs.contains(m)).cloned().collect();
+		let added_msgs: Vec<_> =
+			msgs.iter().filter(|m| !previous_msgs.contains(m)).cloned().collect();
+		tapestry_fragment.context_tokens = Zero::zero();
+		tapestry_fragment.extend_messages(msgs)?;
+
+		let instance = self
+			.chest
+			.save_tapestry_fragment(&tapestry_id, tapestry_fragment, false)
+			.await?;
+		self.forget(&tapestry_id, &removed_msgs).await?;
+		self.remember(&tapestry_id, &added_msgs).await?;
+
+		Ok(instance)
+	}
+
+	/// Applies `update` to the [`TapestryFragment::players`] of the current instance and saves it.
+	async fn update_players<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		update: impl FnOnce(&mut Vec<String>),
+	) -> Result<u64, LoomError<T>> {
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		let mut tapestry_fragment = self
+			.chest
+			.get_tapestry_fragment(tapestry_id.clone(), None)
+			.await?
+			.unwrap_or_default();
+		update(&mut tapestry_fragment.players);
+
+		self.chest.save_tapestry_fragment(&tapestry_id, tapestry_fragment, false).await
+	}
+
+	/// Checks the current [`TapestryFragment`] instance for continuity errors.
+	///
+	/// The LLM is asked to review the message history for inconsistencies, such as a character
+	/// described as dead who is still speaking or a change of location without transition, and to
+	/// respond with a JSON array of [`ConsistencyWarning`]s.
+	///
+	/// Returns an empty list if nothing has been stored for the [`TapestryId`] yet.
+	pub async fn check_consistency<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		tapestry_id: TID,
+	) -> Result<Vec<ConsistencyWarning>, LoomError<T>> {
+		Ok(self
+			.prompt_json(
+				prompt_llm_config,
+				tapestry_id,
+				"Review the following conversation for continuity errors, such as characters \
+				 acting after their death or changes of location without transition. Respond \
+				 only with a JSON array of objects with a \"description\" field and an optional \
+				 \"message_index\" field referring to the zero based index of the offending \
+				 message. Respond with an empty array if there are none.",
+			)
+			.await?
+			.unwrap_or_default())
+	}
+
+	/// Extracts a [`CharacterSheet`] for each player character of the current
+	/// [`TapestryFragment`] instance.
+	///
+	/// The LLM is asked to respond with a JSON array of the characters' names, traits, inventory
+	/// and relationships to other characters.
+	///
+	/// Returns an empty list if nothing has been stored for the [`TapestryId`] yet.
+	pub async fn extract_character_sheets<TID: TapestryId>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		tapestry_id: TID,
+	) -> Result<Vec<CharacterSheet>, LoomError<T>> {
+		Ok(self
+			.prompt_json(
+				prompt_llm_config,
+				tapestry_id,
+				"Extract a character sheet for each player character of the following \
+				 conversation. Respond only with a JSON array of objects with a \"name\" field, \
+				 a \"traits\" array of strings, an \"inventory\" array of strings and a \
+				 \"relationships\" object mapping the names of other characters to their \
+				 relationship with the character. Respond with an empty array if there are none.",
+			)
+			.await?
+			.unwrap_or_default())
+	}
+
+	/// Prompts the LLM with `instructions` followed by the current [`TapestryFragment`] instance,
+	/// and parses the JSON response.
+	///
+	/// JSON mode is enabled if the model supports it. Returns `None` if nothing has been stored
+	/// for the [`TapestryId`] yet.
+	async fn prompt_json<TID: TapestryId, R: DeserializeOwned>(
+		&self,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		tapestry_id: TID,
+		instructions: &str,
+	) -> Result<Option<R>, LoomError<T>> {
+		prompt_llm_config.check_capabilities()?;
+
+		let Some(tapestry_fragment) = self.chest.get_tapestry_fragment(tapestry_id, None).await?
+		else {
+			return Ok(None);
+		};
+
+		let mut params = prompt_llm_config.params.clone();
+		if prompt_llm_config.model.capabilities().json_mode {
+			prompt_llm_config.model.set_json_mode(&mut params);
+		}
+
+		let mut req_msgs = VecPromptMsgsDeque::<T, T::PromptModel>::with_capacity(
+			tapestry_fragment.context_messages.len() + 1,
+		);
+		req_msgs.push_back(
+			Self::build_context_message(SYSTEM_ROLE.into(), instructions.to_string(), None).into(),
+		);
+		req_msgs.extend(Self::prompt_requests(
+			&prompt_llm_config.model,
+			&self.redact_messages(&tapestry_fragment.context_messages),
+		));
+
+		let max_completion_tokens = prompt_llm_config
+			.model
+			.get_max_prompt_token_limit()
+			.saturating_sub(&req_msgs.tokens);
+		if max_completion_tokens.is_zero() {
+			return Err(LoomError::MaxCompletionTokensIsZero);
+		}
+
+		let (response, _) = self
+			.prompt_model(
+				&prompt_llm_config.model,
+				false,
+				req_msgs.tokens,
+				req_msgs.into_vec(),
+				&params,
+				max_completion_tokens,
+				None,
+			)
+			.await?;
+
+		let content = response.into().unwrap_or_default();
+
+		trace!("JSON response: {}", content);
+
+		serde_json::from_str(&content)
+			.map(Some)
+			.map_err(|e| LoomError::ParseFailed(e.to_string()))
+	}
+
+	/// Whether the current [`TapestryFragment`] instance of the [`TapestryId`] fits in `model`.
+	///
+	/// The fragment fits if its `context_tokens` along with the [`Config::MINIMUM_RESPONSE_LENGTH`]
+	/// stay below the [`Llm::get_max_prompt_token_limit`] of `model`, meaning that it could be
+	/// prompted without generating a summary. Useful to decide whether a tapestry can be switched
+	/// to a model with a smaller context.
+	pub async fn fits_model<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		model: &T::PromptModel,
+	) -> Result<bool, LoomError<T>> {
+		let context_tokens = self
+			.chest
+			.get_tapestry_fragment(tapestry_id, None)
+			.await?
+			.map(|tapestry_fragment| tapestry_fragment.context_tokens)
+			.unwrap_or_default();
+		let minimum_response_length = PromptModelTokens::<T>::from_u64(T::MINIMUM_RESPONSE_LENGTH)
+			.ok_or_else(|| {
+				LoomError::BadConfig("MINIMUM_RESPONSE_LENGTH exceeds model tokens".to_string())
+			})?;
+
+		Ok(context_tokens.saturating_add(&minimum_response_length) <
+			model.get_max_prompt_token_limit())
+	}
+
+	/// Average sentiment of the messages of each player of the [`TapestryId`], keyed by
+	/// [`ContextMessage::account_id`].
+	///
+	/// Messages of every [`TapestryFragment`] instance are scored by [`Hooks::sentiment`].
+	/// Messages without an `account_id` or a score are ignored.
+	pub async fn player_sentiment<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<HashMap<String, f64>, LoomError<T>> {
+		let instances = self.chest.get_instance_index(tapestry_id.clone()).await?.unwrap_or(0);
+
+		let mut scores = HashMap::<String, (f64, usize)>::new();
+		for instance in 1..=instances as u64 {
+			let Some(tapestry_fragment) =
+				self.chest.get_tapestry_fragment(tapestry_id.clone(), Some(instance)).await?
+			else {
+				continue;
+			};
+
+			for msg in tapestry_fragment.context_messages {
+				let Some(account_id) = msg.account_id else {
+					continue;
+				};
+				let Some(score) = self.hooks.sentiment(&msg.content).await? else {
+					continue;
+				};
+				let (sum, count) = scores.entry(account_id).or_default();
+				*sum += score;
+				*count += 1;
+			}
+		}
+
+		Ok(scores
+			.into_iter()
+			.map(|(account_id, (sum, count))| (account_id, sum / count as f64))
+			.collect())
+	}
+
+	/// Runs every message of every [`TapestryFragment`] instance of the [`TapestryId`] through
+	/// [`Hooks::moderate`], returning the location and categories of the flagged ones.
+	pub async fn audit<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Vec<ModerationHit>, LoomError<T>> {
+		let instances = self.chest.get_instance_index(tapestry_id.clone()).await?.unwrap_or(0);
+
+		let mut hits = Vec::new();
+		for instance in 1..=instances as u64 {
+			let Some(tapestry_fragment) =
+				self.chest.get_tapestry_fragment(tapestry_id.clone(), Some(instance)).await?
+			else {
+				continue;
+			};
+
+			for (index, msg) in tapestry_fragment.context_messages.iter().enumerate() {
+				let categories = self.hooks.moderate(&msg.content).await?;
+				if !categories.is_empty() {
+					hits.push(ModerationHit { instance, index, categories });
+				}
+			}
+		}
+
+		Ok(hits)
+	}
+
+	/// Fraction of the [`Llm::max_context_length`] of each of the `models` occupied by the current
+	/// [`TapestryFragment`] instance of the [`TapestryId`].
+	///
+	/// Fractions are returned in the same order as `models`.
+	pub async fn context_usage_per_model<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		models: &[T::PromptModel],
+	) -> Result<Vec<(T::PromptModel, f64)>, LoomError<T>> {
+		let context_tokens = self
+			.chest
+			.get_tapestry_fragment(tapestry_id, None)
+			.await?
+			.map(|tapestry_fragment| tapestry_fragment.context_tokens)
+			.unwrap_or_default()
+			.to_f64()
+			.unwrap_or_default();
+
+		Ok(models
+			.iter()
+			.map(|model| {
+				let max_context_length = model.max_context_length().to_f64().unwrap_or_default();
+				let usage = if max_context_length > 0.0 {
+					context_tokens / max_context_length
+				} else {
+					0.0
+				};
+				(*model, usage)
+			})
+			.collect())
+	}
+
+	/// Checks that both models are reachable through [`Llm::check_connection`], e.g. on startup or
+	/// from a readiness probe, so that an invalid API key or unreachable endpoint is detected
+	/// before the first prompt.
+	///
+	/// Each check is bounded by the [`Config::PROMPT_TIMEOUT`].
+	pub async fn check_connection(
+		&self,
+		prompt_model: &T::PromptModel,
+		summary_model: &T::SummaryModel,
+	) -> Result<(), LoomError<T>> {
+		let with_timeout = |check| async move {
+			match T::PROMPT_TIMEOUT {
+				Some(timeout) => time::timeout(timeout, check)
+					.await
+					.unwrap_or(Err(LoomError::Timeout { timeout })),
+				None => check.await,
+			}
+		};
+
+		with_timeout(prompt_model.check_connection()).await?;
+		with_timeout(summary_model.check_connection()).await
+	}
+
+	/// The `context_tokens` of the current [`TapestryFragment`] instance of the [`TapestryId`]
+	/// along with the [`Llm::max_context_length`] of `model`, e.g. to show how close the story is
+	/// to its next summary.
+	///
+	/// Returns zero context tokens if nothing has been stored for the [`TapestryId`] yet.
+	pub async fn story_token_count<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		model: &T::PromptModel,
+	) -> Result<(PromptModelTokens<T>, PromptModelTokens<T>), LoomError<T>> {
+		let context_tokens = self
+			.chest
+			.get_tapestry_fragment(tapestry_id, None)
+			.await?
+			.map(|tapestry_fragment| tapestry_fragment.context_tokens)
+			.unwrap_or_default();
+
+		Ok((context_tokens, model.max_context_length()))
+	}
+
+	/// Exports every [`TapestryFragment`] instance of the [`TapestryId`] in OpenAI's chat
+	/// fine-tuning JSONL format.
+	///
+	/// Each instance is rendered as a `{"messages": [...]}` line starting with the `instructions`
+	/// as the system message. Instances created by a summary keep the summary as a system message
+	/// so each line carries the context it was generated with. Messages are redacted using the
+	/// [`Config::REDACTION_PATTERNS`].
+	pub async fn export_finetune_jsonl<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		instructions: String,
+	) -> Result<String, LoomError<T>> {
+		let instances = self.chest.get_instance_index(tapestry_id.clone()).await?.unwrap_or(0);
+
+		let mut lines = Vec::with_capacity(instances as usize);
+		for instance in 1..=instances as u64 {
+			let Some(tapestry_fragment) =
+				self.chest.get_tapestry_fragment(tapestry_id.clone(), Some(instance)).await?
+			else {
+				continue;
+			};
+			if tapestry_fragment.context_messages.is_empty() {
+				continue;
+			}
+
+			let mut messages = vec![serde_json::json!({
+				"role": SYSTEM_ROLE,
+				"content": instructions,
+			})];
+			messages.extend(
+				self.redact_messages(&tapestry_fragment.context_messages).into_iter().map(
+					|m| serde_json::json!({ "role": String::from(m.role), "content": m.content }),
+				),
+			);
+
+			lines.push(serde_json::json!({ "messages": messages }).to_string());
+		}
+
+		trace!("Exported {} tapestry fragments for fine-tuning", lines.len());
+
+		Ok(lines.join("\n"))
+	}
+
+	/// Renders every [`TapestryFragment`] instance of the [`TapestryId`] in the given `format`,
+	/// e.g. to let users download their story.
+	///
+	/// In [`ExportFormat::Markdown`], a `— Chapter N —` divider starts every instance after the
+	/// first one, and the summary carried over by an instance is left out like in
+	/// [`Loom::get_full_story`].
+	pub async fn export<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		format: ExportFormat,
+	) -> Result<String, LoomError<T>>
+	where
+		T: Serialize,
+	{
+		let instances = self.chest.get_instance_index(tapestry_id.clone()).await?.unwrap_or(0);
+
+		let mut tapestry_fragments = Vec::with_capacity(instances as usize);
+		for instance in 1..=instances as u64 {
+			if let Some(tapestry_fragment) =
+				self.chest.get_tapestry_fragment(tapestry_id.clone(), Some(instance)).await?
+			{
+				tapestry_fragments.push(tapestry_fragment);
+			}
+		}
+
+		trace!("Exporting {} tapestry fragments as {:?}", tapestry_fragments.len(), format);
+
+		match format {
+			ExportFormat::Json => serde_json::to_string(&tapestry_fragments)
+				.map_err(|e| LoomError::UnknownError(e.to_string())),
+			ExportFormat::Markdown { timestamps } => {
+				let assistant_role = WrapperRole::from(ASSISTANT_ROLE);
+				let mut blocks = Vec::new();
+				for (chapter, tapestry_fragment) in tapestry_fragments.into_iter().enumerate() {
+					if chapter > 0 {
+						blocks.push(format!("— Chapter {} —", chapter + 1));
+					}
+
+					let mut msgs = tapestry_fragment.context_messages.into_iter().peekable();
+					msgs.next_if(|msg| msg.content.starts_with(SUMMARY_PREFIX));
+					for msg in msgs {
+						let block = if msg.role == assistant_role {
+							msg.content
+						} else {
+							let label = msg.account_id.unwrap_or_else(|| String::from(msg.role));
+							if timestamps {
+								format!("**{}** ({}): {}", label, msg.timestamp, msg.content)
+							} else {
+								format!("**{}**: {}", label, msg.content)
+							}
+						};
+						blocks.push(block);
+					}
+				}
+
+				Ok(blocks.join("\n\n"))
+			},
+		}
+	}
+
+	/// Stores the [`TapestryFragment`] instances of a story exported by [`Loom::export`] in
+	/// [`ExportFormat::Json`] under the [`TapestryId`], e.g. to move a story between deployments.
+	///
+	/// The `context_tokens` of each instance are recounted with the current
+	/// [`Config::PromptModel`]. Returns the number of instances stored.
+	///
+	/// Fails with [`LoomError::ParseFailed`] if `data` is not a valid export, e.g. holding an
+	/// unknown role, and with [`LoomError::TapestryAlreadyExists`] if the [`TapestryId`] already
+	/// holds data, unless `overwrite` is set in which case it is replaced along with its metadata
+	/// and remembered messages.
+	///
+	/// Every instance is recounted before anything is written, and the story being overwritten
+	/// is restored as it was if saving the imported one fails.
+	pub async fn import<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		data: &str,
+		overwrite: bool,
+	) -> Result<u64, LoomError<T>>
+	where
+		T: DeserializeOwned,
+	{
+		let tapestry_fragments: Vec<TapestryFragment<T>> =
+			serde_json::from_str(data).map_err(|e| LoomError::ParseFailed(e.to_string()))?;
+
+		let tapestry_fragments = tapestry_fragments
+			.into_iter()
+			.map(|mut tapestry_fragment| {
+				let msgs = std::mem::take(&mut tapestry_fragment.context_messages);
+				tapestry_fragment.context_tokens = Zero::zero();
+				tapestry_fragment.extend_messages(msgs)?;
+				Ok(tapestry_fragment)
+			})
+			.collect::<Result<Vec<_>, LoomError<T>>>()?;
+
+		let _guard = self.lock_tapestry(&tapestry_id).await;
+		if self.chest.get_instance_index(tapestry_id.clone()).await?.unwrap_or(0) > 0 {
+			if !overwrite {
+				return Err(LoomError::TapestryAlreadyExists);
+			}
+			debug!("Overwriting tapestry {:?}", tapestry_id);
+		}
+
+		let instances = self.replace_tapestry(&tapestry_id, tapestry_fragments, None).await?;
+		self.memory.delete(&storage_key::<T, _>(&tapestry_id)).await?;
+
+		trace!("Imported {} tapestry fragments", instances);
+
+		Ok(instances)
+	}
+
+	/// Returns the messages of every [`TapestryFragment`] instance of the [`TapestryId`] in order.
+	///
+	/// The summary message carried over at the start of instances created by a summary is skipped
+	/// since the messages it summarizes are already part of the story.
+	pub async fn get_full_story<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Vec<ContextMessage<T>>, LoomError<T>> {
+		let instances = self.chest.get_instance_index(tapestry_id.clone()).await?.unwrap_or(0);
+
+		let mut story = Vec::new();
+		for instance in 1..=instances as u64 {
+			let Some(tapestry_fragment) =
+				self.chest.get_tapestry_fragment(tapestry_id.clone(), Some(instance)).await?
+			else {
+				continue;
+			};
+
+			let mut msgs = tapestry_fragment.context_messages.into_iter().peekable();
+			if msgs.next_if(|msg| msg.content.starts_with(SUMMARY_PREFIX)).is_some() {
+				trace!("Skipping carried over summary of instance {}", instance);
+			}
+			story.extend(msgs);
+		}
+
+		Ok(story)
+	}
+
+	/// Summarizes `tapestry_fragment` into a new [`TapestryFragment`] starting with the summary
+	/// message, publishing it as a [`LoomEvent::SummaryGenerated`].
+	///
+	/// Returns the summary along with the new tapestry fragment and the [`Usage`] of the prompts
+	/// made.
+	async fn summarize_tapestry_fragment<TID: TapestryId>(
+		&self,
+		prompt_llm_config: &LlmConfig<T, T::PromptModel>,
+		summary_llm_config: &LlmConfig<T, T::SummaryModel>,
+		tapestry_id: &TID,
+		tapestry_fragment: &TapestryFragment<T>,
+	) -> Result<(String, TapestryFragment<T>, Usage), LoomError<T>> {
+		// Summary generation should not exceed the maximum token limit of the prompt model since
+		// it will be added to the tapestry fragment
+		let mut summary_max_tokens: PromptModelTokens<T> = prompt_llm_config
+			.model
+			.max_context_length()
+			.saturating_sub(&prompt_llm_config.model.get_max_prompt_token_limit());
+		if let Some(target_tokens) =
+			T::SUMMARY_TARGET_TOKENS.and_then(PromptModelTokens::<T>::from_u64)
+		{
+			summary_max_tokens = summary_max_tokens.min(target_tokens);
+		}
+
+		let (summary, usage) = self
+			.generate_summary(
+				summary_llm_config,
+				tapestry_fragment,
+				T::convert_prompt_tokens_to_summary_model_tokens(summary_max_tokens),
+			)
+			.await?;
+
+		let summary_tokens = Self::count_tokens(summary.clone()).await?;
+		if summary_tokens > summary_max_tokens {
+			debug!("Summary of {:?} tokens exceeds its budget", summary_tokens);
+			return Err(LoomError::SummaryExceedsBudget {
+				tokens: summary_tokens.to_u64().unwrap_or(u64::MAX),
+				max_tokens: summary_max_tokens.to_u64().unwrap_or(u64::MAX),
+			});
+		}
+
+		self.publish(tapestry_id, LoomEvent::SummaryGenerated(summary.clone()));
+
+		let mut new_tapestry_fragment = TapestryFragment::new();
+		new_tapestry_fragment.push_message(Self::build_context_message(
+			SYSTEM_ROLE.into(),
+			format!("{}{}", SUMMARY_PREFIX, summary),
+			None,
+		))?;
+
+		Ok((summary, new_tapestry_fragment, usage))
+	}
+
+	/// Generates the summary of the current [`TapestryFragment`] instance.
+	///
+	/// If the messages do not fit in the context of the [`Config::SummaryModel`] along with the
+	/// `summary_max_tokens`, they are summarized in chunks which fit and the summaries of the
+	/// chunks are summarized in turn until they fit. Should chunking not reduce the number of
+	/// messages, the oldest messages are dropped instead.
+	///
+	/// Returns the summary message as a string, along with the [`Usage`] of the prompts made.
+	async fn generate_summary(
+		&self,
+		summary_model_config: &LlmConfig<T, T::SummaryModel>,
+		tapestry_fragment: &TapestryFragment<T>,
+		summary_max_tokens: SummaryModelTokens<T>,
+	) -> Result<(String, Usage), LoomError<T>> {
+		trace!(
+			"Generating summary with max tokens: {:?}, for tapestry fragment: {:?}",
+			summary_max_tokens,
+			tapestry_fragment
+		);
+
+		// Prefix scored messages with their importance so the summary can be weighted by it
+		let msgs = self
+			.redact_messages(&tapestry_fragment.context_messages)
+			.into_iter()
+			.map(|msg| match msg.importance {
+				Some(importance) => ContextMessage {
+					content: format!("[Importance {}/10] {}", importance, msg.content),
+					..msg
+				},
+				None => msg,
+			})
+			.collect::<Vec<_>>();
+
+		let instruction = msgs.iter().any(|msg| msg.importance.is_some()).then(|| {
+			Self::build_context_message(
+				SYSTEM_ROLE.into(),
+				"Retain the details of messages with a high importance and compress those with a \
+				 low importance."
+					.to_string(),
+				None,
+			)
+			.into()
+		});
+		let summary_instructions: Option<SummaryModelRequest<T>> = T::SUMMARY_INSTRUCTIONS
+			.map(|i| Self::build_context_message(SYSTEM_ROLE.into(), i.to_string(), None).into());
+		let instruction_tokens = instruction
+			.iter()
+			.chain(&summary_instructions)
+			.map(|req: &SummaryModelRequest<T>| T::SummaryModel::count_tokens(&req.to_string()))
+			.collect::<Result<Vec<_>, _>>()?
+			.into_iter()
+			.fold(SummaryModelTokens::<T>::zero(), |total, tokens| total.saturating_add(&tokens));
+
+		// Tokens available for the messages to summarize in a single request
+		let max_input_tokens = summary_model_config
+			.model
+			.max_context_length()
+			.saturating_sub(&summary_max_tokens)
+			.saturating_sub(&instruction_tokens);
+
+		let mut usage = Usage::default();
+		let mut reqs = Self::prompt_requests(&summary_model_config.model, &msgs);
+		loop {
+			let mut chunks: Vec<VecPromptMsgsDeque<T, T::SummaryModel>> = vec![];
+			for req in reqs.iter().cloned() {
+				let tokens = T::SummaryModel::count_tokens(&req.to_string())?;
+				match chunks.last_mut() {
+					Some(chunk) if chunk.tokens.saturating_add(&tokens) <= max_input_tokens =>
+						chunk.push_back(req),
+					_ => {
+						let mut chunk = VecPromptMsgsDeque::new();
+						chunk.push_back(req);
+						chunks.push(chunk);
+					},
+				}
+			}
+
+			if chunks.len() <= 1 {
+				break;
+			}
+
+			if chunks.len() >= reqs.len() {
+				debug!("Chunking does not reduce the summary input, dropping oldest messages");
+
+				let mut kept = VecPromptMsgsDeque::<T, T::SummaryModel>::new();
+				for req in reqs.into_iter().rev() {
+					let tokens = T::SummaryModel::count_tokens(&req.to_string())?;
+					if !kept.inner.is_empty() &&
+						kept.tokens.saturating_add(&tokens) > max_input_tokens
+					{
+						break;
+					}
+					kept.push_front(req);
+				}
+				reqs = kept.into_vec();
+				break;
+			}
+
+			trace!("Summarizing {} messages in {} chunks", reqs.len(), chunks.len());
+
+			let mut summaries = Vec::with_capacity(chunks.len());
+			for chunk in chunks {
+				let (summary, summary_usage) = self
+					.prompt_summary(
+						summary_model_config,
+						chunk.into_vec(),
+						instruction.clone(),
+						summary_max_tokens,
+					)
+					.await?;
+				usage += summary_usage;
+				summaries
+					.push(Self::build_context_message(SYSTEM_ROLE.into(), summary, None).into());
+			}
+			reqs = summaries;
+		}
+
+		let (summary, summary_usage) = self
+			.prompt_summary(summary_model_config, reqs, instruction, summary_max_tokens)
+			.await?;
+		usage += summary_usage;
+
+		Ok((summary, usage))
+	}
+
+	/// Prompts `model`, retrying up to [`Config::MAX_RATE_LIMIT_RETRIES`] times when rate limited
+	/// and up to [`Config::MAX_TRANSIENT_RETRIES`] times on transient failures.
+	///
+	/// Prompts are queued in order while waiting for the backoff signaled by
+	/// [`Llm::retry_after`] to elapse, so a rate limit hit by one prompt delays all of them. A
+	/// rate limited prompt is queued again behind the prompts already waiting.
+	///
+	/// If `on_delta` is given, the response is streamed to it through [`Llm::prompt_stream`], in
+	/// which case the prompt is no longer retried once a delta was passed on.
+	#[allow(clippy::too_many_arguments)]
+	async fn prompt_model<L: Llm<T>>(
+		&self,
+		model: &L,
+		is_summarizing: bool,
+		prompt_tokens: L::Tokens,
+		msgs: Vec<L::Request>,
+		params: &L::Parameters,
+		max_tokens: L::Tokens,
+		mut on_delta: Option<&mut (dyn FnMut(&str) + Send)>,
+	) -> Result<(L::Response, Usage), LoomError<T>> {
+		let mut retries = 0;
+		let mut transient_retries = 0;
+		loop {
+			{
+				let backoff_until = self.backoff_until.lock().await;
+				if let Some(backoff_until) = *backoff_until {
+					time::sleep_until(backoff_until).await;
+				}
+			}
+
+			let mut streamed = false;
+			let idle_timeout = T::STREAM_IDLE_TIMEOUT.filter(|_| on_delta.is_some());
+			let last_delta = Mutex::new(time::Instant::now());
+			let prompt = async {
+				match on_delta.as_deref_mut() {
+					Some(on_delta) => {
+						let mut forward = |delta: &str| {
+							streamed = true;
+							*last_delta.lock().unwrap() = time::Instant::now();
+							on_delta(delta);
+						};
+						model
+							.prompt_stream(
+								is_summarizing,
+								prompt_tokens,
+								msgs.clone(),
+								params,
+								max_tokens,
+								&mut forward,
+							)
+							.await
+					},
+					None =>
+						model
+							.prompt(is_summarizing, prompt_tokens, msgs.clone(), params, max_tokens)
+							.await,
+				}
+			};
+			let prompt = async {
+				match idle_timeout {
+					Some(idle_timeout) => Self::until_idle(prompt, &last_delta, idle_timeout).await,
+					None => prompt.await,
+				}
+			};
+			let result = match T::PROMPT_TIMEOUT {
+				Some(timeout) => time::timeout(timeout, prompt)
+					.await
+					.unwrap_or(Err(LoomError::Timeout { timeout })),
+				None => prompt.await,
+			};
+
+			match result {
+				Ok(response)
+					if response.clone().into().is_none() &&
+						model.tool_call(&response).is_none() =>
+				{
+					error!("LLM response holds no content");
+					return Err(LoomError::EmptyResponse);
+				},
+				Ok(response) => {
+					// Every candidate generated counts towards the completion tokens
+					let completion_tokens = model
+						.candidates(&response)
+						.iter()
+						.map(|content| L::count_tokens(content))
+						.collect::<Result<Vec<_>, _>>()?
+						.into_iter()
+						.fold(L::Tokens::zero(), |total, tokens| total.saturating_add(&tokens));
+					let usage = Usage {
+						prompt_tokens: prompt_tokens.to_u64().unwrap_or_default(),
+						completion_tokens: completion_tokens.to_u64().unwrap_or_default(),
+						cost: model.compute_cost(prompt_tokens, completion_tokens),
+					};
+					let mut metrics = self.metrics.lock().unwrap();
+					metrics.prompts += 1;
+					metrics.prompt_tokens += usage.prompt_tokens;
+					metrics.completion_tokens += usage.completion_tokens;
+					return Ok((response, usage));
+				},
+				// A stream is not retried once deltas were passed on
+				Err(e) if streamed => {
+					error!("Failed to prompt LLM: {}", e);
+					return Err(e);
+				},
+				Err(e) => match model.retry_after(&e) {
+					Some(retry_after) if retries < T::MAX_RATE_LIMIT_RETRIES => {
+						retries += 1;
+						warn!("Rate limited, retrying in {:?}, attempt {}", retry_after, retries);
+						*self.backoff_until.lock().await = Some(time::Instant::now() + retry_after);
+					},
+					None if model.is_transient(&e) &&
+						transient_retries < T::MAX_TRANSIENT_RETRIES =>
+					{
+						let delay = T::RETRY_BASE_DELAY
+							.saturating_mul(2u32.saturating_pow(transient_retries.into()));
+						transient_retries += 1;
+						warn!(
+							"Failed to prompt LLM: {}, retrying in {:?}, attempt {}",
+							e, delay, transient_retries
+						);
+						time::sleep(delay).await;
+					},
+					_ => {
+						error!("Failed to prompt LLM: {}", e);
+						return Err(e);
+					},
+				},
+			}
+		}
+	}
+
+	/// Prompts the [`Config::SummaryModel`] to summarize `reqs`, followed by the `instruction`.
+	async fn prompt_summary(
+		&self,
+		summary_model_config: &LlmConfig<T, T::SummaryModel>,
+		reqs: Vec<SummaryModelRequest<T>>,
+		instruction: Option<SummaryModelRequest<T>>,
+		summary_max_tokens: SummaryModelTokens<T>,
+	) -> Result<(String, Usage), LoomError<T>> {
+		let mut summary_generation_prompt = VecPromptMsgsDeque::<T, T::SummaryModel>::new();
+		if let Some(summary_instructions) = T::SUMMARY_INSTRUCTIONS {
+			summary_generation_prompt.push_back(
+				Self::build_context_message(
+					SYSTEM_ROLE.into(),
+					summary_instructions.to_string(),
+					None,
+				)
+				.into(),
+			);
+		}
+		summary_generation_prompt.extend(reqs);
+		if let Some(instruction) = instruction {
+			summary_generation_prompt.push_back(instruction);
+		}
+
+		let (res, usage) = self
+			.prompt_model(
+				&summary_model_config.model,
+				true,
+				summary_generation_prompt.tokens,
+				summary_generation_prompt.into_vec(),
+				&summary_model_config.params,
+				summary_max_tokens,
+				None,
+			)
+			.await?;
+
+		let summary_response_content = res.into();
+
+		trace!("Generated summary: {:?}", summary_response_content);
+
+		Ok((summary_response_content.unwrap_or_default(), usage))
+	}
+
+	/// Number of tokens `msg` would add to the context of the [`TapestryId`] sent to the
+	/// [`Config::PromptModel`] if woven as a user message, e.g. to warn a player before sending a
+	/// long message.
+	///
+	/// The message is transformed by the [`Hooks::before_prompt`] and redacted as it would be when
+	/// woven, then converted to a [`Llm::Request`] so that the overhead of the request format, e.g.
+	/// the role, is counted. The memory refresher it would cause to be inserted into the history is
+	/// counted as well. Tokens are counted by the default [`Config::PromptModel`], since counting
+	/// only depends on its type.
+	pub async fn message_token_cost<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		msg: &str,
+	) -> Result<PromptModelTokens<T>, LoomError<T>> {
+		let model = T::PromptModel::default();
+		let mut content = msg.to_string();
+		self.hooks.before_prompt(&mut content).await;
+		let msg = Self::build_context_message(USER_ROLE.into(), content, None);
+		let mut cost = Self::request_token_cost(&model, &self.redact_messages(&[msg])[0])?;
+
+		let history = self
+			.chest
+			.get_tapestry_fragment(tapestry_id, None)
+			.await?
+			.map(|tapestry_fragment| self.redact_messages(&tapestry_fragment.context_messages))
+			.unwrap_or_default();
+		if let Some((interval, refresher)) = Self::memory_refresher(&history) {
+			// Refreshers are inserted after every `interval` messages following the summary, except
+			// after the last one
+			let refreshers = |len: usize| len.saturating_sub(2) / interval;
+			if refreshers(history.len() + 1) > refreshers(history.len()) {
+				cost = cost.saturating_add(&Self::request_token_cost(&model, &refresher)?);
+			}
+		}
+
+		Ok(cost)
+	}
+
+	/// Number of tokens of `msg` once converted to the [`Llm::Request`] of `model`, including any
+	/// overhead added by its request format, e.g. the role or name.
+	fn request_token_cost(
+		model: &T::PromptModel,
+		msg: &ContextMessage<T>,
+	) -> Result<PromptModelTokens<T>, LoomError<T>> {
+		Self::prompt_requests(model, std::slice::from_ref(msg)).iter().try_fold(
+			PromptModelTokens::<T>::default(),
+			|acc, req| {
+				let tokens = T::PromptModel::count_tokens(&req.to_string())?;
+				acc.checked_add(&tokens).ok_or_else(|| {
+					LoomError::BadConfig(
+						"Number of tokens exceeds max tokens for model".to_string(),
+					)
+				})
+			},
+		)
+	}
+
+	/// Splits a message into consecutive messages of at most `max_tokens` tokens each.
+	///
+	/// The content is split on whitespace boundaries, so concatenating the contents of the
+	/// returned messages yields the original content. A single word exceeding `max_tokens` is kept
+	/// whole in its own message.
+	pub fn split_message(
+		msg: ContextMessage<T>,
+		max_tokens: PromptModelTokens<T>,
+	) -> Result<Vec<ContextMessage<T>>, LoomError<T>> {
+		if T::PromptModel::count_tokens(&msg.content)? <= max_tokens {
+			return Ok(vec![msg]);
+		}
+
+		let mut chunks = vec![];
+		let mut chunk = String::new();
+		for word in msg.content.split_inclusive(char::is_whitespace) {
+			// Chunks are bounded by `max_tokens`, so recounting the whole chunk stays cheap while
+			// accounting for tokens spanning word boundaries
+			if !chunk.is_empty() &&
+				T::PromptModel::count_tokens(&format!("{}{}", chunk, word))? > max_tokens
+			{
+				chunks.push(std::mem::take(&mut chunk));
+			}
+			chunk.push_str(word);
+		}
+		if !chunk.is_empty() {
+			chunks.push(chunk);
+		}
+
+		trace!("Split message into {} chunks", chunks.len());
+
+		Ok(chunks
+			.into_iter()
+			.map(|content| ContextMessage { content, ..msg.clone() })
+			.collect())
+	}
+
+	/// Removes the sentences of `content` containing any of the
+	/// [`Config::OUT_OF_CHARACTER_PHRASES`].
+	pub fn strip_out_of_character(content: &str) -> String {
+		content
+			.split_inclusive(['.', '!', '?'])
+			.filter(|sentence| {
+				let sentence = sentence.to_lowercase();
+				!T::OUT_OF_CHARACTER_PHRASES
+					.iter()
+					.any(|phrase| sentence.contains(&phrase.to_lowercase()))
+			})
+			.collect::<String>()
+			.trim()
+			.to_string()
+	}
+
+	/// Inserts a "story so far" reminder after every [`Config::MEMORY_REFRESH_INTERVAL`] messages.
+	///
+	/// The reminder holds the first [`Config::MEMORY_REFRESH_WORDS`] words of the summary starting
+	/// `msgs`. Nothing is inserted if `msgs` does not start with a summary.
+	pub fn interleave_memory_refreshers(msgs: &[ContextMessage<T>]) -> Vec<ContextMessage<T>> {
+		let Some((interval, refresher)) = Self::memory_refresher(msgs) else {
+			return msgs.to_vec();
+		};
+
+		let mut interleaved = Vec::with_capacity(msgs.len() + msgs.len() / interval);
+		for (i, msg) in msgs.iter().enumerate() {
+			interleaved.push(msg.clone());
+			// The summary itself is not counted as a turn
+			if i > 0 && i % interval == 0 && i + 1 < msgs.len() {
+				interleaved.push(refresher.clone());
+			}
+		}
+
+		interleaved
+	}
+
+	/// The reminder inserted by [`Loom::interleave_memory_refreshers`] into `msgs` along with the
+	/// interval it is inserted at, or `None` if nothing is inserted.
+	fn memory_refresher(msgs: &[ContextMessage<T>]) -> Option<(usize, ContextMessage<T>)> {
+		let interval = T::MEMORY_REFRESH_INTERVAL.filter(|interval| *interval > 0)?;
+		let summary = msgs.first()?.content.strip_prefix(SUMMARY_PREFIX)?;
+
+		let refresher = format!(
+			"Story so far: {}",
+			summary
+				.split_whitespace()
+				.take(T::MEMORY_REFRESH_WORDS)
+				.collect::<Vec<_>>()
+				.join(" ")
+		);
+
+		Some((interval, Self::build_context_message(SYSTEM_ROLE.into(), refresher, None)))
+	}
+
+	/// Stop sequences preventing the LLM from writing lines on behalf of the speakers of `msgs`.
+	///
+	/// Returns the `account_id:` prefix of each distinct speaker, starting with the most recent
+	/// one, up to [`Llm::MAX_STOP_SEQUENCES`] of the [`Config::PromptModel`].
+	pub fn speaker_stop_sequences(msgs: &[ContextMessage<T>]) -> Vec<String> {
+		let mut stop = Vec::new();
+		for account_id in msgs.iter().rev().filter_map(|m| m.account_id.as_ref()) {
+			if stop.len() == T::PromptModel::MAX_STOP_SEQUENCES {
+				break;
+			}
+			let prefix = format!("{}:", account_id);
+			if !stop.contains(&prefix) {
+				stop.push(prefix);
+			}
+		}
+
+		trace!("Speaker stop sequences: {:?}", stop);
+
+		stop
+	}
+
+	/// Sanitizes a display name, such as an `account_id`, to OpenAI's `^[a-zA-Z0-9_-]{1,64}$`
+	/// message name format.
+	///
+	/// Whitespace becomes `_`, other disallowed characters are dropped and the result is truncated
+	/// to 64 characters. Returns `None` if nothing is left, in which case the name should be
+	/// omitted.
+	///
+	/// Applied to the `account_id` of every message before it is converted with
+	/// [`Llm::ctx_msgs_to_prompt_requests`]. Stored messages keep the original `account_id`.
+	pub fn sanitize_name(name: &str) -> Option<String> {
+		let sanitized = name
+			.split_whitespace()
+			.map(|word| {
+				word.chars()
+					.filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+					.collect::<String>()
+			})
+			.filter(|word| !word.is_empty())
+			.collect::<Vec<_>>()
+			.join("_")
+			.chars()
+			.take(64)
+			.collect::<String>();
+
+		(!sanitized.is_empty()).then_some(sanitized)
+	}
+
+	/// Replaces every match of the [`Config::REDACTION_PATTERNS`] in the content of `msgs` with
+	/// its placeholder.
+	///
+	/// Applied to all messages before they are sent to the LLM. Stored messages are never
+	/// redacted.
+	pub fn redact_messages(&self, msgs: &[ContextMessage<T>]) -> Vec<ContextMessage<T>> {
+		if self.redaction_patterns.is_empty() {
+			return msgs.to_vec();
+		}
+
+		msgs.iter()
+			.map(|msg| ContextMessage {
+				content: self.redaction_patterns.iter().fold(
+					msg.content.clone(),
+					|content, (regex, placeholder)| {
+						regex.replace_all(&content, NoExpand(placeholder)).into_owned()
+					},
+				),
+				..msg.clone()
+			})
+			.collect()
 	}
 
 	/// Helper method to build a [`ContextMessage`]
@@ -258,22 +3448,97 @@ impl<T: Config> Loom<T> {
 			content,
 			account_id,
 			timestamp: chrono::Utc::now().to_rfc3339(),
+			importance: None,
 			_phantom: PhantomData,
 		}
 	}
 
-	fn count_tokens_in_messages(
-		msgs: impl Iterator<Item = &ContextMessage<T>>,
-	) -> <T::PromptModel as Llm<T>>::Tokens {
-		msgs.fold(<T::PromptModel as Llm<T>>::Tokens::from_u8(0).unwrap(), |acc, m| {
-			let tokens = T::PromptModel::count_tokens(&m.content).unwrap_or_default();
-			match acc.checked_add(&tokens) {
-				Some(v) => v,
-				None => {
-					error!("Token overflow");
-					acc
+	/// Counts the tokens in `content` using the [`Config::PromptModel`].
+	///
+	/// Counting is CPU bound, so content of at least [`Config::BLOCKING_TOKEN_COUNT_THRESHOLD`]
+	/// bytes is counted on a blocking thread to avoid stalling other tasks. Smaller content is
+	/// counted inline to avoid the overhead of spawning a thread.
+	pub async fn count_tokens(content: String) -> Result<PromptModelTokens<T>, LoomError<T>> {
+		if content.len() < T::BLOCKING_TOKEN_COUNT_THRESHOLD {
+			return T::PromptModel::count_tokens(&content);
+		}
+
+		trace!("Counting tokens of {} bytes on a blocking thread", content.len());
+
+		tokio::task::spawn_blocking(move || T::PromptModel::count_tokens(&content))
+			.await
+			.map_err(|e| LoomError::UnknownError(e.to_string()))?
+	}
+
+	/// Converts the history of a tapestry fragment to the request messages sent to the LLM,
+	/// redacted and interleaved with memory refreshers.
+	fn history_requests(
+		&self,
+		model: &T::PromptModel,
+		msgs: &[ContextMessage<T>],
+	) -> VecDeque<PromptModelRequest<T>> {
+		VecDeque::from(Self::prompt_requests(
+			model,
+			&Self::interleave_memory_refreshers(&self.redact_messages(msgs)),
+		))
+	}
+
+	/// Awaits the streamed `prompt`, failing with [`LoomError::Timeout`] once no delta was received
+	/// for `idle_timeout` since the `last_delta`.
+	async fn until_idle<R>(
+		prompt: impl Future<Output = Result<R, LoomError<T>>>,
+		last_delta: &Mutex<time::Instant>,
+		idle_timeout: Duration,
+	) -> Result<R, LoomError<T>> {
+		tokio::pin!(prompt);
+		loop {
+			let deadline = *last_delta.lock().unwrap() + idle_timeout;
+			tokio::select! {
+				result = &mut prompt => return result,
+				_ = time::sleep_until(deadline) => {
+					if *last_delta.lock().unwrap() + idle_timeout <= time::Instant::now() {
+						return Err(LoomError::Timeout { timeout: idle_timeout });
+					}
 				},
 			}
-		})
+		}
+	}
+
+	/// Inserts `account_id` into the sorted `players`, unless already present.
+	fn join_players(players: &mut Vec<String>, account_id: &str) {
+		if let Err(index) = players.binary_search_by(|p| p.as_str().cmp(account_id)) {
+			players.insert(index, account_id.to_string());
+		}
+	}
+
+	/// Converts `msgs` to the requests of `model`, with each `account_id` sanitized by
+	/// [`Loom::sanitize_name`] since it is sent as the name of the message.
+	fn prompt_requests<L: Llm<T>>(model: &L, msgs: &[ContextMessage<T>]) -> Vec<L::Request> {
+		let msgs = msgs
+			.iter()
+			.map(|msg| ContextMessage {
+				account_id: msg.account_id.as_deref().and_then(Self::sanitize_name),
+				..msg.clone()
+			})
+			.collect::<Vec<_>>();
+
+		model.ctx_msgs_to_prompt_requests(&msgs)
+	}
+
+	/// Counts the tokens in the content of `msgs` using the [`Config::PromptModel`].
+	///
+	/// Fails with [`LoomError::BadConfig`] if the sum overflows the tokens of the model.
+	async fn count_tokens_in_messages(
+		msgs: &[ContextMessage<T>],
+	) -> Result<PromptModelTokens<T>, LoomError<T>> {
+		let mut sum = PromptModelTokens::<T>::default();
+		for m in msgs {
+			let tokens = Self::count_tokens(m.content.clone()).await?;
+			sum = sum.checked_add(&tokens).ok_or_else(|| {
+				LoomError::BadConfig("Number of tokens exceeds max tokens for model".to_string())
+			})?;
+		}
+
+		Ok(sum)
 	}
 }
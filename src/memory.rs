@@ -0,0 +1,103 @@
+//! Long-term memory recalling the past messages of a tapestry most relevant to new messages, so
+//! that details lost to summaries can be recalled.
+//!
+//! Messages are embedded with [`Hooks::embed`](crate::Hooks::embed) and stored in the
+//! [`MemoryStore`] of the [`Loom`](crate::loom::Loom), [`InMemoryStore`] unless set with
+//! [`Loom::with_memory_store`](crate::loom::Loom::with_memory_store).
+
+use std::{collections::HashMap, fmt::Debug, sync::Mutex};
+
+use async_trait::async_trait;
+
+use crate::{Config, ContextMessage, Result};
+
+/// Vector storage of the embedded messages of each tapestry, keyed by
+/// [`storage_key`](crate::storage::storage_key).
+#[async_trait]
+pub trait MemoryStore<T: Config>: Debug + Send + Sync {
+	/// Stores a message of the tapestry under `key` along with its embedding.
+	async fn insert(&self, key: &str, embedding: Vec<f32>, msg: ContextMessage<T>)
+		-> Result<(), T>;
+	/// Retrieves up to `k` messages of the tapestry under `key` most similar to `embedding`, the
+	/// most similar first.
+	async fn search(
+		&self,
+		key: &str,
+		embedding: &[f32],
+		k: usize,
+	) -> Result<Vec<ContextMessage<T>>, T>;
+	/// Deletes the messages of the tapestry under `key` equal to `msg`, if any.
+	async fn remove(&self, key: &str, msg: &ContextMessage<T>) -> Result<(), T>;
+	/// Deletes every message of the tapestry under `key`.
+	async fn delete(&self, key: &str) -> Result<(), T>;
+}
+
+/// [`MemoryStore`] ranking messages by the cosine similarity of their embeddings, held in
+/// memory for the lifetime of the [`Loom`](crate::loom::Loom).
+#[derive(Debug, Default)]
+pub struct InMemoryStore<T: Config> {
+	entries: Mutex<HashMap<String, Vec<Memory<T>>>>,
+}
+
+/// Embedding of a remembered message, along with the message.
+type Memory<T> = (Vec<f32>, ContextMessage<T>);
+
+#[async_trait]
+impl<T: Config> MemoryStore<T> for InMemoryStore<T> {
+	async fn insert(
+		&self,
+		key: &str,
+		embedding: Vec<f32>,
+		msg: ContextMessage<T>,
+	) -> Result<(), T> {
+		self.entries
+			.lock()
+			.unwrap()
+			.entry(key.to_string())
+			.or_default()
+			.push((embedding, msg));
+		Ok(())
+	}
+
+	async fn search(
+		&self,
+		key: &str,
+		embedding: &[f32],
+		k: usize,
+	) -> Result<Vec<ContextMessage<T>>, T> {
+		let entries = self.entries.lock().unwrap();
+		let mut scored: Vec<_> = entries
+			.get(key)
+			.into_iter()
+			.flatten()
+			.map(|(e, msg)| (cosine_similarity(e, embedding), msg))
+			.collect();
+		scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+		Ok(scored.into_iter().take(k).map(|(_, msg)| msg.clone()).collect())
+	}
+
+	async fn remove(&self, key: &str, msg: &ContextMessage<T>) -> Result<(), T> {
+		if let Some(memories) = self.entries.lock().unwrap().get_mut(key) {
+			memories.retain(|(_, m)| m != msg);
+		}
+		Ok(())
+	}
+
+	async fn delete(&self, key: &str) -> Result<(), T> {
+		self.entries.lock().unwrap().remove(key);
+		Ok(())
+	}
+}
+
+/// Cosine similarity of two embeddings, `0.0` if either is a zero vector.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+	let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+	let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+	let norms = norm(a) * norm(b);
+	if norms == 0.0 {
+		0.0
+	} else {
+		dot / norms
+	}
+}
@@ -1,75 +1,202 @@
-use std::fmt::Formatter;
+use std::{cell::RefCell, collections::VecDeque, fmt::Formatter, sync::OnceLock, time::Duration};
 
 use async_trait::async_trait;
-use serde::de::DeserializeOwned;
-use tiktoken_rs::p50k_base;
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::{p50k_base, CoreBPE};
 
 use crate::*;
 
-use self::types::StorageError;
+use self::{storage::memory::InMemoryBackend, types::StorageError};
+
+thread_local! {
+	/// Every call made to [`MockLlm::prompt`] on the current thread.
+	pub static PROMPTS: RefCell<Vec<MockPrompt>> = const { RefCell::new(Vec::new()) };
+	/// Responses returned by [`MockLlm::prompt`] on the current thread, in order.
+	///
+	/// A default response is returned once exhausted.
+	pub static RESPONSES: RefCell<VecDeque<MockLlmResponse>> = const { RefCell::new(VecDeque::new()) };
+	/// Backoffs signaled by rate limiting the next calls to [`MockLlm::prompt`] on the current
+	/// thread, in order.
+	pub static RATE_LIMITS: RefCell<VecDeque<Duration>> = const { RefCell::new(VecDeque::new()) };
+	/// Number of next calls to [`MockLlm::prompt`] on the current thread failing with a transient
+	/// server error.
+	pub static SERVER_ERRORS: RefCell<usize> = const { RefCell::new(0) };
+	/// Time taken by each call to [`MockLlm::prompt`] on the current thread.
+	pub static PROMPT_DELAY: RefCell<Option<Duration>> = const { RefCell::new(None) };
+	/// Number of deltas after which the next call to [`MockLlm::prompt_stream`] on the current
+	/// thread fails.
+	pub static STREAM_INTERRUPTION: RefCell<Option<usize>> = const { RefCell::new(None) };
+	/// Number of deltas after which the next call to [`MockLlm::prompt_stream`] on the current
+	/// thread stalls indefinitely.
+	pub static STREAM_STALL: RefCell<Option<usize>> = const { RefCell::new(None) };
+	/// Stop sequences last set through [`MockLlm`] on the current thread.
+	pub static STOP_SEQUENCES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+	/// Whether JSON mode was enabled through [`MockLlm`] on the current thread.
+	pub static JSON_MODE: RefCell<bool> = const { RefCell::new(false) };
+	/// Sampling parameters last set through [`MockLlm`] on the current thread.
+	pub static SAMPLING: RefCell<Option<SamplingParameters>> = const { RefCell::new(None) };
+	/// Number of candidates requested from the next call to [`MockLlm::prompt`] on the current
+	/// thread, each popped from the [`RESPONSES`].
+	pub static CANDIDATES: RefCell<Option<u8>> = const { RefCell::new(None) };
+	/// Names of the tools last set through [`MockLlm`] on the current thread.
+	pub static TOOLS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+	/// Capabilities required by the parameters of [`MockLlm`] on the current thread.
+	pub static REQUIRED_CAPABILITIES: RefCell<ModelCapabilities> = RefCell::new(ModelCapabilities::default());
+	/// Number of the next saves of a fragment to [`FailingChest`] on the current thread that fail.
+	pub static SAVE_FAILURES: RefCell<usize> = const { RefCell::new(0) };
+}
+
+/// Queues a response to be returned by [`MockLlm::prompt`] on the current thread.
+pub fn push_response(content: &str, truncated: bool) {
+	RESPONSES.with(|responses| {
+		responses.borrow_mut().push_back(MockLlmResponse {
+			content: content.to_string(),
+			truncated,
+			tool_call: None,
+			candidates: Vec::new(),
+		})
+	});
+}
+
+/// Queues a response requesting `tool_call` to be returned by [`MockLlm::prompt`] on the current
+/// thread.
+pub fn push_tool_call(tool_call: ToolCall) {
+	RESPONSES.with(|responses| {
+		responses.borrow_mut().push_back(MockLlmResponse {
+			content: String::new(),
+			truncated: false,
+			tool_call: Some(tool_call),
+			candidates: Vec::new(),
+		})
+	});
+}
 
-pub struct MockChest;
+/// A recorded call to [`MockLlm::prompt`].
+#[derive(Debug, Clone)]
+pub struct MockPrompt {
+	pub msgs: Vec<MockLlmRequest>,
+	pub max_tokens: u16,
+}
+
+/// Returns the last call made to [`MockLlm::prompt`] on the current thread.
+pub fn last_prompt() -> Option<MockPrompt> {
+	PROMPTS.with(|prompts| prompts.borrow().last().cloned())
+}
+
+/// [`InMemoryBackend`] used by the tests.
+pub type MockChest = InMemoryBackend;
+
+/// [`MockChest`] failing the next [`SAVE_FAILURES`] saves of a fragment.
+#[derive(Default, Clone)]
+pub struct FailingChest(pub MockChest);
 
 #[async_trait]
-impl TapestryChestHandler<MockConfig> for MockChest {
+impl<T: Config + Serialize + DeserializeOwned> TapestryChestHandler<T> for FailingChest {
 	type Error = StorageError;
 
 	fn new() -> Self {
-		Self {}
+		Self::default()
 	}
 
 	async fn save_tapestry_fragment<TID: TapestryId>(
 		&self,
-		_tapestry_id: &TID,
-		_tapestry_fragment: TapestryFragment<MockConfig>,
-		_increment: bool,
-	) -> crate::Result<u64, MockConfig> {
-		Ok(0)
+		tapestry_id: &TID,
+		tapestry_fragment: TapestryFragment<T>,
+		increment: bool,
+	) -> crate::Result<u64, T> {
+		let fail = SAVE_FAILURES.with(|failures| {
+			let mut failures = failures.borrow_mut();
+			let fail = *failures > 0;
+			*failures = failures.saturating_sub(1);
+			fail
+		});
+		if fail {
+			return Err(StorageError::DatabaseError("Save failed".to_string()).into());
+		}
+		self.0.save_tapestry_fragment(tapestry_id, tapestry_fragment, increment).await
+	}
+
+	async fn save_tapestry_metadata<TID: TapestryId, M: Serialize + Debug + Clone + Send + Sync>(
+		&self,
+		tapestry_id: TID,
+		metadata: M,
+	) -> crate::Result<(), T> {
+		TapestryChestHandler::<T>::save_tapestry_metadata(&self.0, tapestry_id, metadata).await
 	}
 
-	async fn save_tapestry_metadata<TID: TapestryId, M: Debug + Clone + Send + Sync>(
+	async fn save_story_metadata<TID: TapestryId>(
 		&self,
-		_tapestry_id: TID,
-		_metadata: M,
-	) -> crate::Result<(), MockConfig> {
-		Ok(())
+		tapestry_id: TID,
+		metadata: StoryMetadata,
+	) -> crate::Result<(), T> {
+		TapestryChestHandler::<T>::save_story_metadata(&self.0, tapestry_id, metadata).await
 	}
 
 	async fn get_instance_index<TID: TapestryId>(
 		&self,
-		_tapestry_id: TID,
-	) -> crate::Result<Option<u16>, MockConfig> {
-		Ok(Some(0))
+		tapestry_id: TID,
+	) -> crate::Result<Option<u16>, T> {
+		TapestryChestHandler::<T>::get_instance_index(&self.0, tapestry_id).await
 	}
 
 	async fn get_tapestry_fragment<TID: TapestryId>(
 		&self,
-		_tapestry_id: TID,
-		_instance: Option<u64>,
-	) -> crate::Result<Option<TapestryFragment<MockConfig>>, MockConfig> {
-		Ok(Some(TapestryFragment { context_tokens: 0, context_messages: vec![] }))
+		tapestry_id: TID,
+		instance: Option<u64>,
+	) -> crate::Result<Option<TapestryFragment<T>>, T> {
+		self.0.get_tapestry_fragment(tapestry_id, instance).await
 	}
 
-	async fn get_tapestry_metadata<TID: TapestryId, M: DeserializeOwned>(
+	async fn get_tapestry_metadata<TID: TapestryId, M: DeserializeOwned + Send + Sync>(
 		&self,
-		_tapestry_id: TID,
-	) -> crate::Result<Option<M>, MockConfig> {
-		Ok(Some(serde_json::from_str("{}").unwrap()))
+		tapestry_id: TID,
+	) -> crate::Result<Option<M>, T> {
+		TapestryChestHandler::<T>::get_tapestry_metadata(&self.0, tapestry_id).await
 	}
 
-	async fn delete_tapestry<TID: TapestryId>(
+	async fn get_story_metadata<TID: TapestryId>(
 		&self,
-		_tapestry_id: TID,
-	) -> crate::Result<(), MockConfig> {
-		Ok(())
+		tapestry_id: TID,
+	) -> crate::Result<Option<StoryMetadata>, T> {
+		TapestryChestHandler::<T>::get_story_metadata(&self.0, tapestry_id).await
+	}
+
+	async fn save_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		checkpoint: StreamCheckpoint<T>,
+	) -> crate::Result<(), T> {
+		self.0.save_stream_checkpoint(tapestry_id, checkpoint).await
+	}
+
+	async fn get_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> crate::Result<Option<StreamCheckpoint<T>>, T> {
+		self.0.get_stream_checkpoint(tapestry_id).await
+	}
+
+	async fn delete_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> crate::Result<(), T> {
+		TapestryChestHandler::<T>::delete_stream_checkpoint(&self.0, tapestry_id).await
+	}
+
+	async fn delete_tapestry<TID: TapestryId>(&self, tapestry_id: TID) -> crate::Result<(), T> {
+		TapestryChestHandler::<T>::delete_tapestry(&self.0, tapestry_id).await
 	}
 
 	async fn delete_tapestry_fragment<TID: TapestryId>(
 		&self,
-		_tapestry_id: TID,
-		_instance: Option<u64>,
-	) -> crate::Result<(), MockConfig> {
-		Ok(())
+		tapestry_id: TID,
+		instance: Option<u64>,
+	) -> crate::Result<(), T> {
+		TapestryChestHandler::<T>::delete_tapestry_fragment(&self.0, tapestry_id, instance).await
+	}
+
+	async fn purge_expired(&self) -> crate::Result<u64, T> {
+		TapestryChestHandler::<T>::purge_expired(&self.0).await
 	}
 }
 
@@ -81,7 +208,7 @@ impl TapestryId for MockTapestryId {
 	}
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MockConfig;
 impl Config for MockConfig {
 	const TOKEN_THRESHOLD_PERCENTILE: BoundedU8<0, 100> = BoundedU8::new(70).unwrap();
@@ -98,20 +225,47 @@ impl Config for MockConfig {
 	}
 }
 
+/// [`MockConfig`] storing tapestries in a [`FailingChest`].
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailingChestConfig;
+impl Config for FailingChestConfig {
+	const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+
+	type PromptModel = MockLlm;
+	type SummaryModel = MockLlm;
+	type Chest = FailingChest;
+
+	fn convert_prompt_tokens_to_summary_model_tokens(
+		tokens: PromptModelTokens<Self>,
+	) -> SummaryModelTokens<Self> {
+		tokens
+	}
+}
+
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct MockLlm;
 
+impl MockLlm {
+	/// Counts tokens for [`MockConfig`].
+	///
+	/// Takes precedence over [`Llm::count_tokens`] so that the implementation can be resolved when
+	/// referred to through [`MockConfig`].
+	pub fn count_tokens(content: &str) -> Result<u16, MockConfig> {
+		<Self as Llm<MockConfig>>::count_tokens(content)
+	}
+}
+
 #[async_trait]
-impl Llm<MockConfig> for MockLlm {
+impl<T: Config<PromptModel = MockLlm>> Llm<T> for MockLlm {
 	type Tokens = u16;
 	type Parameters = ();
 	type Request = MockLlmRequest;
 	type Response = MockLlmResponse;
 	type PromptError = MockPromptError;
 
-	fn count_tokens(content: &str) -> Result<Self::Tokens, MockConfig> {
-		let bpe = p50k_base().unwrap();
-		let tokens = bpe.encode_with_special_tokens(&content.to_string());
+	fn count_tokens(content: &str) -> Result<Self::Tokens, T> {
+		static BPE: OnceLock<CoreBPE> = OnceLock::new();
+		let tokens = BPE.get_or_init(|| p50k_base().unwrap()).encode_with_special_tokens(content);
 
 		tokens.len().try_into().map_err(|_| {
 			LoomError::Llm(MockPromptError::BadConfig("Token count exceeds u16".to_string()))
@@ -128,13 +282,95 @@ impl Llm<MockConfig> for MockLlm {
 
 	async fn prompt(
 		&self,
-		_is_summarize: bool,
+		_is_summarizing: bool,
 		_prompt_tokens: Self::Tokens,
-		_msgs: Vec<Self::Request>,
+		msgs: Vec<Self::Request>,
 		_params: &Self::Parameters,
-		_max_tokens: Self::Tokens,
-	) -> Result<Self::Response, MockConfig> {
-		Ok(MockLlmResponse {})
+		max_tokens: Self::Tokens,
+	) -> Result<Self::Response, T> {
+		PROMPTS.with(|prompts| prompts.borrow_mut().push(MockPrompt { msgs, max_tokens }));
+
+		if let Some(delay) = PROMPT_DELAY.with(|delay| *delay.borrow()) {
+			tokio::time::sleep(delay).await;
+		}
+
+		if let Some(retry_after) =
+			RATE_LIMITS.with(|rate_limits| rate_limits.borrow_mut().pop_front())
+		{
+			return Err(LoomError::Llm(MockPromptError::RateLimited(retry_after)));
+		}
+
+		let server_error = SERVER_ERRORS.with(|server_errors| {
+			let mut server_errors = server_errors.borrow_mut();
+			let server_error = *server_errors > 0;
+			*server_errors = server_errors.saturating_sub(1);
+			server_error
+		});
+		if server_error {
+			return Err(LoomError::Llm(MockPromptError::ServerError));
+		}
+
+		let pop_response = || {
+			RESPONSES
+				.with(|responses| responses.borrow_mut().pop_front())
+				.unwrap_or_default()
+		};
+		let mut response = pop_response();
+		if let Some(n) = CANDIDATES.with(|candidates| candidates.take()) {
+			response.candidates = std::iter::once(response.content.clone())
+				.chain((1..n).map(|_| pop_response().content))
+				.collect();
+		}
+
+		Ok(response)
+	}
+
+	/// Fails like [`MockLlm::prompt`] on a server error or after the prompt delay.
+	async fn check_connection(&self) -> Result<(), T> {
+		if let Some(delay) = PROMPT_DELAY.with(|delay| *delay.borrow()) {
+			tokio::time::sleep(delay).await;
+		}
+
+		let server_error = SERVER_ERRORS.with(|server_errors| {
+			let mut server_errors = server_errors.borrow_mut();
+			let server_error = *server_errors > 0;
+			*server_errors = server_errors.saturating_sub(1);
+			server_error
+		});
+		if server_error {
+			return Err(LoomError::Llm(MockPromptError::ServerError));
+		}
+
+		Ok(())
+	}
+
+	/// Streams the response word by word.
+	async fn prompt_stream(
+		&self,
+		is_summarizing: bool,
+		prompt_tokens: Self::Tokens,
+		msgs: Vec<Self::Request>,
+		params: &Self::Parameters,
+		max_tokens: Self::Tokens,
+		on_delta: &mut (dyn for<'a> FnMut(&'a str) + Send),
+	) -> Result<Self::Response, T> {
+		let response =
+			<Self as Llm<T>>::prompt(self, is_summarizing, prompt_tokens, msgs, params, max_tokens)
+				.await?;
+		let interruption = STREAM_INTERRUPTION.with(|interruption| interruption.take());
+		let stall = STREAM_STALL.with(|stall| stall.take());
+
+		for (i, delta) in response.content.split_inclusive(' ').enumerate() {
+			if interruption == Some(i) {
+				return Err(LoomError::Llm(MockPromptError::StreamInterrupted));
+			}
+			if stall == Some(i) {
+				std::future::pending::<()>().await;
+			}
+			on_delta(delta);
+		}
+
+		Ok(response)
 	}
 
 	fn max_context_length(&self) -> Self::Tokens {
@@ -145,62 +381,157 @@ impl Llm<MockConfig> for MockLlm {
 		tokens
 	}
 
-	fn ctx_msgs_to_prompt_requests(
-		&self,
-		msgs: &[ContextMessage<MockConfig>],
-	) -> Vec<Self::Request> {
+	fn ctx_msgs_to_prompt_requests(&self, msgs: &[ContextMessage<T>]) -> Vec<Self::Request> {
 		msgs.iter().map(|msg| MockLlmRequest::from(msg.clone())).collect()
 	}
 
 	fn compute_cost(&self, prompt_tokens: Self::Tokens, response_tokens: Self::Tokens) -> f64 {
 		(prompt_tokens + response_tokens) as f64
 	}
+
+	fn retry_after(&self, error: &LoomError<T>) -> Option<Duration> {
+		match error {
+			LoomError::Llm(MockPromptError::RateLimited(retry_after)) => Some(*retry_after),
+			_ => None,
+		}
+	}
+
+	fn is_transient(&self, error: &LoomError<T>) -> bool {
+		matches!(error, LoomError::Llm(MockPromptError::ServerError))
+	}
+
+	fn set_stop_sequences(&self, _params: &mut Self::Parameters, stop: Vec<String>) {
+		STOP_SEQUENCES.with(|stop_sequences| *stop_sequences.borrow_mut() = stop);
+	}
+
+	fn set_json_mode(&self, _params: &mut Self::Parameters) {
+		JSON_MODE.with(|json_mode| *json_mode.borrow_mut() = true);
+	}
+
+	fn set_sampling(&self, _params: &mut Self::Parameters, sampling: SamplingParameters) {
+		SAMPLING.with(|s| *s.borrow_mut() = Some(sampling));
+	}
+
+	fn set_candidates(&self, _params: &mut Self::Parameters, n: u8) {
+		CANDIDATES.with(|candidates| *candidates.borrow_mut() = Some(n));
+	}
+
+	fn candidates(&self, response: &Self::Response) -> Vec<String> {
+		if response.candidates.is_empty() {
+			vec![response.content.clone()]
+		} else {
+			response.candidates.clone()
+		}
+	}
+
+	fn set_tools(&self, _params: &mut Self::Parameters, tools: &[ToolDefinition]) {
+		TOOLS.with(|t| *t.borrow_mut() = tools.iter().map(|tool| tool.name.clone()).collect());
+	}
+
+	fn tool_call(&self, response: &Self::Response) -> Option<ToolCall> {
+		response.tool_call.clone()
+	}
+
+	fn set_response_content(&self, response: Self::Response, content: String) -> Self::Response {
+		MockLlmResponse { content, ..response }
+	}
+
+	fn capabilities(&self) -> ModelCapabilities {
+		ModelCapabilities { json_mode: true, tools: true, ..Default::default() }
+	}
+
+	fn required_capabilities(&self, _params: &Self::Parameters) -> ModelCapabilities {
+		let json_mode = JSON_MODE.with(|json_mode| *json_mode.borrow());
+		let required = REQUIRED_CAPABILITIES.with(|required| *required.borrow());
+		ModelCapabilities { json_mode: required.json_mode || json_mode, ..required }
+	}
+
+	fn is_truncated(&self, response: &Self::Response) -> bool {
+		response.truncated
+	}
+
+	fn append_response(
+		&self,
+		response: Self::Response,
+		continuation: Self::Response,
+	) -> Self::Response {
+		MockLlmResponse {
+			content: response.content + &continuation.content,
+			truncated: continuation.truncated,
+			tool_call: continuation.tool_call,
+			candidates: Vec::new(),
+		}
+	}
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MockLlmRequest {
 	pub id: u32,
 	pub msg: String,
+	/// Role of the message, `None` for requests built with [`MockLlmRequest::new`].
+	pub role: Option<String>,
+	/// Name of the message, taken from the `account_id`.
+	pub name: Option<String>,
 }
 
 impl MockLlmRequest {
 	pub fn new(id: u32, msg: String) -> Self {
-		Self { id, msg }
+		Self { id, msg, role: None, name: None }
 	}
 }
 
+/// Renders the role and name of the request ahead of its message, like the overhead they add to
+/// the requests of chat models.
 impl Display for MockLlmRequest {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-		write!(f, "{}", self.msg)
+		match (&self.role, &self.name) {
+			(Some(role), Some(name)) => write!(f, "{} {}: {}", role, name, self.msg),
+			(Some(role), None) => write!(f, "{}: {}", role, self.msg),
+			_ => write!(f, "{}", self.msg),
+		}
 	}
 }
 
-impl From<ContextMessage<MockConfig>> for MockLlmRequest {
-	fn from(_msg: ContextMessage<MockConfig>) -> Self {
+impl<T: Config> From<ContextMessage<T>> for MockLlmRequest {
+	fn from(msg: ContextMessage<T>) -> Self {
+		Self { id: 0, msg: msg.content, role: Some(msg.role.into()), name: msg.account_id }
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockLlmResponse {
+	pub content: String,
+	pub truncated: bool,
+	pub tool_call: Option<ToolCall>,
+	/// Every candidate generated, if more than one was requested.
+	pub candidates: Vec<String>,
+}
+
+impl Default for MockLlmResponse {
+	fn default() -> Self {
 		Self {
-			id: 0,                      // or some logic to assign an ID
-			msg: "default".to_string(), // or use data from _msg
+			content: "TestLlmResponse".to_string(),
+			truncated: false,
+			tool_call: None,
+			candidates: Vec::new(),
 		}
 	}
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct MockLlmResponse;
-
 impl Display for MockLlmResponse {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-		write!(f, "TestLlmResponse")
+		write!(f, "{}", self.content)
 	}
 }
 
 impl From<Option<String>> for MockLlmResponse {
-	fn from(_msg: Option<String>) -> Self {
-		Self {}
+	fn from(msg: Option<String>) -> Self {
+		msg.map(|content| Self { content, ..Default::default() }).unwrap_or_default()
 	}
 }
 
 impl From<MockLlmResponse> for Option<String> {
-	fn from(_msg: MockLlmResponse) -> Self {
-		Some("TestLlmResponse".to_string())
+	fn from(msg: MockLlmResponse) -> Self {
+		(!msg.content.is_empty()).then_some(msg.content)
 	}
 }
 
@@ -208,4 +539,10 @@ impl From<MockLlmResponse> for Option<String> {
 pub enum MockPromptError {
 	#[error("Bad configuration: {0}")]
 	BadConfig(String),
+	#[error("Rate limited, retry after {0:?}")]
+	RateLimited(Duration),
+	#[error("Stream interrupted")]
+	StreamInterrupted,
+	#[error("Internal server error")]
+	ServerError,
 }
@@ -0,0 +1,161 @@
+//! Builder collecting the arguments of [`Loom::weave`] and its variants.
+
+use serde::de::DeserializeOwned;
+
+use crate::{
+	loom::Loom,
+	types::{LoomError, PromptModelResponse, PromptPreview, ToolDefinition, ToolReply, Usage},
+	Config, ContextMessage, LlmConfig, TapestryId,
+};
+
+/// Arguments of a [`Loom::weave`] call, created by [`Loom::weave_request`].
+///
+/// The instructions default to none and messages are appended one by one or in bulk, the request
+/// then being sent by one of the terminal methods, each running the [`Loom`] method of the same
+/// purpose.
+pub struct WeaveRequest<'a, T: Config, TID: TapestryId> {
+	loom: &'a Loom<T>,
+	prompt_llm_config: LlmConfig<T, T::PromptModel>,
+	summary_llm_config: LlmConfig<T, T::SummaryModel>,
+	tapestry_id: TID,
+	instructions: String,
+	msgs: Vec<ContextMessage<T>>,
+}
+
+impl<'a, T: Config, TID: TapestryId> WeaveRequest<'a, T, TID> {
+	pub(crate) fn new(
+		loom: &'a Loom<T>,
+		prompt_llm_config: LlmConfig<T, T::PromptModel>,
+		summary_llm_config: LlmConfig<T, T::SummaryModel>,
+		tapestry_id: TID,
+	) -> Self {
+		Self {
+			loom,
+			prompt_llm_config,
+			summary_llm_config,
+			tapestry_id,
+			instructions: String::new(),
+			msgs: Vec::new(),
+		}
+	}
+
+	/// Sets the instruction message. See [`Config::instructions_path`].
+	pub fn instructions(mut self, instructions: impl Into<String>) -> Self {
+		self.instructions = instructions.into();
+		self
+	}
+
+	/// Appends a message to prompt the LLM with.
+	pub fn message(mut self, msg: ContextMessage<T>) -> Self {
+		self.msgs.push(msg);
+		self
+	}
+
+	/// Appends messages to prompt the LLM with.
+	pub fn messages(mut self, msgs: impl IntoIterator<Item = ContextMessage<T>>) -> Self {
+		self.msgs.extend(msgs);
+		self
+	}
+
+	/// Runs [`Loom::weave`].
+	pub async fn send(self) -> Result<(PromptModelResponse<T>, u64, bool), LoomError<T>> {
+		self.loom
+			.weave(
+				self.prompt_llm_config,
+				self.summary_llm_config,
+				self.tapestry_id,
+				self.instructions,
+				self.msgs,
+			)
+			.await
+	}
+
+	/// Runs [`Loom::weave_with_usage`].
+	pub async fn send_with_usage(
+		self,
+	) -> Result<(PromptModelResponse<T>, u64, bool, Usage), LoomError<T>> {
+		self.loom
+			.weave_with_usage(
+				self.prompt_llm_config,
+				self.summary_llm_config,
+				self.tapestry_id,
+				self.instructions,
+				self.msgs,
+			)
+			.await
+	}
+
+	/// Runs [`Loom::weave_streaming`].
+	pub async fn stream(
+		self,
+		on_delta: &mut (dyn FnMut(&str) + Send),
+	) -> Result<(PromptModelResponse<T>, u64, bool), LoomError<T>> {
+		self.loom
+			.weave_streaming(
+				self.prompt_llm_config,
+				self.summary_llm_config,
+				self.tapestry_id,
+				self.instructions,
+				self.msgs,
+				on_delta,
+			)
+			.await
+	}
+
+	/// Runs [`Loom::prompt_with_tools`].
+	pub async fn send_with_tools(
+		self,
+		tools: &[ToolDefinition],
+	) -> Result<(ToolReply<T>, u64, bool), LoomError<T>> {
+		self.loom
+			.prompt_with_tools(
+				self.prompt_llm_config,
+				self.summary_llm_config,
+				self.tapestry_id,
+				self.instructions,
+				self.msgs,
+				tools,
+			)
+			.await
+	}
+
+	/// Runs [`Loom::weave_json`].
+	pub async fn send_json<D: DeserializeOwned>(self) -> Result<(D, u64, bool), LoomError<T>> {
+		self.loom
+			.weave_json(
+				self.prompt_llm_config,
+				self.summary_llm_config,
+				self.tapestry_id,
+				self.instructions,
+				self.msgs,
+			)
+			.await
+	}
+
+	/// Runs [`Loom::weave_candidates`].
+	pub async fn candidates(self, n: u8) -> Result<(Vec<String>, Usage), LoomError<T>> {
+		self.loom
+			.weave_candidates(
+				self.prompt_llm_config,
+				self.summary_llm_config,
+				self.tapestry_id,
+				self.instructions,
+				self.msgs,
+				n,
+			)
+			.await
+	}
+
+	/// Runs [`Loom::weave_dry_run`].
+	pub async fn dry_run(self) -> Result<PromptPreview<T>, LoomError<T>> {
+		self.loom
+			.weave_dry_run(
+				self.prompt_llm_config,
+				self.summary_llm_config,
+				self.tapestry_id,
+				self.instructions,
+				self.msgs,
+			)
+			.await
+	}
+}
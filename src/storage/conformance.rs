@@ -0,0 +1,203 @@
+//! Conformance test kit for [`TapestryChestHandler`] implementations.
+//!
+//! Enable the `test-util` feature and call [`run`] from the tests of a storage backend to check
+//! that it follows the semantics expected by [`crate::loom::Loom`].
+//!
+//! ```ignore
+//! #[tokio::test]
+//! async fn conformance() {
+//!     llm_weaver::storage::conformance::run::<MyConfig, _>(&MyChest::new()).await;
+//! }
+//! ```
+
+use chrono::Utc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+	types::{ASSISTANT_ROLE, USER_ROLE},
+	Config, ContextMessage, StoryMetadata, StreamCheckpoint, TapestryChestHandler,
+	TapestryFragment, TapestryId,
+};
+
+/// [`TapestryId`] unique to each [`run`], so that backends with persistent storage are not
+/// affected by previous runs.
+#[derive(Debug, Clone)]
+struct ConformanceTapestryId(String);
+
+impl TapestryId for ConformanceTapestryId {
+	fn base_key(&self) -> String {
+		self.0.clone()
+	}
+}
+
+fn fragment<T: Config>(contents: &[&str]) -> TapestryFragment<T> {
+	TapestryFragment {
+		context_messages: contents
+			.iter()
+			.enumerate()
+			.map(|(i, content)| {
+				ContextMessage::new(
+					if i % 2 == 0 { USER_ROLE } else { ASSISTANT_ROLE }.into(),
+					content.to_string(),
+					None,
+					"timestamp".to_string(),
+				)
+			})
+			.collect(),
+		..Default::default()
+	}
+}
+
+fn contents<T: Config>(tapestry_fragment: Option<TapestryFragment<T>>) -> Option<Vec<String>> {
+	tapestry_fragment.map(|f| f.context_messages.into_iter().map(|m| m.content).collect())
+}
+
+fn checkpoint_contents<T: Config>(
+	checkpoint: Option<StreamCheckpoint<T>>,
+) -> Option<(Vec<String>, String)> {
+	checkpoint.map(|c| (c.msgs.into_iter().map(|m| m.content).collect(), c.response))
+}
+
+/// Exercises saving, retrieving, incrementing and deleting tapestry fragments, metadata and stream
+/// checkpoints against `handler`, panicking on the first deviation from the expected semantics.
+pub async fn run<T: Config, H: TapestryChestHandler<T>>(handler: &H) {
+	let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+	let id = ConformanceTapestryId(format!("conformance:{}", nanos));
+
+	// Unknown tapestry
+	assert_eq!(handler.get_instance_index(id.clone()).await.unwrap(), None);
+	assert!(handler.get_tapestry_fragment(id.clone(), None).await.unwrap().is_none());
+	assert_eq!(handler.get_tapestry_metadata::<_, String>(id.clone()).await.unwrap(), None);
+
+	// Updating an unknown tapestry creates the first instance
+	let other_id = ConformanceTapestryId(format!("conformance:{}:update", nanos));
+	let instance = handler
+		.save_tapestry_fragment(&other_id, fragment::<T>(&["a"]), false)
+		.await
+		.unwrap();
+	assert_eq!(instance, 1, "saving without incrementing should create the first instance");
+	assert_eq!(handler.get_instance_index(other_id.clone()).await.unwrap(), Some(1));
+	handler.delete_tapestry(other_id).await.unwrap();
+
+	// First instance
+	let instance = handler.save_tapestry_fragment(&id, fragment::<T>(&["a"]), true).await.unwrap();
+	assert_eq!(instance, 1, "first instance should be 1");
+	assert_eq!(handler.get_instance_index(id.clone()).await.unwrap(), Some(1));
+	assert_eq!(
+		contents(handler.get_tapestry_fragment(id.clone(), None).await.unwrap()),
+		Some(vec!["a".to_string()])
+	);
+
+	// Updating the current instance
+	let instance = handler
+		.save_tapestry_fragment(&id, fragment::<T>(&["a", "b"]), false)
+		.await
+		.unwrap();
+	assert_eq!(instance, 1, "saving without incrementing should update the current instance");
+	assert_eq!(
+		contents(handler.get_tapestry_fragment(id.clone(), Some(1)).await.unwrap()),
+		Some(vec!["a".to_string(), "b".to_string()])
+	);
+
+	// Incrementing the instance keeps previous instances
+	let instance = handler.save_tapestry_fragment(&id, fragment::<T>(&["c"]), true).await.unwrap();
+	assert_eq!(instance, 2, "incrementing should create the next instance");
+	assert_eq!(handler.get_instance_index(id.clone()).await.unwrap(), Some(2));
+	assert_eq!(
+		contents(handler.get_tapestry_fragment(id.clone(), None).await.unwrap()),
+		Some(vec!["c".to_string()])
+	);
+	assert_eq!(
+		contents(handler.get_tapestry_fragment(id.clone(), Some(1)).await.unwrap()),
+		Some(vec!["a".to_string(), "b".to_string()])
+	);
+
+	// Metadata
+	handler
+		.save_tapestry_metadata(id.clone(), "metadata".to_string())
+		.await
+		.unwrap();
+	assert_eq!(
+		handler.get_tapestry_metadata::<_, String>(id.clone()).await.unwrap(),
+		Some("metadata".to_string())
+	);
+
+	// Story metadata is kept apart from the metadata
+	assert_eq!(handler.get_story_metadata(id.clone()).await.unwrap(), None);
+	let story_metadata = StoryMetadata {
+		title: "title".to_string(),
+		tags: vec!["tag".to_string()],
+		created_at: Utc::now(),
+		updated_at: Utc::now(),
+	};
+	handler.save_story_metadata(id.clone(), story_metadata.clone()).await.unwrap();
+	assert_eq!(handler.get_story_metadata(id.clone()).await.unwrap(), Some(story_metadata));
+	assert_eq!(
+		handler.get_tapestry_metadata::<_, String>(id.clone()).await.unwrap(),
+		Some("metadata".to_string())
+	);
+
+	// Stream checkpoints replace each other until deleted
+	assert!(handler.get_stream_checkpoint(id.clone()).await.unwrap().is_none());
+	for response in ["b", "b c"] {
+		let checkpoint = StreamCheckpoint {
+			msgs: fragment::<T>(&["a"]).context_messages,
+			response: response.to_string(),
+		};
+		handler.save_stream_checkpoint(id.clone(), checkpoint).await.unwrap();
+		assert_eq!(
+			checkpoint_contents(handler.get_stream_checkpoint(id.clone()).await.unwrap()),
+			Some((vec!["a".to_string()], response.to_string()))
+		);
+	}
+	handler.delete_stream_checkpoint(id.clone()).await.unwrap();
+	assert!(handler.get_stream_checkpoint(id.clone()).await.unwrap().is_none());
+	handler.delete_stream_checkpoint(id.clone()).await.unwrap();
+	let checkpoint = StreamCheckpoint { msgs: Vec::new(), response: "b".to_string() };
+	handler.save_stream_checkpoint(id.clone(), checkpoint).await.unwrap();
+
+	// Deleting the last instance
+	handler.delete_tapestry_fragment(id.clone(), None).await.unwrap();
+	assert_eq!(handler.get_instance_index(id.clone()).await.unwrap(), Some(1));
+	assert_eq!(
+		contents(handler.get_tapestry_fragment(id.clone(), None).await.unwrap()),
+		Some(vec!["a".to_string(), "b".to_string()])
+	);
+
+	// Deleting the tapestry
+	handler.delete_tapestry(id.clone()).await.unwrap();
+	assert_eq!(handler.get_instance_index(id.clone()).await.unwrap(), None);
+	assert!(handler.get_tapestry_fragment(id.clone(), None).await.unwrap().is_none());
+	assert!(handler.get_tapestry_fragment(id.clone(), Some(1)).await.unwrap().is_none());
+	assert_eq!(handler.get_tapestry_metadata::<_, String>(id.clone()).await.unwrap(), None);
+	assert_eq!(handler.get_story_metadata(id.clone()).await.unwrap(), None);
+	assert!(handler.get_stream_checkpoint(id.clone()).await.unwrap().is_none());
+
+	// Deleting a tapestry that does not exist
+	handler.delete_tapestry(id).await.unwrap();
+
+	// Purging expired tapestries, the expiry being overwritten on save when a TTL is set
+	if T::TAPESTRY_TTL.is_none() {
+		let expired_id = ConformanceTapestryId(format!("conformance:{}:expired", nanos));
+		let kept_id = ConformanceTapestryId(format!("conformance:{}:kept", nanos));
+		let expired = TapestryFragment {
+			expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+			..fragment::<T>(&["a"])
+		};
+		handler.save_tapestry_fragment(&expired_id, expired, true).await.unwrap();
+		handler
+			.save_tapestry_metadata(expired_id.clone(), "metadata".to_string())
+			.await
+			.unwrap();
+		handler
+			.save_tapestry_fragment(&kept_id, fragment::<T>(&["a"]), true)
+			.await
+			.unwrap();
+
+		assert!(handler.purge_expired().await.unwrap() >= 1, "expired tapestry should be purged");
+		assert_eq!(handler.get_instance_index(expired_id.clone()).await.unwrap(), None);
+		assert_eq!(handler.get_tapestry_metadata::<_, String>(expired_id).await.unwrap(), None);
+		assert_eq!(handler.get_instance_index(kept_id.clone()).await.unwrap(), Some(1));
+		handler.delete_tapestry(kept_id).await.unwrap();
+	}
+}
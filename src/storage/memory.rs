@@ -0,0 +1,237 @@
+//! In-memory [`TapestryChestHandler`] implementation, enabled by the `test-util` feature.
+
+use std::{
+	collections::HashMap,
+	fmt::Debug,
+	sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{decode_fragment, encode_fragment, refresh_expiry, storage_key, TapestryChestHandler};
+use crate::{
+	types::StorageError, Config, Result, StoryMetadata, StreamCheckpoint, TapestryFragment,
+	TapestryId,
+};
+
+/// Fragment serialized by [`encode_fragment`].
+type EncodedFragment = Vec<u8>;
+
+/// In-memory [`TapestryChestHandler`] storing serialized fragments and metadata per
+/// [`storage_key`], for tests and local development.
+///
+/// Each [`TapestryId`] holds a list of instances, `increment` pushing a new one and otherwise
+/// overwriting the latest. Clones share the same storage, so it can be shared across tasks.
+#[derive(Default, Clone)]
+pub struct InMemoryBackend {
+	fragments: Arc<Mutex<HashMap<String, Vec<Option<EncodedFragment>>>>>,
+	metadata: Arc<Mutex<HashMap<String, String>>>,
+	story_metadata: Arc<Mutex<HashMap<String, StoryMetadata>>>,
+	stream_checkpoints: Arc<Mutex<HashMap<String, String>>>,
+}
+
+#[async_trait]
+impl<T: Config + Serialize + DeserializeOwned> TapestryChestHandler<T> for InMemoryBackend {
+	type Error = StorageError;
+
+	fn new() -> Self {
+		Self::default()
+	}
+
+	async fn save_tapestry_fragment<TID: TapestryId>(
+		&self,
+		tapestry_id: &TID,
+		mut tapestry_fragment: TapestryFragment<T>,
+		increment: bool,
+	) -> Result<u64, T> {
+		refresh_expiry(&mut tapestry_fragment);
+		let fragment = encode_fragment(&tapestry_fragment)?;
+		let mut fragments = self.fragments.lock().unwrap();
+		let instances = fragments.entry(storage_key::<T, _>(tapestry_id)).or_default();
+
+		if increment || instances.is_empty() {
+			instances.push(Some(fragment));
+		} else {
+			*instances.last_mut().unwrap() = Some(fragment);
+		}
+
+		Ok(instances.len() as u64)
+	}
+
+	async fn save_tapestry_metadata<TID: TapestryId, M: Serialize + Debug + Clone + Send + Sync>(
+		&self,
+		tapestry_id: TID,
+		metadata: M,
+	) -> Result<(), T> {
+		let metadata = serde_json::to_string(&metadata)
+			.map_err(|e| StorageError::SerializationError(e.to_string()))?;
+		self.metadata
+			.lock()
+			.unwrap()
+			.insert(storage_key::<T, _>(&tapestry_id), metadata);
+		Ok(())
+	}
+
+	async fn save_story_metadata<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		metadata: StoryMetadata,
+	) -> Result<(), T> {
+		self.story_metadata
+			.lock()
+			.unwrap()
+			.insert(storage_key::<T, _>(&tapestry_id), metadata);
+		Ok(())
+	}
+
+	async fn get_instance_index<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Option<u16>, T> {
+		Ok(self
+			.fragments
+			.lock()
+			.unwrap()
+			.get(&storage_key::<T, _>(&tapestry_id))
+			.map(|f| f.len() as u16))
+	}
+
+	async fn get_tapestry_fragment<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		instance: Option<u64>,
+	) -> Result<Option<TapestryFragment<T>>, T> {
+		let fragments = self.fragments.lock().unwrap();
+		let fragment =
+			fragments.get(&storage_key::<T, _>(&tapestry_id)).and_then(|f| match instance {
+				Some(i) => f.get((i as usize).checked_sub(1)?)?.as_ref(),
+				None => f.last()?.as_ref(),
+			});
+
+		fragment.map(|f| decode_fragment(f)).transpose()
+	}
+
+	async fn get_tapestry_metadata<TID: TapestryId, M: DeserializeOwned>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Option<M>, T> {
+		self.metadata
+			.lock()
+			.unwrap()
+			.get(&storage_key::<T, _>(&tapestry_id))
+			.map(|m| {
+				serde_json::from_str(m)
+					.map_err(|e| StorageError::DeserializationError(e.to_string()))
+			})
+			.transpose()
+			.map_err(|e| e.into())
+	}
+
+	async fn get_story_metadata<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Option<StoryMetadata>, T> {
+		Ok(self
+			.story_metadata
+			.lock()
+			.unwrap()
+			.get(&storage_key::<T, _>(&tapestry_id))
+			.cloned())
+	}
+
+	async fn save_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		checkpoint: StreamCheckpoint<T>,
+	) -> Result<(), T> {
+		let checkpoint = serde_json::to_string(&checkpoint)
+			.map_err(|e| StorageError::SerializationError(e.to_string()))?;
+		self.stream_checkpoints
+			.lock()
+			.unwrap()
+			.insert(storage_key::<T, _>(&tapestry_id), checkpoint);
+		Ok(())
+	}
+
+	async fn get_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Option<StreamCheckpoint<T>>, T> {
+		self.stream_checkpoints
+			.lock()
+			.unwrap()
+			.get(&storage_key::<T, _>(&tapestry_id))
+			.map(|c| {
+				serde_json::from_str(c)
+					.map_err(|e| StorageError::DeserializationError(e.to_string()))
+			})
+			.transpose()
+			.map_err(|e| e.into())
+	}
+
+	async fn delete_stream_checkpoint<TID: TapestryId>(&self, tapestry_id: TID) -> Result<(), T> {
+		self.stream_checkpoints
+			.lock()
+			.unwrap()
+			.remove(&storage_key::<T, _>(&tapestry_id));
+		Ok(())
+	}
+
+	async fn delete_tapestry<TID: TapestryId>(&self, tapestry_id: TID) -> Result<(), T> {
+		self.fragments.lock().unwrap().remove(&storage_key::<T, _>(&tapestry_id));
+		self.metadata.lock().unwrap().remove(&storage_key::<T, _>(&tapestry_id));
+		self.story_metadata.lock().unwrap().remove(&storage_key::<T, _>(&tapestry_id));
+		self.stream_checkpoints
+			.lock()
+			.unwrap()
+			.remove(&storage_key::<T, _>(&tapestry_id));
+		Ok(())
+	}
+
+	async fn delete_tapestry_fragment<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		instance: Option<u64>,
+	) -> Result<(), T> {
+		let mut fragments = self.fragments.lock().unwrap();
+		if let Some(f) = fragments.get_mut(&storage_key::<T, _>(&tapestry_id)) {
+			let instance = instance.unwrap_or(f.len() as u64) as usize;
+			if instance == f.len() {
+				f.pop();
+			} else if let Some(fragment) = instance.checked_sub(1).and_then(|i| f.get_mut(i)) {
+				*fragment = None;
+			}
+		}
+		Ok(())
+	}
+
+	async fn purge_expired(&self) -> Result<u64, T> {
+		let now = Utc::now();
+		let mut fragments = self.fragments.lock().unwrap();
+
+		let mut expired = Vec::new();
+		for (key, instances) in fragments.iter() {
+			let Some(fragment) = instances.last().and_then(Option::as_ref) else {
+				continue;
+			};
+			let fragment: TapestryFragment<T> = decode_fragment(fragment)?;
+			if fragment.expires_at.is_some_and(|expires_at| expires_at <= now) {
+				expired.push(key.clone());
+			}
+		}
+
+		let mut metadata = self.metadata.lock().unwrap();
+		let mut story_metadata = self.story_metadata.lock().unwrap();
+		let mut stream_checkpoints = self.stream_checkpoints.lock().unwrap();
+		for key in &expired {
+			fragments.remove(key);
+			metadata.remove(key);
+			story_metadata.remove(key);
+			stream_checkpoints.remove(key);
+		}
+
+		Ok(expired.len() as u64)
+	}
+}
@@ -1,12 +1,87 @@
 use async_trait::async_trait;
+use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde::{de::DeserializeOwned, Serialize};
-use std::fmt::{Debug, Display};
+use std::{
+	fmt::{Debug, Display},
+	io::{Read, Write},
+};
 
-use crate::{Config, TapestryFragment, TapestryId};
+use crate::{
+	types::StorageError, Config, StoryMetadata, StreamCheckpoint, TapestryFragment, TapestryId,
+};
 
+#[cfg(any(test, feature = "test-util"))]
+pub mod conformance;
+#[cfg(any(test, feature = "test-util"))]
+pub mod memory;
 #[cfg(feature = "rocksdb")]
 pub mod rocksdb;
 
+/// Derives the storage key of a tapestry.
+///
+/// Prefixes the [`TapestryId::base_key`] with the [`Config::STORAGE_NAMESPACE`], if any, so that
+/// deployments sharing a storage backend are isolated from each other. Every
+/// [`TapestryChestHandler`] implementation should derive its keys from this function.
+pub fn storage_key<T: Config, TID: TapestryId>(tapestry_id: &TID) -> String {
+	match T::STORAGE_NAMESPACE {
+		Some(namespace) => format!("{}:{}", namespace, tapestry_id.base_key()),
+		None => tapestry_id.base_key(),
+	}
+}
+
+/// Sets the `expires_at` of a tapestry fragment about to be saved to the [`Config::TAPESTRY_TTL`]
+/// from now, if any.
+///
+/// Every [`TapestryChestHandler`] implementation should call this function before saving a
+/// fragment, so that a tapestry expires once it has not been saved for the TTL.
+pub fn refresh_expiry<T: Config>(tapestry_fragment: &mut TapestryFragment<T>) {
+	if let Some(ttl) = T::TAPESTRY_TTL.and_then(|ttl| chrono::Duration::from_std(ttl).ok()) {
+		tapestry_fragment.expires_at = Utc::now().checked_add_signed(ttl);
+	}
+}
+
+/// Magic bytes of the gzip header starting a compressed fragment, which cannot start a JSON
+/// fragment.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Serializes a tapestry fragment to JSON, gzip compressed when [`Config::COMPRESS_FRAGMENTS`] is
+/// enabled.
+///
+/// Every [`TapestryChestHandler`] implementation should serialize fragments with this function
+/// and deserialize them with [`decode_fragment`].
+pub fn encode_fragment<T: Config + Serialize>(
+	tapestry_fragment: &TapestryFragment<T>,
+) -> crate::Result<Vec<u8>, T> {
+	let json = serde_json::to_vec(tapestry_fragment)
+		.map_err(|e| StorageError::SerializationError(e.to_string()))?;
+	if !T::COMPRESS_FRAGMENTS {
+		return Ok(json);
+	}
+
+	let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+	encoder
+		.write_all(&json)
+		.and_then(|_| encoder.finish())
+		.map_err(|e| StorageError::SerializationError(e.to_string()).into())
+}
+
+/// Deserializes a tapestry fragment serialized by [`encode_fragment`], whether compressed or not.
+pub fn decode_fragment<T: Config + DeserializeOwned>(
+	bytes: &[u8],
+) -> crate::Result<TapestryFragment<T>, T> {
+	let deserialization_error = |e: String| StorageError::DeserializationError(e).into();
+	if !bytes.starts_with(&GZIP_MAGIC) {
+		return serde_json::from_slice(bytes).map_err(|e| deserialization_error(e.to_string()));
+	}
+
+	let mut json = Vec::new();
+	GzDecoder::new(bytes)
+		.read_to_end(&mut json)
+		.map_err(|e| deserialization_error(e.to_string()))?;
+	serde_json::from_slice(&json).map_err(|e| deserialization_error(e.to_string()))
+}
+
 /// A storage handler trait designed for saving and retrieving fragments of a tapestry.
 ///
 /// # Usage
@@ -26,7 +101,7 @@ pub trait TapestryChestHandler<T: Config> {
 	/// Tapestry fragments are stored incrementally, also refered to as "instances". Each instance
 	/// is identified by an integer, starting at 1 and incrementing by 1 for each new instance.
 	///
-	/// This method is executed primarily by the [`crate::Loom::weave`] function.
+	/// This method is executed primarily by the [`crate::loom::Loom::weave`] function.
 	///
 	/// # Parameters
 	///
@@ -36,6 +111,13 @@ pub trait TapestryChestHandler<T: Config> {
 	///     - A boolean flag indicating whether the tapestry instance should be incremented.
 	///     - This should typically be `true` when saving a new instance of [`TapestryFragment`],
 	///       and `false` when updating an existing one.
+	///     - When `true`, the fragment is saved as the next instance, keeping the previous ones.
+	///     - When `false`, the fragment overwrites the latest instance, or is saved as instance 1
+	///       if the tapestry does not exist yet.
+	///
+	/// # Returns
+	///
+	/// The instance the fragment was saved to, which becomes the [`Self::get_instance_index`].
 	async fn save_tapestry_fragment<TID: TapestryId>(
 		&self,
 		tapestry_id: &TID,
@@ -50,6 +132,13 @@ pub trait TapestryChestHandler<T: Config> {
 		tapestry_id: TID,
 		metadata: M,
 	) -> crate::Result<(), T>;
+	/// Saves the [`StoryMetadata`] of a tapestry, kept apart from the metadata saved by
+	/// [`TapestryChestHandler::save_tapestry_metadata`].
+	async fn save_story_metadata<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		metadata: StoryMetadata,
+	) -> crate::Result<(), T>;
 	/// Retrieves the index of a tapestry.
 	///
 	/// Returns None if the tapestry does not exist.
@@ -79,7 +168,37 @@ pub trait TapestryChestHandler<T: Config> {
 		&self,
 		tapestry_id: TID,
 	) -> crate::Result<Option<M>, T>;
-	/// Deletes a tapestry and all its instances.
+	/// Retrieves the [`StoryMetadata`] of a tapestry.
+	///
+	/// Returns None if it was never saved.
+	async fn get_story_metadata<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> crate::Result<Option<StoryMetadata>, T>;
+	/// Saves the [`StreamCheckpoint`] of a tapestry, replacing the previous one.
+	async fn save_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		checkpoint: StreamCheckpoint<T>,
+	) -> crate::Result<(), T>;
+	/// Retrieves the [`StreamCheckpoint`] of a tapestry.
+	///
+	/// Returns None if there is none.
+	async fn get_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> crate::Result<Option<StreamCheckpoint<T>>, T>;
+	/// Deletes the [`StreamCheckpoint`] of a tapestry.
+	///
+	/// Deleting a checkpoint that does not exist is a successful no-op.
+	async fn delete_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> crate::Result<(), T>;
+	/// Deletes a tapestry, all its instances, its metadata, its story metadata and its stream
+	/// checkpoint.
+	///
+	/// Deleting a tapestry that does not exist is a successful no-op.
 	async fn delete_tapestry<TID: TapestryId>(&self, tapestry_id: TID) -> crate::Result<(), T>;
 	/// Deletes a tapestry fragment.
 	async fn delete_tapestry_fragment<TID: TapestryId>(
@@ -87,4 +206,10 @@ pub trait TapestryChestHandler<T: Config> {
 		tapestry_id: TID,
 		instance: Option<u64>,
 	) -> crate::Result<(), T>;
+	/// Deletes every tapestry whose last fragment is past its `expires_at`, along with all its
+	/// instances, its metadata, its story metadata and its stream checkpoint.
+	///
+	/// Backends with native key expiration can map the `expires_at` to it, this sweep then only
+	/// having to delete what remains. Returns the number of tapestries deleted.
+	async fn purge_expired(&self) -> crate::Result<u64, T>;
 }
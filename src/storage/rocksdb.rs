@@ -1,6 +1,8 @@
 use async_trait::async_trait;
+use chrono::Utc;
 use rocksdb::{
-	ColumnFamilyDescriptor, DBCompressionType, OptimisticTransactionDB, Options, Transaction,
+	ColumnFamilyDescriptor, DBCompressionType, ErrorKind, IteratorMode, OptimisticTransactionDB,
+	Options, Transaction,
 };
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
@@ -8,13 +10,18 @@ use std::{
 	sync::{Arc, Mutex},
 };
 
-use crate::{types::StorageError, Config, Result, TapestryFragment, TapestryId};
+use crate::{
+	types::StorageError, Config, Result, StoryMetadata, StreamCheckpoint, TapestryFragment,
+	TapestryId,
+};
 
-use super::TapestryChestHandler;
+use super::{decode_fragment, encode_fragment, refresh_expiry, storage_key, TapestryChestHandler};
 
 const INSTANCE_INDEX_CF: &str = "instance_counts";
 const TAPESTRY_METADATA_CF: &str = "tapestry_metadata";
 const TAPESTRY_FRAGMENT_CF: &str = "tapestry_fragments";
+const STORY_METADATA_CF: &str = "story_metadata";
+const STREAM_CHECKPOINT_CF: &str = "stream_checkpoints";
 
 lazy_static::lazy_static! {
 	static ref DB_INSTANCE: Mutex<Option<Arc<OptimisticTransactionDB>>> = Mutex::new(None);
@@ -62,6 +69,8 @@ impl<T: Config> RocksDbBackend<T> {
 		let cf_descriptors = vec![
 			ColumnFamilyDescriptor::new(INSTANCE_INDEX_CF, cf_opts.clone()),
 			ColumnFamilyDescriptor::new(TAPESTRY_METADATA_CF, cf_opts.clone()),
+			ColumnFamilyDescriptor::new(STORY_METADATA_CF, cf_opts.clone()),
+			ColumnFamilyDescriptor::new(STREAM_CHECKPOINT_CF, cf_opts.clone()),
 			ColumnFamilyDescriptor::new(TAPESTRY_FRAGMENT_CF, cf_opts),
 		];
 
@@ -79,10 +88,10 @@ impl<T: Config> RocksDbBackend<T> {
 		let mut txn_backend =
 			RocksDbTransaction { txn, db: Arc::clone(&self.db), phantom: std::marker::PhantomData };
 		let value = func(&mut txn_backend)?;
-		txn_backend
-			.txn
-			.commit()
-			.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+		txn_backend.txn.commit().map_err(|e| match e.kind() {
+			ErrorKind::Busy | ErrorKind::TryAgain => StorageError::Conflict,
+			_ => StorageError::DatabaseError(e.to_string()),
+		})?;
 		Ok(value)
 	}
 }
@@ -120,6 +129,51 @@ impl<'a, T: Config> RocksDbTransaction<'a, T> {
 			.delete_cf(&cf_handle, key)
 			.map_err(|e| StorageError::DatabaseError(e.to_string()).into())
 	}
+
+	fn keys_cf(&self, cf: &str) -> Result<Vec<Vec<u8>>, T> {
+		let cf_handle = self.db.cf_handle(cf).ok_or_else(|| {
+			StorageError::DatabaseError(format!("Column family not found: {}", cf))
+		})?;
+		self.txn
+			.iterator_cf(&cf_handle, IteratorMode::Start)
+			.map(|item| {
+				item.map(|(key, _)| key.into_vec())
+					.map_err(|e| StorageError::DatabaseError(e.to_string()).into())
+			})
+			.collect()
+	}
+
+	/// Deletes every fragment, the instance index and the metadata of the tapestry stored under
+	/// `key`, as derived by [`storage_key`].
+	fn delete_tapestry(&self, key: &str) -> Result<(), T> {
+		let current_index: u64 = self
+			.get_cf(INSTANCE_INDEX_CF, key.as_bytes())?
+			.map(|bytes| {
+				String::from_utf8(bytes)
+					.map_err(|e| StorageError::DeserializationError(e.to_string()))
+			})
+			.transpose()?
+			.map(|s| {
+				s.parse::<u64>().map_err(|e| StorageError::DeserializationError(e.to_string()))
+			})
+			.transpose()?
+			.unwrap_or(0);
+
+		// Delete all fragments
+		for i in 1..=current_index {
+			self.delete_cf(TAPESTRY_FRAGMENT_CF, format!("{}:{}", key, i).as_bytes())?;
+		}
+
+		// Delete instance count
+		self.delete_cf(INSTANCE_INDEX_CF, key.as_bytes())?;
+
+		// Delete metadata
+		self.delete_cf(TAPESTRY_METADATA_CF, key.as_bytes())?;
+		self.delete_cf(STORY_METADATA_CF, key.as_bytes())?;
+		self.delete_cf(STREAM_CHECKPOINT_CF, key.as_bytes())?;
+
+		Ok(())
+	}
 }
 
 #[async_trait]
@@ -135,14 +189,14 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 	async fn save_tapestry_fragment<TID: TapestryId>(
 		&self,
 		tapestry_id: &TID,
-		tapestry_fragment: TapestryFragment<T>,
+		mut tapestry_fragment: TapestryFragment<T>,
 		increment_index: bool,
 	) -> Result<u64, T> {
-		let fragment_bytes = serde_json::to_vec(&tapestry_fragment)
-			.map_err(|e| StorageError::SerializationError(e.to_string()))?;
+		refresh_expiry(&mut tapestry_fragment);
+		let fragment_bytes = encode_fragment(&tapestry_fragment)?;
 
 		self.transaction(|txn| {
-			let instance_index_key = derive_instance_index_key(tapestry_id);
+			let instance_index_key = derive_instance_index_key::<T, _>(tapestry_id);
 			let current_index = txn
 				.get_cf(INSTANCE_INDEX_CF, instance_index_key.as_bytes())?
 				.map(|bytes| {
@@ -165,7 +219,7 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 				new_instance_index.to_string().as_bytes(),
 			)?;
 
-			let fragment_key = derive_instance_key(tapestry_id, new_instance_index);
+			let fragment_key = derive_instance_key::<T, _>(tapestry_id, new_instance_index);
 			txn.put_cf(TAPESTRY_FRAGMENT_CF, fragment_key.as_bytes(), &fragment_bytes)?;
 
 			Ok(new_instance_index)
@@ -181,16 +235,30 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 			.map_err(|e| StorageError::SerializationError(e.to_string()))?;
 
 		self.transaction(|txn| {
-			let metadata_key = derive_metadata_key(&tapestry_id);
+			let metadata_key = derive_metadata_key::<T, _>(&tapestry_id);
 			txn.put_cf(TAPESTRY_METADATA_CF, metadata_key.as_bytes(), &metadata_bytes)
 		})
 	}
 
+	async fn save_story_metadata<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		metadata: StoryMetadata,
+	) -> Result<(), T> {
+		let metadata_bytes = serde_json::to_vec(&metadata)
+			.map_err(|e| StorageError::SerializationError(e.to_string()))?;
+
+		self.transaction(|txn| {
+			let metadata_key = derive_metadata_key::<T, _>(&tapestry_id);
+			txn.put_cf(STORY_METADATA_CF, metadata_key.as_bytes(), &metadata_bytes)
+		})
+	}
+
 	async fn get_instance_index<TID: TapestryId>(
 		&self,
 		tapestry_id: TID,
 	) -> Result<Option<u16>, T> {
-		let instance_index_key = derive_instance_index_key(&tapestry_id);
+		let instance_index_key = derive_instance_index_key::<T, _>(&tapestry_id);
 		self.transaction(|txn| {
 			txn.get_cf(INSTANCE_INDEX_CF, instance_index_key.as_bytes())?
 				.map(|bytes| {
@@ -215,7 +283,7 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 			let instance = if let Some(i) = instance {
 				i
 			} else {
-				let instance_index_key = derive_instance_index_key(&tapestry_id);
+				let instance_index_key = derive_instance_index_key::<T, _>(&tapestry_id);
 				match txn.get_cf(INSTANCE_INDEX_CF, instance_index_key.as_bytes())? {
 					Some(count_bytes) => String::from_utf8(count_bytes)
 						.map_err(|e| StorageError::DeserializationError(e.to_string()))?
@@ -225,14 +293,10 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 				}
 			};
 
-			let fragment_key = derive_instance_key(&tapestry_id, instance);
+			let fragment_key = derive_instance_key::<T, _>(&tapestry_id, instance);
 			txn.get_cf(TAPESTRY_FRAGMENT_CF, fragment_key.as_bytes())?
-				.map(|bytes| {
-					serde_json::from_slice(&bytes)
-						.map_err(|e| StorageError::DeserializationError(e.to_string()))
-				})
+				.map(|bytes| decode_fragment(&bytes))
 				.transpose()
-				.map_err(|e| e.into())
 		})
 	}
 
@@ -240,7 +304,7 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 		&self,
 		tapestry_id: TID,
 	) -> Result<Option<M>, T> {
-		let metadata_key = derive_metadata_key(&tapestry_id);
+		let metadata_key = derive_metadata_key::<T, _>(&tapestry_id);
 		self.transaction(|txn| {
 			txn.get_cf(TAPESTRY_METADATA_CF, metadata_key.as_bytes())?
 				.map(|bytes| {
@@ -252,46 +316,68 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 		})
 	}
 
-	async fn delete_tapestry<TID: TapestryId>(&self, tapestry_id: TID) -> Result<(), T> {
+	async fn get_story_metadata<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Option<StoryMetadata>, T> {
+		let metadata_key = derive_metadata_key::<T, _>(&tapestry_id);
 		self.transaction(|txn| {
-			let instance_index_key = derive_instance_index_key(&tapestry_id);
-			let current_index: u64 = txn
-				.get_cf(INSTANCE_INDEX_CF, instance_index_key.as_bytes())?
+			txn.get_cf(STORY_METADATA_CF, metadata_key.as_bytes())?
 				.map(|bytes| {
-					String::from_utf8(bytes)
+					serde_json::from_slice(&bytes)
 						.map_err(|e| StorageError::DeserializationError(e.to_string()))
 				})
-				.transpose()?
-				.map(|s| {
-					s.parse::<u64>().map_err(|e| StorageError::DeserializationError(e.to_string()))
-				})
-				.transpose()?
-				.unwrap_or(0);
-
-			// Delete all fragments
-			for i in 1..=current_index {
-				let fragment_key = derive_instance_key(&tapestry_id, i);
-				txn.delete_cf(TAPESTRY_FRAGMENT_CF, fragment_key.as_bytes())?;
-			}
+				.transpose()
+				.map_err(|e| e.into())
+		})
+	}
 
-			// Delete instance count
-			txn.delete_cf(INSTANCE_INDEX_CF, instance_index_key.as_bytes())?;
+	async fn save_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+		checkpoint: StreamCheckpoint<T>,
+	) -> Result<(), T> {
+		let checkpoint_bytes = serde_json::to_vec(&checkpoint)
+			.map_err(|e| StorageError::SerializationError(e.to_string()))?;
 
-			// Delete metadata
-			let metadata_key = derive_metadata_key(&tapestry_id);
-			txn.delete_cf(TAPESTRY_METADATA_CF, metadata_key.as_bytes())?;
+		self.transaction(|txn| {
+			let checkpoint_key = derive_metadata_key::<T, _>(&tapestry_id);
+			txn.put_cf(STREAM_CHECKPOINT_CF, checkpoint_key.as_bytes(), &checkpoint_bytes)
+		})
+	}
 
-			Ok(())
+	async fn get_stream_checkpoint<TID: TapestryId>(
+		&self,
+		tapestry_id: TID,
+	) -> Result<Option<StreamCheckpoint<T>>, T> {
+		let checkpoint_key = derive_metadata_key::<T, _>(&tapestry_id);
+		self.transaction(|txn| {
+			txn.get_cf(STREAM_CHECKPOINT_CF, checkpoint_key.as_bytes())?
+				.map(|bytes| {
+					serde_json::from_slice(&bytes)
+						.map_err(|e| StorageError::DeserializationError(e.to_string()))
+				})
+				.transpose()
+				.map_err(|e| e.into())
 		})
 	}
 
+	async fn delete_stream_checkpoint<TID: TapestryId>(&self, tapestry_id: TID) -> Result<(), T> {
+		let checkpoint_key = derive_metadata_key::<T, _>(&tapestry_id);
+		self.transaction(|txn| txn.delete_cf(STREAM_CHECKPOINT_CF, checkpoint_key.as_bytes()))
+	}
+
+	async fn delete_tapestry<TID: TapestryId>(&self, tapestry_id: TID) -> Result<(), T> {
+		self.transaction(|txn| txn.delete_tapestry(&storage_key::<T, _>(&tapestry_id)))
+	}
+
 	async fn delete_tapestry_fragment<TID: TapestryId>(
 		&self,
 		tapestry_id: TID,
 		instance: Option<u64>,
 	) -> Result<(), T> {
 		self.transaction(|txn| {
-			let instance_index_key = derive_instance_index_key(&tapestry_id);
+			let instance_index_key = derive_instance_index_key::<T, _>(&tapestry_id);
 			let current_index: u64 = txn
 				.get_cf(INSTANCE_INDEX_CF, instance_index_key.as_bytes())?
 				.map(|bytes| {
@@ -308,7 +394,7 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 			let instance_to_delete = instance.unwrap_or(current_index);
 
 			if instance_to_delete > 0 && instance_to_delete <= current_index {
-				let fragment_key = derive_instance_key(&tapestry_id, instance_to_delete);
+				let fragment_key = derive_instance_key::<T, _>(&tapestry_id, instance_to_delete);
 				txn.delete_cf(TAPESTRY_FRAGMENT_CF, fragment_key.as_bytes())?;
 
 				// Update instance count if we deleted the last fragment
@@ -329,6 +415,50 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 			Ok(())
 		})
 	}
+
+	async fn purge_expired(&self) -> Result<u64, T> {
+		let now = Utc::now();
+		let namespace = T::STORAGE_NAMESPACE.map(|namespace| format!("{}:", namespace));
+
+		self.transaction(|txn| {
+			let mut purged = 0;
+			for key in txn.keys_cf(INSTANCE_INDEX_CF)? {
+				let key = String::from_utf8(key)
+					.map_err(|e| StorageError::DeserializationError(e.to_string()))?;
+				if namespace.as_ref().is_some_and(|namespace| !key.starts_with(namespace)) {
+					continue;
+				}
+
+				let current_index = txn
+					.get_cf(INSTANCE_INDEX_CF, key.as_bytes())?
+					.map(|bytes| {
+						String::from_utf8(bytes)
+							.map_err(|e| StorageError::DeserializationError(e.to_string()))
+					})
+					.transpose()?
+					.map(|s| {
+						s.parse::<u64>()
+							.map_err(|e| StorageError::DeserializationError(e.to_string()))
+					})
+					.transpose()?
+					.unwrap_or(0);
+				let Some(bytes) = txn.get_cf(
+					TAPESTRY_FRAGMENT_CF,
+					format!("{}:{}", key, current_index).as_bytes(),
+				)?
+				else {
+					continue;
+				};
+				let fragment: TapestryFragment<T> = decode_fragment(&bytes)?;
+
+				if fragment.expires_at.is_some_and(|expires_at| expires_at <= now) {
+					txn.delete_tapestry(&key)?;
+					purged += 1;
+				}
+			}
+			Ok(purged)
+		})
+	}
 }
 
 /// Derives the key for storing the instance count of a tapestry.
@@ -336,8 +466,8 @@ impl<T: Config + Serialize + DeserializeOwned + Send + Sync> TapestryChestHandle
 /// # Arguments
 ///
 /// * `tapestry_id` - The identifier of the tapestry.
-fn derive_instance_index_key<TID: TapestryId>(tapestry_id: &TID) -> String {
-	tapestry_id.base_key()
+fn derive_instance_index_key<T: Config, TID: TapestryId>(tapestry_id: &TID) -> String {
+	storage_key::<T, _>(tapestry_id)
 }
 
 /// Derives the key for storing a specific instance of a tapestry fragment.
@@ -346,8 +476,8 @@ fn derive_instance_index_key<TID: TapestryId>(tapestry_id: &TID) -> String {
 ///
 /// * `tapestry_id` - The identifier of the tapestry.
 /// * `instance` - The instance number.
-fn derive_instance_key<TID: TapestryId>(tapestry_id: &TID, instance: u64) -> String {
-	format!("{}:{}", tapestry_id.base_key(), instance)
+fn derive_instance_key<T: Config, TID: TapestryId>(tapestry_id: &TID, instance: u64) -> String {
+	format!("{}:{}", storage_key::<T, _>(tapestry_id), instance)
 }
 
 /// Derives the key for storing the metadata of a tapestry.
@@ -355,8 +485,8 @@ fn derive_instance_key<TID: TapestryId>(tapestry_id: &TID, instance: u64) -> Str
 /// # Arguments
 ///
 /// * `tapestry_id` - The identifier of the tapestry.
-fn derive_metadata_key<TID: TapestryId>(tapestry_id: &TID) -> String {
-	tapestry_id.base_key()
+fn derive_metadata_key<T: Config, TID: TapestryId>(tapestry_id: &TID) -> String {
+	storage_key::<T, _>(tapestry_id)
 }
 
 #[cfg(test)]
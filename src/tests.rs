@@ -2,12 +2,34 @@ use async_openai::types::Role;
 
 use crate::{
 	loom::Loom,
-	mock::{MockConfig, MockLlm, MockLlmRequest, MockTapestryId},
+	mock::{MockChest, MockConfig, MockLlm, MockLlmRequest, MockTapestryId},
 	types::VecPromptMsgsDeque,
 };
 
 use super::*;
 
+/// Declares a [`Config`] prompting the [`MockLlm`] and storing in the [`MockChest`], implementing
+/// the given associated constants and functions.
+macro_rules! test_config {
+	($name:ident { $($body:tt)* }) => {
+		#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+		struct $name;
+		impl Config for $name {
+			$($body)*
+
+			type PromptModel = MockLlm;
+			type SummaryModel = MockLlm;
+			type Chest = MockChest;
+
+			fn convert_prompt_tokens_to_summary_model_tokens(
+				tokens: PromptModelTokens<Self>,
+			) -> SummaryModelTokens<Self> {
+				tokens
+			}
+		}
+	};
+}
+
 #[cfg(test)]
 mod vec_prompt_msgs_deque {
 	use super::*;
@@ -151,3 +173,4800 @@ mod vec_prompt_msgs_deque {
 		assert_eq!(vec[1], request2);
 	}
 }
+
+#[cfg(test)]
+mod response_length {
+	use super::*;
+	use crate::{mock::last_prompt, types::SYSTEM_ROLE};
+
+	#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+	struct SentencesConfig<const N: u64>;
+	impl<const N: u64> Config for SentencesConfig<N> {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const RESPONSE_SENTENCES: Option<u64> = Some(N);
+
+		type PromptModel = MockLlm;
+		type SummaryModel = MockLlm;
+		type Chest = MockChest;
+
+		fn convert_prompt_tokens_to_summary_model_tokens(
+			tokens: PromptModelTokens<Self>,
+		) -> SummaryModelTokens<Self> {
+			tokens
+		}
+	}
+
+	async fn weave_with<C: Config<PromptModel = MockLlm, SummaryModel = MockLlm>>() {
+		Loom::<C>::new()
+			.weave(
+				LlmConfig::<C, MockLlm> { model: MockLlm, params: () },
+				LlmConfig::<C, MockLlm> { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![ContextMessage::<C>::new(
+					SYSTEM_ROLE.into(),
+					"Hello".to_string(),
+					None,
+					"time".to_string(),
+				)],
+			)
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn sentence_target_sets_token_budget_and_instruction() {
+		weave_with::<SentencesConfig<3>>().await;
+		let three = last_prompt().unwrap();
+		weave_with::<SentencesConfig<6>>().await;
+		let six = last_prompt().unwrap();
+
+		// 3 sentences * 20 words / 75% token to word ratio
+		assert_eq!(three.max_tokens, 80);
+		assert_eq!(six.max_tokens, 2 * three.max_tokens);
+		assert_eq!(three.msgs.last().unwrap().msg, "Respond in about 3 sentences.");
+		assert_eq!(six.msgs.last().unwrap().msg, "Respond in about 6 sentences.");
+	}
+
+	test_config!(SilentSentencesConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const RESPONSE_SENTENCES: Option<u64> = Some(3);
+		const RESPONSE_LENGTH_INSTRUCTION: Option<&'static str> = None;
+	});
+
+	#[tokio::test]
+	async fn disabled_instruction_keeps_token_budget() {
+		weave_with::<SilentSentencesConfig>().await;
+		let prompt = last_prompt().unwrap();
+
+		assert_eq!(prompt.max_tokens, 80);
+		assert!(!prompt.msgs.iter().any(|m| m.msg.starts_with("Respond in about")));
+	}
+}
+
+#[cfg(test)]
+mod message_token_cost {
+	use super::*;
+	use crate::{loom::SUMMARY_PREFIX, types::USER_ROLE};
+
+	test_config!(RefreshConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MEMORY_REFRESH_INTERVAL: Option<usize> = Some(3);
+		const MEMORY_REFRESH_WORDS: usize = 3;
+	});
+
+	async fn save_history(loom: &Loom<RefreshConfig>, len: usize) {
+		let mut fragment = TapestryFragment::<RefreshConfig>::default();
+		let summary = format!("{}The party crossed the desert at night", SUMMARY_PREFIX);
+		for content in std::iter::once(summary).chain((1..len).map(|i| format!("Turn {}", i))) {
+			fragment
+				.push_message(Loom::<RefreshConfig>::build_context_message(
+					USER_ROLE.into(),
+					content,
+					None,
+				))
+				.unwrap();
+		}
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn matches_manual_count_with_role_overhead() {
+		let loom = Loom::<MockConfig>::new();
+		let content = "The dragon wakes beneath the mountain.";
+
+		let cost = loom.message_token_cost(MockTapestryId, content).await.unwrap();
+
+		assert_eq!(cost, MockLlm::count_tokens(&format!("user: {}", content)).unwrap());
+		assert!(cost > MockLlm::count_tokens(content).unwrap());
+	}
+
+	#[tokio::test]
+	async fn includes_the_memory_refresher_it_triggers() {
+		let loom = Loom::<RefreshConfig>::new();
+		let content = "I light a torch.";
+		let msg_cost = MockLlm::count_tokens(&format!("user: {}", content)).unwrap();
+
+		save_history(&loom, 3).await;
+		assert_eq!(loom.message_token_cost(MockTapestryId, content).await.unwrap(), msg_cost);
+
+		save_history(&loom, 4).await;
+		let refresher_cost =
+			MockLlm::count_tokens("system: Story so far: The party crossed").unwrap();
+		assert_eq!(
+			loom.message_token_cost(MockTapestryId, content).await.unwrap(),
+			msg_cost + refresher_cost
+		);
+	}
+}
+
+#[cfg(test)]
+mod language_consistency {
+	use super::*;
+	use crate::{mock::last_prompt, types::USER_ROLE};
+
+	test_config!(LanguageConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+
+		fn detect_language(content: &str) -> Option<String> {
+			if content.starts_with("Bonjour") {
+				Some("French".to_string())
+			} else if content.starts_with("Hello") {
+				Some("English".to_string())
+			} else {
+				None
+			}
+		}
+	});
+
+	#[tokio::test]
+	async fn first_detected_language_is_enforced() {
+		let loom = Loom::<LanguageConfig>::new();
+
+		for content in ["Bonjour, je suis un chevalier.", "Hello! Merci, on y va."] {
+			loom.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<LanguageConfig>::build_context_message(
+					USER_ROLE.into(),
+					content.to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+		}
+
+		let prompt = last_prompt().unwrap();
+		assert_eq!(prompt.msgs.last().unwrap().msg, "Maintain French throughout your response.");
+		assert!(!prompt.msgs.iter().any(|m| m.msg.contains("English")));
+	}
+}
+
+#[cfg(test)]
+mod summarization {
+	use super::*;
+	use crate::{
+		mock::{push_response, PROMPTS},
+		types::{SYSTEM_ROLE, USER_ROLE},
+	};
+
+	/// Saves a fragment large enough for the next [`Loom::weave`] to generate a summary.
+	async fn save_full_fragment(loom: &Loom<MockConfig>) {
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"word ".repeat(450),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+	}
+
+	async fn weave(loom: &Loom<MockConfig>, content: &str) -> (u64, bool) {
+		let (_, instance, was_summary_generated) = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					content.to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+		(instance, was_summary_generated)
+	}
+
+	#[tokio::test]
+	async fn triggering_message_is_carried_into_new_fragment() {
+		let loom = Loom::<MockConfig>::new();
+		save_full_fragment(&loom).await;
+
+		let (instance, was_summary_generated) = weave(&loom, "What lies beyond the gate?").await;
+		assert!(was_summary_generated);
+		assert_eq!(instance, 2);
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let messages = &fragment.context_messages;
+		assert_eq!(messages.len(), 3);
+		assert_eq!(messages[0].role, SYSTEM_ROLE.into());
+		assert!(messages[0].content.contains("Summary"));
+		assert_eq!(messages[1].role, USER_ROLE.into());
+		assert_eq!(messages[1].content, "What lies beyond the gate?");
+	}
+
+	#[tokio::test]
+	async fn failure_after_summary_leaves_a_single_summary_instance() {
+		let loom = Loom::<MockConfig>::new();
+		save_full_fragment(&loom).await;
+
+		// The summary is generated but the prompt fails before the turn is saved
+		push_response("A dragon sleeps beneath the mountain.", false);
+		push_response("", false);
+		let result = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I enter the cave.".to_string(),
+					None,
+				)],
+			)
+			.await;
+		assert!(matches!(result, Err(LoomError::EmptyResponse)));
+		assert_eq!(
+			TapestryChestHandler::<MockConfig>::get_instance_index(&loom.chest, MockTapestryId)
+				.await
+				.unwrap(),
+			Some(1)
+		);
+
+		push_response("A dragon sleeps beneath the mountain.", false);
+		push_response("You enter the cave.", false);
+		assert_eq!(weave(&loom, "I enter the cave.").await, (2, true));
+
+		assert_eq!(
+			TapestryChestHandler::<MockConfig>::get_instance_index(&loom.chest, MockTapestryId)
+				.await
+				.unwrap(),
+			Some(2)
+		);
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let contents: Vec<_> =
+			fragment.context_messages.iter().map(|m| m.content.as_str()).collect();
+		assert_eq!(contents.iter().filter(|c| c.contains("Summary")).count(), 1);
+		assert_eq!(contents[1..], ["I enter the cave.", "You enter the cave."]);
+	}
+
+	#[tokio::test]
+	async fn summaries_carry_prior_summaries_forward() {
+		let loom = Loom::<MockConfig>::new();
+		save_full_fragment(&loom).await;
+
+		push_response("A dragon sleeps beneath the mountain.", false);
+		push_response("You enter the cave.", false);
+		assert_eq!(weave(&loom, "I enter the cave.").await, (2, true));
+
+		push_response("The dragon woke and the hero fled to the gate.", false);
+		push_response("The gate is shut.", false);
+		assert_eq!(weave(&loom, &"word ".repeat(450)).await, (3, true));
+
+		// The second summary is generated from the second fragment, which starts with the first
+		// summary
+		let summary_prompt = PROMPTS.with(|prompts| prompts.borrow()[2].clone());
+		assert!(summary_prompt.msgs[0].msg.contains("A dragon sleeps beneath the mountain."));
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert!(fragment.context_messages[0].content.contains("The dragon woke"));
+	}
+
+	#[tokio::test]
+	async fn oversized_fragment_is_summarized_in_chunks() {
+		let loom = Loom::<MockConfig>::new();
+		let max_context_length = <MockLlm as Llm<MockConfig>>::max_context_length(&MockLlm);
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		for _ in 0..10 {
+			fragment
+				.push_message(Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"word ".repeat(100),
+					None,
+				))
+				.unwrap();
+		}
+		assert!(fragment.context_tokens > max_context_length);
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		push_response("The hero walked.", false);
+		push_response("The hero ran.", false);
+		push_response("The hero walked, then ran.", false);
+		assert_eq!(weave(&loom, "Where am I?").await, (2, true));
+
+		// Two chunks fitting the summary input budget, then the summary of their summaries
+		let prompts = PROMPTS.with(|prompts| prompts.borrow().clone());
+		assert_eq!(prompts.len(), 4);
+		for prompt in &prompts[..2] {
+			let tokens: u16 =
+				prompt.msgs.iter().map(|m| MockLlm::count_tokens(&m.msg).unwrap()).sum();
+			assert!(tokens <= max_context_length - prompt.max_tokens);
+		}
+		assert_eq!(
+			prompts[2].msgs.iter().map(|m| m.msg.as_str()).collect::<Vec<_>>(),
+			vec!["The hero walked.", "The hero ran."]
+		);
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert!(fragment.context_messages[0].content.contains("The hero walked, then ran."));
+	}
+}
+
+#[cfg(test)]
+mod split_message {
+	use super::*;
+	use crate::types::{ASSISTANT_ROLE, USER_ROLE};
+
+	test_config!(SplitConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MAX_MESSAGE_TOKENS: Option<u64> = Some(50);
+	});
+
+	#[tokio::test]
+	async fn oversized_message_is_split_into_multiple_messages() {
+		let loom = Loom::<SplitConfig>::new();
+		let backstory = "word ".repeat(120);
+
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<SplitConfig>::build_context_message(
+				USER_ROLE.into(),
+				backstory.clone(),
+				Some("player".to_string()),
+			)],
+		)
+		.await
+		.unwrap();
+
+		let fragment: TapestryFragment<SplitConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let (user_msgs, assistant_msgs): (Vec<_>, Vec<_>) =
+			fragment.context_messages.iter().partition(|m| m.role == USER_ROLE.into());
+
+		assert_eq!(user_msgs.len(), 3);
+		assert_eq!(assistant_msgs.len(), 1);
+		assert_eq!(assistant_msgs[0].role, ASSISTANT_ROLE.into());
+		assert!(user_msgs.iter().all(|m| MockLlm::count_tokens(&m.content).unwrap() <= 50 &&
+			m.account_id.as_deref() == Some("player")));
+		assert_eq!(user_msgs.iter().map(|m| m.content.as_str()).collect::<String>(), backstory);
+	}
+}
+
+#[cfg(test)]
+mod continuations {
+	use super::*;
+	use crate::{
+		mock::{push_response, PROMPTS},
+		types::{ASSISTANT_ROLE, USER_ROLE},
+	};
+
+	test_config!(ContinueConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MAX_CONTINUATIONS: u8 = 2;
+	});
+
+	#[tokio::test]
+	async fn truncated_response_is_continued_and_stitched() {
+		let loom = Loom::<ContinueConfig>::new();
+		push_response("The gate creaks open and", true);
+		push_response(" a cold wind", true);
+		push_response(" sweeps through the hall.", false);
+
+		let (response, _, _) = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<ContinueConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I open the gate.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+
+		let stitched = "The gate creaks open and a cold wind sweeps through the hall.";
+		assert_eq!(response.content, stitched);
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 3);
+
+		let fragment: TapestryFragment<ContinueConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages.len(), 2);
+		assert_eq!(fragment.context_messages[1].role, ASSISTANT_ROLE.into());
+		assert_eq!(fragment.context_messages[1].content, stitched);
+	}
+}
+
+#[cfg(test)]
+mod consistency {
+	use super::*;
+	use crate::{mock::push_response, types::ConsistencyWarning};
+
+	#[tokio::test]
+	async fn warnings_are_parsed() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				WrapperRole::Role(Role::Assistant),
+				"The knight falls, slain by the dragon.".to_string(),
+				None,
+			))
+			.unwrap();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				WrapperRole::Role(Role::Assistant),
+				"The knight laughs and orders another ale.".to_string(),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		push_response(
+			r#"[{"description": "The knight speaks after dying", "message_index": 1}]"#,
+			false,
+		);
+		let warnings = loom
+			.check_consistency(LlmConfig { model: MockLlm, params: () }, MockTapestryId)
+			.await
+			.unwrap();
+
+		assert_eq!(
+			warnings,
+			vec![ConsistencyWarning {
+				description: "The knight speaks after dying".to_string(),
+				message_index: Some(1),
+			}]
+		);
+	}
+
+	#[tokio::test]
+	async fn malformed_response_fails_to_parse() {
+		let loom = Loom::<MockConfig>::new();
+		loom.chest
+			.save_tapestry_fragment(
+				&MockTapestryId,
+				TapestryFragment::<MockConfig>::default(),
+				true,
+			)
+			.await
+			.unwrap();
+
+		push_response("Everything looks fine!", false);
+		let result = loom
+			.check_consistency(LlmConfig { model: MockLlm, params: () }, MockTapestryId)
+			.await;
+
+		assert!(matches!(result, Err(LoomError::ParseFailed(_))));
+	}
+}
+
+#[cfg(test)]
+mod redaction {
+	use super::*;
+	use crate::{mock::last_prompt, types::USER_ROLE};
+
+	test_config!(RedactionConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const REDACTION_PATTERNS: &'static [(&'static str, &'static str)] =
+			&[(r"[\w.+-]+@[\w-]+\.[\w.]+", "[EMAIL]")];
+	});
+
+	#[tokio::test]
+	async fn email_is_redacted_in_request_but_stored_intact() {
+		let loom = Loom::<RedactionConfig>::new();
+		let content = "Send the map to jane.doe@example.com please.";
+
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<RedactionConfig>::build_context_message(
+				USER_ROLE.into(),
+				content.to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+
+		let prompt = last_prompt().unwrap();
+		assert!(prompt.msgs.iter().any(|m| m.msg == "Send the map to [EMAIL] please."));
+		assert!(!prompt.msgs.iter().any(|m| m.msg.contains("jane.doe@example.com")));
+
+		let fragment: TapestryFragment<RedactionConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages[0].content, content);
+	}
+
+	test_config!(InvalidRedactionConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const REDACTION_PATTERNS: &'static [(&'static str, &'static str)] = &[("[unclosed", "")];
+	});
+
+	#[test]
+	#[should_panic(expected = "Invalid redaction pattern [unclosed")]
+	fn invalid_pattern_is_rejected_by_new() {
+		Loom::<InvalidRedactionConfig>::new();
+	}
+}
+
+#[cfg(test)]
+mod capabilities {
+	use super::*;
+	use crate::{
+		mock::{PROMPTS, REQUIRED_CAPABILITIES},
+		types::{ModelCapabilities, USER_ROLE},
+	};
+
+	async fn weave_requiring(required: ModelCapabilities) -> Result<(), MockConfig> {
+		REQUIRED_CAPABILITIES.with(|r| *r.borrow_mut() = required);
+		Loom::<MockConfig>::new()
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"Describe this map.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.map(|_| ())
+	}
+
+	#[tokio::test]
+	async fn unsupported_capability_fails_before_prompting() {
+		let result =
+			weave_requiring(ModelCapabilities { vision: true, ..Default::default() }).await;
+
+		assert!(matches!(
+			result,
+			Err(LoomError::UnsupportedCapability { model: "TestLlm", capability: "vision" })
+		));
+		assert!(PROMPTS.with(|prompts| prompts.borrow().is_empty()));
+	}
+
+	#[tokio::test]
+	async fn unsupported_logprobs_fail_before_prompting() {
+		let result =
+			weave_requiring(ModelCapabilities { logprobs: true, ..Default::default() }).await;
+
+		assert!(matches!(
+			result,
+			Err(LoomError::UnsupportedCapability { model: "TestLlm", capability: "logprobs" })
+		));
+	}
+
+	#[tokio::test]
+	async fn supported_capability_is_prompted() {
+		weave_requiring(ModelCapabilities { json_mode: true, ..Default::default() })
+			.await
+			.unwrap();
+	}
+}
+
+#[cfg(test)]
+mod stop_at_speakers {
+	use super::*;
+	use crate::{mock::STOP_SEQUENCES, types::USER_ROLE};
+
+	test_config!(SpeakersConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const STOP_AT_SPEAKERS: bool = true;
+	});
+
+	fn speaker_msg(account_id: &str) -> ContextMessage<SpeakersConfig> {
+		Loom::<SpeakersConfig>::build_context_message(
+			USER_ROLE.into(),
+			"I draw my sword.".to_string(),
+			Some(account_id.to_string()),
+		)
+	}
+
+	#[tokio::test]
+	async fn most_recent_speakers_are_stop_sequences() {
+		let loom = Loom::<SpeakersConfig>::new();
+		let mut fragment = TapestryFragment::<SpeakersConfig>::default();
+		for account_id in ["alice", "bob", "carol", "dave", "erin"] {
+			fragment.push_message(speaker_msg(account_id)).unwrap();
+		}
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![speaker_msg("alice")],
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(
+			STOP_SEQUENCES.with(|stop| stop.borrow().clone()),
+			vec!["alice:", "erin:", "dave:", "carol:"]
+		);
+	}
+}
+
+#[cfg(test)]
+mod cooldown {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::types::USER_ROLE;
+
+	test_config!(CooldownConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MIN_PROMPT_INTERVAL: Option<Duration> = Some(Duration::from_secs(2));
+	});
+
+	async fn weave(loom: &Loom<CooldownConfig>) -> Result<(), CooldownConfig> {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<CooldownConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I knock on the door.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.map(|_| ())
+	}
+
+	#[tokio::test]
+	async fn rapid_prompt_hits_cooldown() {
+		let loom = Loom::<CooldownConfig>::new();
+
+		weave(&loom).await.unwrap();
+		let result = weave(&loom).await;
+
+		assert!(matches!(
+			result,
+			Err(LoomError::Cooldown { retry_after }) if retry_after <= Duration::from_secs(2)
+		));
+	}
+}
+
+#[cfg(test)]
+mod export_finetune_jsonl {
+	use super::*;
+	use crate::{
+		mock::push_response,
+		types::{ASSISTANT_ROLE, SYSTEM_ROLE, USER_ROLE},
+	};
+
+	#[tokio::test]
+	async fn renders_one_line_per_fragment() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"word ".repeat(450),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		push_response("The hero crossed the desert.", false);
+		push_response("The gate opens.", false);
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I open the gate.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+
+		let jsonl = loom
+			.export_finetune_jsonl(MockTapestryId, "instructions".to_string())
+			.await
+			.unwrap();
+		let lines = jsonl
+			.lines()
+			.map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+			.collect::<Vec<_>>();
+
+		assert_eq!(lines.len(), 2);
+		let messages = lines[1]["messages"].as_array().unwrap();
+		let roles = messages.iter().map(|m| m["role"].as_str().unwrap()).collect::<Vec<_>>();
+		assert_eq!(roles, vec![SYSTEM_ROLE, SYSTEM_ROLE, USER_ROLE, ASSISTANT_ROLE]);
+		assert_eq!(messages[0]["content"], "instructions");
+		assert!(messages[1]["content"]
+			.as_str()
+			.unwrap()
+			.contains("The hero crossed the desert."));
+		assert_eq!(messages[2]["content"], "I open the gate.");
+		assert_eq!(messages[3]["content"], "The gate opens.");
+	}
+}
+
+#[cfg(test)]
+mod count_tokens {
+	use std::sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn large_count_does_not_block_concurrent_tasks() {
+		let content = "word ".repeat(12_000);
+		assert!(content.len() >= MockConfig::BLOCKING_TOKEN_COUNT_THRESHOLD);
+
+		// The test runtime is single threaded, so the probe can only make progress while the
+		// count is awaited off the executor thread
+		let ticks = Arc::new(AtomicUsize::new(0));
+		let probe = tokio::spawn({
+			let ticks = Arc::clone(&ticks);
+			async move {
+				loop {
+					ticks.fetch_add(1, Ordering::Relaxed);
+					tokio::task::yield_now().await;
+				}
+			}
+		});
+
+		let tokens = Loom::<MockConfig>::count_tokens(content.clone()).await;
+		probe.abort();
+
+		assert!(ticks.load(Ordering::Relaxed) > 0);
+		assert_eq!(tokens.unwrap(), MockLlm::count_tokens(&content).unwrap());
+	}
+}
+
+#[cfg(test)]
+mod fits_model {
+	use super::*;
+	use crate::types::USER_ROLE;
+
+	async fn save_fragment(loom: &Loom<MockConfig>, words: usize) {
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"word ".repeat(words),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn small_fragment_fits() {
+		let loom = Loom::<MockConfig>::new();
+		save_fragment(&loom, 100).await;
+
+		assert!(loom.fits_model(MockTapestryId, &MockLlm).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn context_usage_is_a_fraction_of_each_model() {
+		let loom = Loom::<MockConfig>::new();
+		save_fragment(&loom, 250).await;
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+
+		let usage = loom.context_usage_per_model(MockTapestryId, &[MockLlm]).await.unwrap();
+
+		assert_eq!(usage, vec![(MockLlm, fragment.context_tokens as f64 / 1000.0)]);
+	}
+
+	#[tokio::test]
+	async fn large_fragment_does_not_fit() {
+		let loom = Loom::<MockConfig>::new();
+		save_fragment(&loom, 450).await;
+
+		assert!(!loom.fits_model(MockTapestryId, &MockLlm).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn story_token_count_reports_context_tokens() {
+		let loom = Loom::<MockConfig>::new();
+		assert_eq!(loom.story_token_count(MockTapestryId, &MockLlm).await.unwrap(), (0, 1000));
+
+		save_fragment(&loom, 250).await;
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+
+		assert_eq!(
+			loom.story_token_count(MockTapestryId, &MockLlm).await.unwrap(),
+			(fragment.context_tokens, 1000)
+		);
+	}
+}
+
+#[cfg(test)]
+mod importance {
+	use super::*;
+	use crate::{mock::PROMPTS, types::USER_ROLE};
+
+	test_config!(ImportanceConfig {
+		const TOKEN_THRESHOLD_PERCENTILE: BoundedU8<0, 100> = BoundedU8::new(70).unwrap();
+		const MINIMUM_RESPONSE_LENGTH: u64 = 300;
+
+		fn score_importance(msg: &ContextMessage<Self>) -> Option<u8> {
+			Some(if msg.content.contains("dragon") { 10 } else { 1 })
+		}
+	});
+
+	async fn weave(loom: &Loom<ImportanceConfig>, content: &str) {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<ImportanceConfig>::build_context_message(
+				USER_ROLE.into(),
+				content.to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+	}
+
+	#[tokio::test]
+	async fn scores_are_stored_and_weight_the_summary() {
+		let loom = Loom::<ImportanceConfig>::new();
+
+		weave(&loom, "The dragon steals the crown.").await;
+		let fragment: TapestryFragment<ImportanceConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages[0].importance, Some(10));
+		assert_eq!(fragment.context_messages[1].importance, Some(1));
+
+		weave(&loom, &"word ".repeat(450)).await;
+		let summary_prompt = PROMPTS.with(|prompts| prompts.borrow()[1].clone());
+		assert_eq!(summary_prompt.msgs[0].msg, "[Importance 10/10] The dragon steals the crown.");
+		assert_eq!(summary_prompt.msgs[1].msg, "[Importance 1/10] TestLlmResponse");
+		assert!(summary_prompt.msgs.last().unwrap().msg.contains("high importance"));
+	}
+}
+
+#[cfg(test)]
+mod stay_in_character {
+	use super::*;
+	use crate::{
+		mock::{last_prompt, push_response},
+		types::USER_ROLE,
+	};
+
+	test_config!(InCharacterConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const STAY_IN_CHARACTER: bool = true;
+	});
+
+	#[tokio::test]
+	async fn ai_disclaimer_is_stripped() {
+		let loom = Loom::<InCharacterConfig>::new();
+		push_response(
+			"The dragon roars. As an AI language model, I cannot feel fear. The cave trembles!",
+			false,
+		);
+
+		let (response, _, _) = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<InCharacterConfig>::build_context_message(
+					USER_ROLE.into(),
+					"Are you scared?".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(response.content, "The dragon roars. The cave trembles!");
+		assert!(last_prompt()
+			.unwrap()
+			.msgs
+			.last()
+			.unwrap()
+			.msg
+			.starts_with("Stay in character."));
+
+		let fragment: TapestryFragment<InCharacterConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages[1].content, "The dragon roars. The cave trembles!");
+	}
+}
+
+#[cfg(test)]
+mod storage_namespace {
+	use super::*;
+	use crate::types::USER_ROLE;
+
+	#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+	struct NamespacedConfig<const N: u8>;
+	impl<const N: u8> Config for NamespacedConfig<N> {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const STORAGE_NAMESPACE: Option<&'static str> = Some(if N == 0 { "a" } else { "b" });
+
+		type PromptModel = MockLlm;
+		type SummaryModel = MockLlm;
+		type Chest = MockChest;
+
+		fn convert_prompt_tokens_to_summary_model_tokens(
+			tokens: PromptModelTokens<Self>,
+		) -> SummaryModelTokens<Self> {
+			tokens
+		}
+	}
+
+	#[tokio::test]
+	async fn namespaces_are_isolated_in_shared_storage() {
+		let loom_a = Loom::<NamespacedConfig<0>>::new();
+		let mut loom_b = Loom::<NamespacedConfig<1>>::new();
+		loom_b.chest = loom_a.chest.clone();
+
+		loom_a
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<NamespacedConfig<0>>::build_context_message(
+					USER_ROLE.into(),
+					"Hello".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+
+		let fragment_a: Option<TapestryFragment<NamespacedConfig<0>>> =
+			loom_a.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		let fragment_b: Option<TapestryFragment<NamespacedConfig<1>>> =
+			loom_b.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment_a.is_some());
+		assert!(fragment_b.is_none());
+	}
+}
+
+#[cfg(test)]
+mod events {
+	use super::*;
+	use crate::types::{LoomEvent, ASSISTANT_ROLE, USER_ROLE};
+
+	#[tokio::test]
+	async fn appended_messages_are_published() {
+		let loom = Loom::<MockConfig>::new();
+		let mut events = loom.subscribe(&MockTapestryId);
+
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I light a torch.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+
+		let LoomEvent::MessageAppended(msg) = events.recv().await.unwrap() else {
+			panic!("Expected a message appended event");
+		};
+		assert_eq!(msg.role, USER_ROLE.into());
+		assert_eq!(msg.content, "I light a torch.");
+
+		let LoomEvent::MessageAppended(msg) = events.recv().await.unwrap() else {
+			panic!("Expected a message appended event");
+		};
+		assert_eq!(msg.role, ASSISTANT_ROLE.into());
+	}
+}
+
+#[cfg(test)]
+mod last_message_by_role {
+	use super::*;
+	use crate::types::{ASSISTANT_ROLE, SYSTEM_ROLE, USER_ROLE};
+
+	#[test]
+	fn returns_most_recent_message_of_role() {
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		for (role, content) in [
+			(USER_ROLE, "I open the door."),
+			(ASSISTANT_ROLE, "The door creaks."),
+			(USER_ROLE, "I step inside."),
+			(ASSISTANT_ROLE, "It is dark."),
+		] {
+			fragment
+				.push_message(Loom::<MockConfig>::build_context_message(
+					role.into(),
+					content.to_string(),
+					None,
+				))
+				.unwrap();
+		}
+
+		let (index, msg) = fragment.last_message_by_role(&USER_ROLE.into()).unwrap();
+		assert_eq!((index, msg.content.as_str()), (2, "I step inside."));
+		let (index, msg) = fragment.last_message_by_role(&ASSISTANT_ROLE.into()).unwrap();
+		assert_eq!((index, msg.content.as_str()), (3, "It is dark."));
+		assert!(fragment.last_message_by_role(&SYSTEM_ROLE.into()).is_none());
+	}
+}
+
+#[cfg(test)]
+mod rate_limits {
+	use std::{cell::RefCell, time::Duration};
+
+	use super::*;
+	use crate::{mock::RATE_LIMITS, types::USER_ROLE};
+
+	test_config!(BackoffConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MAX_RATE_LIMIT_RETRIES: u8 = 2;
+	});
+
+	#[tokio::test]
+	async fn queued_prompts_complete_in_order_after_backoff() {
+		let loom = Loom::<BackoffConfig>::new();
+		let completed = RefCell::new(vec![]);
+		RATE_LIMITS
+			.with(|rate_limits| rate_limits.borrow_mut().push_back(Duration::from_millis(20)));
+
+		let weave = |i: usize| {
+			let (loom, completed) = (&loom, &completed);
+			async move {
+				loom.weave(
+					LlmConfig { model: MockLlm, params: () },
+					LlmConfig { model: MockLlm, params: () },
+					MockTapestryId,
+					"instructions".to_string(),
+					vec![Loom::<BackoffConfig>::build_context_message(
+						USER_ROLE.into(),
+						format!("Prompt {}", i),
+						None,
+					)],
+				)
+				.await
+				.unwrap();
+				completed.borrow_mut().push(i);
+			}
+		};
+
+		let start = std::time::Instant::now();
+		futures::join!(weave(0), weave(1), weave(2));
+
+		assert!(start.elapsed() >= Duration::from_millis(20));
+		assert_eq!(completed.into_inner(), vec![0, 1, 2]);
+	}
+
+	#[tokio::test]
+	async fn rate_limit_fails_without_retries() {
+		RATE_LIMITS.with(|rate_limits| rate_limits.borrow_mut().push_back(Duration::ZERO));
+
+		let result = Loom::<MockConfig>::new()
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"Hello".to_string(),
+					None,
+				)],
+			)
+			.await;
+
+		assert!(matches!(result, Err(LoomError::Llm(_))));
+	}
+}
+
+#[cfg(test)]
+mod instructions_file {
+	use std::path::PathBuf;
+
+	use super::*;
+	use crate::{mock::last_prompt, types::USER_ROLE};
+
+	test_config!(FileConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+
+		fn instructions_path() -> Option<PathBuf> {
+			Some(std::env::temp_dir().join("llm-weaver-instructions-test.txt"))
+		}
+	});
+
+	async fn weave(loom: &Loom<FileConfig>) -> String {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"ignored".to_string(),
+			vec![Loom::<FileConfig>::build_context_message(
+				USER_ROLE.into(),
+				"Hello".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+		last_prompt().unwrap().msgs[0].msg.clone()
+	}
+
+	#[tokio::test]
+	async fn updated_file_is_used_on_next_prompt() {
+		let loom = Loom::<FileConfig>::new();
+		let path = FileConfig::instructions_path().unwrap();
+
+		std::fs::write(&path, "You are a narrator.").unwrap();
+		assert_eq!(weave(&loom).await, "You are a narrator.");
+
+		std::fs::write(&path, "You are a grim narrator.").unwrap();
+		assert_eq!(weave(&loom).await, "You are a grim narrator.");
+
+		std::fs::remove_file(&path).unwrap();
+	}
+}
+
+#[cfg(test)]
+mod response_validation {
+	use super::*;
+	use crate::{
+		mock::{push_response, MockLlmResponse, PROMPTS},
+		types::USER_ROLE,
+	};
+
+	test_config!(SchemaConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MAX_VALIDATION_RETRIES: u8 = 1;
+
+		fn validate_response(content: &str) -> std::result::Result<(), String> {
+			let value: serde_json::Value =
+				serde_json::from_str(content).map_err(|e| e.to_string())?;
+			match value.get("hp") {
+				Some(hp) if hp.is_u64() => Ok(()),
+				_ => Err("missing integer field \"hp\"".to_string()),
+			}
+		}
+	});
+
+	async fn weave(loom: &Loom<SchemaConfig>) -> Result<MockLlmResponse, SchemaConfig> {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<SchemaConfig>::build_context_message(
+				USER_ROLE.into(),
+				"Describe the goblin as JSON.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.map(|(response, _, _)| response)
+	}
+
+	#[tokio::test]
+	async fn invalid_response_is_retried() {
+		let loom = Loom::<SchemaConfig>::new();
+		push_response(r#"{"hp": "many"}"#, false);
+		push_response(r#"{"hp": 7}"#, false);
+
+		let response = weave(&loom).await.unwrap();
+
+		assert_eq!(response.content, r#"{"hp": 7}"#);
+		let retry = PROMPTS.with(|prompts| prompts.borrow()[1].clone());
+		assert!(retry.msgs.last().unwrap().msg.contains("missing integer field"));
+
+		let fragment: TapestryFragment<SchemaConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages.len(), 2);
+		assert_eq!(fragment.context_messages[1].content, r#"{"hp": 7}"#);
+	}
+
+	#[tokio::test]
+	async fn never_conforming_response_fails() {
+		let loom = Loom::<SchemaConfig>::new();
+		push_response("not json", false);
+		push_response(r#"{"hp": "many"}"#, false);
+
+		assert!(matches!(weave(&loom).await, Err(LoomError::SchemaValidation(_))));
+	}
+}
+
+#[cfg(test)]
+mod player_sentiment {
+	use super::*;
+	use crate::types::{ASSISTANT_ROLE, USER_ROLE};
+
+	test_config!(SentimentConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+	});
+
+	#[derive(Debug)]
+	struct LexiconHooks;
+
+	#[async_trait]
+	impl Hooks<SentimentConfig> for LexiconHooks {
+		async fn sentiment(&self, content: &str) -> Result<Option<f64>, SentimentConfig> {
+			if content.contains("love") {
+				Ok(Some(1.0))
+			} else if content.contains("hate") {
+				Ok(Some(-1.0))
+			} else {
+				Ok(Some(0.0))
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn scores_are_averaged_per_player() {
+		let loom = Loom::<SentimentConfig>::new().with_hooks(LexiconHooks);
+		for (instance, msgs) in [
+			vec![("alice", "I love this tavern."), ("bob", "I hate rain.")],
+			vec![("alice", "I open the chest."), ("bob", "I hate goblins.")],
+		]
+		.into_iter()
+		.enumerate()
+		{
+			let mut fragment = TapestryFragment::<SentimentConfig>::default();
+			for (account_id, content) in msgs {
+				fragment
+					.push_message(Loom::<SentimentConfig>::build_context_message(
+						USER_ROLE.into(),
+						content.to_string(),
+						Some(account_id.to_string()),
+					))
+					.unwrap();
+			}
+			fragment
+				.push_message(Loom::<SentimentConfig>::build_context_message(
+					ASSISTANT_ROLE.into(),
+					"The narrator loves it.".to_string(),
+					None,
+				))
+				.unwrap();
+			loom.chest
+				.save_tapestry_fragment(&MockTapestryId, fragment, instance > 0)
+				.await
+				.unwrap();
+		}
+
+		let sentiment = loom.player_sentiment(MockTapestryId).await.unwrap();
+
+		assert_eq!(sentiment.len(), 2);
+		assert_eq!(sentiment["alice"], 0.5);
+		assert_eq!(sentiment["bob"], -1.0);
+	}
+}
+
+#[cfg(test)]
+mod memory_refreshers {
+	use super::*;
+	use crate::{
+		mock::last_prompt,
+		types::{SYSTEM_ROLE, USER_ROLE},
+	};
+
+	test_config!(RefreshConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MEMORY_REFRESH_INTERVAL: Option<usize> = Some(3);
+		const MEMORY_REFRESH_WORDS: usize = 3;
+	});
+
+	#[tokio::test]
+	async fn refreshers_are_injected_every_interval() {
+		let loom = Loom::<RefreshConfig>::new();
+		let mut fragment = TapestryFragment::<RefreshConfig>::default();
+		fragment
+			.push_message(Loom::<RefreshConfig>::build_context_message(
+				SYSTEM_ROLE.into(),
+				"\n\"\"\"\nSummary\n The dragon sleeps beneath the mountain.".to_string(),
+				None,
+			))
+			.unwrap();
+		for i in 1..=7 {
+			fragment
+				.push_message(Loom::<RefreshConfig>::build_context_message(
+					USER_ROLE.into(),
+					format!("m{}", i),
+					None,
+				))
+				.unwrap();
+		}
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<RefreshConfig>::build_context_message(
+				USER_ROLE.into(),
+				"m8".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+
+		let prompt = last_prompt().unwrap();
+		let msgs = prompt.msgs.iter().skip(2).map(|m| m.msg.as_str()).collect::<Vec<_>>();
+		let refresher = "Story so far: The dragon sleeps";
+		assert_eq!(
+			msgs,
+			vec!["m1", "m2", "m3", refresher, "m4", "m5", "m6", refresher, "m7", "m8"]
+		);
+
+		let fragment: TapestryFragment<RefreshConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert!(!fragment.context_messages.iter().any(|m| m.content == refresher));
+	}
+}
+
+#[cfg(test)]
+mod storage_conformance {
+	use super::*;
+
+	#[tokio::test]
+	async fn in_memory_backend_conforms() {
+		storage::conformance::run::<MockConfig, _>(&storage::memory::InMemoryBackend::default())
+			.await;
+	}
+}
+
+#[cfg(test)]
+mod inject_narration {
+	use super::*;
+	use crate::{
+		mock::{last_prompt, PROMPTS},
+		types::{ASSISTANT_ROLE, USER_ROLE},
+	};
+
+	#[tokio::test]
+	async fn narration_is_saved_and_sent_on_next_prompt() {
+		let loom = Loom::<MockConfig>::new();
+		let narration = "The tavern door bursts open.";
+
+		let instance = loom.inject_narration(MockTapestryId, narration.to_string()).await.unwrap();
+		assert_eq!(instance, 1);
+		assert!(PROMPTS.with(|prompts| prompts.borrow().is_empty()));
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages.len(), 1);
+		assert_eq!(fragment.context_messages[0].role, ASSISTANT_ROLE.into());
+		assert_eq!(fragment.context_tokens, MockLlm::count_tokens(narration).unwrap());
+
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I hide under the table.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(last_prompt().unwrap().msgs[1].msg, narration);
+	}
+}
+
+#[cfg(test)]
+mod genre_presets {
+	use super::*;
+	use crate::{
+		mock::{last_prompt, SAMPLING},
+		types::{GenrePreset, SamplingParameters, USER_ROLE},
+	};
+
+	#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+	struct GenreConfig<const N: u8>;
+	impl<const N: u8> Config for GenreConfig<N> {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const GENRE_PRESET: Option<GenrePreset> = Some(match N {
+			0 => GenrePreset::Mystery,
+			1 => GenrePreset::Comedy,
+			2 => GenrePreset::Horror,
+			_ => GenrePreset::Epic,
+		});
+
+		type PromptModel = MockLlm;
+		type SummaryModel = MockLlm;
+		type Chest = MockChest;
+
+		fn convert_prompt_tokens_to_summary_model_tokens(
+			tokens: PromptModelTokens<Self>,
+		) -> SummaryModelTokens<Self> {
+			tokens
+		}
+	}
+
+	async fn weave_with<const N: u8>() -> (SamplingParameters, String) {
+		Loom::<GenreConfig<N>>::new()
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<GenreConfig<N>>::build_context_message(
+					USER_ROLE.into(),
+					"Begin.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+		(
+			SAMPLING.with(|s| s.borrow().unwrap()),
+			last_prompt().unwrap().msgs.last().unwrap().msg.clone(),
+		)
+	}
+
+	#[tokio::test]
+	async fn presets_apply_documented_sampling_and_directive() {
+		let presets = [
+			(weave_with::<0>().await, GenrePreset::Mystery, (0.7, 0.3, 0.2)),
+			(weave_with::<1>().await, GenrePreset::Comedy, (1.0, 0.5, 0.6)),
+			(weave_with::<2>().await, GenrePreset::Horror, (0.8, 0.4, 0.3)),
+			(weave_with::<3>().await, GenrePreset::Epic, (0.9, 0.2, 0.5)),
+		];
+
+		for ((sampling, directive), genre, (temperature, frequency_penalty, presence_penalty)) in
+			presets
+		{
+			assert_eq!(
+				sampling,
+				SamplingParameters {
+					temperature,
+					top_p: None,
+					frequency_penalty,
+					presence_penalty,
+					seed: None
+				}
+			);
+			assert_eq!(directive, genre.directive());
+		}
+	}
+}
+
+#[cfg(test)]
+mod daily_limit {
+	use super::*;
+	use crate::types::USER_ROLE;
+
+	test_config!(DailyLimitConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MAX_DAILY_COMPLETION_TOKENS: Option<u64> = Some(1);
+	});
+
+	async fn weave(loom: &Loom<DailyLimitConfig>) -> Result<(), DailyLimitConfig> {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<DailyLimitConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I knock on the door.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.map(|_| ())
+	}
+
+	#[tokio::test]
+	async fn exhausted_cap_refuses_until_next_day() {
+		let loom = Loom::<DailyLimitConfig>::new();
+
+		weave(&loom).await.unwrap();
+		let result = weave(&loom).await;
+		assert!(matches!(
+			result,
+			Err(LoomError::DailyLimitReached { retry_after })
+				if retry_after <= std::time::Duration::from_secs(24 * 60 * 60)
+		));
+
+		// Simulate a day rollover by backdating the recorded usage
+		let mut tapestry_fragment: TapestryFragment<DailyLimitConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		tapestry_fragment.daily_usage.as_mut().unwrap().date = "2000-01-01".to_string();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, tapestry_fragment, false)
+			.await
+			.unwrap();
+
+		weave(&loom).await.unwrap();
+		let tapestry_fragment: TapestryFragment<DailyLimitConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(
+			tapestry_fragment.daily_usage.unwrap().date,
+			chrono::Utc::now().date_naive().to_string()
+		);
+	}
+}
+
+#[cfg(test)]
+mod character_sheets {
+	use std::collections::BTreeMap;
+
+	use super::*;
+	use crate::{
+		mock::{push_response, JSON_MODE},
+		types::CharacterSheet,
+	};
+
+	#[tokio::test]
+	async fn sheets_are_parsed() {
+		let loom = Loom::<MockConfig>::new();
+		loom.inject_narration(
+			MockTapestryId,
+			"Aria, a brave elf, draws her bow while her sister Lyra looks on.".to_string(),
+		)
+		.await
+		.unwrap();
+
+		push_response(
+			r#"[
+				{
+					"name": "Aria",
+					"traits": ["brave", "elf"],
+					"inventory": ["bow"],
+					"relationships": {"Lyra": "sister"}
+				},
+				{"name": "Lyra"}
+			]"#,
+			false,
+		);
+		let sheets = loom
+			.extract_character_sheets(LlmConfig { model: MockLlm, params: () }, MockTapestryId)
+			.await
+			.unwrap();
+
+		assert!(JSON_MODE.with(|json_mode| *json_mode.borrow()));
+		assert_eq!(
+			sheets,
+			vec![
+				CharacterSheet {
+					name: "Aria".to_string(),
+					traits: vec!["brave".to_string(), "elf".to_string()],
+					inventory: vec!["bow".to_string()],
+					relationships: BTreeMap::from([("Lyra".to_string(), "sister".to_string())]),
+				},
+				CharacterSheet {
+					name: "Lyra".to_string(),
+					traits: vec![],
+					inventory: vec![],
+					relationships: BTreeMap::new(),
+				},
+			]
+		);
+	}
+}
+
+#[cfg(test)]
+mod random_events {
+	use super::*;
+	use crate::{mock::last_prompt, types::USER_ROLE};
+
+	const EVENTS: &[&str] = &["a sudden storm", "a stranger arrives"];
+
+	#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+	struct RandomEventsConfig<const ALWAYS: bool>;
+	impl<const ALWAYS: bool> Config for RandomEventsConfig<ALWAYS> {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const RANDOM_EVENT_PROBABILITY: f64 = if ALWAYS { 1.0 } else { 0.0 };
+		const RANDOM_EVENTS: &'static [&'static str] = EVENTS;
+		const RANDOM_EVENT_SEED: Option<u64> = Some(42);
+
+		type PromptModel = MockLlm;
+		type SummaryModel = MockLlm;
+		type Chest = MockChest;
+
+		fn convert_prompt_tokens_to_summary_model_tokens(
+			tokens: PromptModelTokens<Self>,
+		) -> SummaryModelTokens<Self> {
+			tokens
+		}
+	}
+
+	async fn event_directives<const ALWAYS: bool>() -> Vec<String> {
+		Loom::<RandomEventsConfig<ALWAYS>>::new()
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<RandomEventsConfig<ALWAYS>>::build_context_message(
+					USER_ROLE.into(),
+					"We set up camp.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+		last_prompt()
+			.unwrap()
+			.msgs
+			.into_iter()
+			.map(|m| m.msg)
+			.filter(|msg| msg.starts_with("Introduce the following event"))
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn certain_event_is_injected() {
+		let directives = event_directives::<true>().await;
+
+		assert_eq!(directives.len(), 1);
+		assert!(EVENTS.iter().any(|event| directives[0].contains(event)));
+	}
+
+	#[tokio::test]
+	async fn impossible_event_is_not_injected() {
+		assert!(event_directives::<false>().await.is_empty());
+	}
+}
+
+#[cfg(test)]
+mod story_length {
+	use super::*;
+	use crate::{mock::last_prompt, types::USER_ROLE};
+
+	const CONCLUDING_DIRECTIVE: &str =
+		"The story is nearing its end. Begin wrapping up its plot lines towards a conclusion.";
+
+	test_config!(StoryLengthConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MAX_STORY_TURNS: Option<u64> = Some(2);
+		const STORY_CONCLUSION_THRESHOLD: f64 = 0.5;
+	});
+
+	async fn weave(loom: &Loom<StoryLengthConfig>) -> Result<(), StoryLengthConfig> {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<StoryLengthConfig>::build_context_message(
+				USER_ROLE.into(),
+				"We press on through the caves.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.map(|_| ())
+	}
+
+	fn prompted_to_conclude() -> bool {
+		last_prompt().unwrap().msgs.iter().any(|m| m.msg == CONCLUDING_DIRECTIVE)
+	}
+
+	#[tokio::test]
+	async fn soft_limit_instructs_to_conclude() {
+		let loom = Loom::<StoryLengthConfig>::new();
+
+		weave(&loom).await.unwrap();
+		assert!(!prompted_to_conclude());
+
+		weave(&loom).await.unwrap();
+		assert!(prompted_to_conclude());
+	}
+
+	#[tokio::test]
+	async fn hard_limit_refuses_prompts() {
+		let loom = Loom::<StoryLengthConfig>::new();
+
+		weave(&loom).await.unwrap();
+		weave(&loom).await.unwrap();
+
+		assert!(matches!(weave(&loom).await, Err(LoomError::StoryComplete)));
+	}
+}
+
+#[cfg(test)]
+mod metrics_snapshot {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{
+		mock::{PROMPT_DELAY, RATE_LIMITS},
+		types::USER_ROLE,
+	};
+
+	async fn weave(loom: &Loom<MockConfig>) -> Result<(), MockConfig> {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"Hello there.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.map(|_| ())
+	}
+
+	#[tokio::test]
+	async fn snapshot_exposes_counters() {
+		let loom = Loom::<MockConfig>::new();
+		weave(&loom).await.unwrap();
+		weave(&loom).await.unwrap();
+		RATE_LIMITS.with(|rate_limits| rate_limits.borrow_mut().push_back(Duration::ZERO));
+		weave(&loom).await.unwrap_err();
+
+		let snapshot = loom.metrics_snapshot();
+
+		for expected in [
+			"# TYPE loom_prompts_total counter",
+			"loom_prompts_total 2",
+			"# TYPE loom_prompt_tokens_total counter",
+			"# TYPE loom_completion_tokens_total counter",
+			"# TYPE loom_errors_total counter",
+			"loom_errors_total{kind=\"llm\"} 1",
+			"# TYPE loom_active_weaves gauge",
+			"loom_active_weaves 0",
+		] {
+			assert!(snapshot.lines().any(|line| line == expected), "missing {expected:?}");
+		}
+	}
+
+	#[tokio::test]
+	async fn active_weaves_are_those_in_progress() {
+		let loom = Loom::<MockConfig>::new();
+		PROMPT_DELAY.with(|delay| *delay.borrow_mut() = Some(Duration::from_millis(10)));
+
+		let (_, snapshot) = futures::join!(weave(&loom), async {
+			tokio::time::sleep(Duration::from_millis(5)).await;
+			loom.metrics_snapshot()
+		});
+
+		assert!(snapshot.lines().any(|line| line == "loom_active_weaves 1"));
+		assert!(loom.metrics_snapshot().lines().any(|line| line == "loom_active_weaves 0"));
+	}
+}
+
+#[cfg(test)]
+mod audit {
+	use super::*;
+	use crate::types::{ModerationHit, ASSISTANT_ROLE, USER_ROLE};
+
+	test_config!(ModerationConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+	});
+
+	#[derive(Debug)]
+	struct ModerationHooks;
+
+	#[async_trait]
+	impl Hooks<ModerationConfig> for ModerationHooks {
+		async fn moderate(&self, content: &str) -> Result<Vec<String>, ModerationConfig> {
+			if content.contains("gore") {
+				Ok(vec!["violence/graphic".to_string()])
+			} else {
+				Ok(vec![])
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn flagged_messages_are_located() {
+		let loom = Loom::<ModerationConfig>::new().with_hooks(ModerationHooks);
+		for (instance, msgs) in [
+			vec![(USER_ROLE, "I enter the crypt."), (ASSISTANT_ROLE, "It is quiet.")],
+			vec![(USER_ROLE, "I attack."), (ASSISTANT_ROLE, "The fight ends in gore.")],
+		]
+		.into_iter()
+		.enumerate()
+		{
+			let mut fragment = TapestryFragment::<ModerationConfig>::default();
+			for (role, content) in msgs {
+				fragment
+					.push_message(Loom::<ModerationConfig>::build_context_message(
+						role.into(),
+						content.to_string(),
+						None,
+					))
+					.unwrap();
+			}
+			loom.chest
+				.save_tapestry_fragment(&MockTapestryId, fragment, instance > 0)
+				.await
+				.unwrap();
+		}
+
+		let hits = loom.audit(MockTapestryId).await.unwrap();
+
+		assert_eq!(
+			hits,
+			vec![ModerationHit {
+				instance: 2,
+				index: 1,
+				categories: vec!["violence/graphic".to_string()],
+			}]
+		);
+	}
+}
+
+#[cfg(test)]
+mod idempotency {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{
+		mock::{push_response, PROMPT_DELAY},
+		types::{ASSISTANT_ROLE, USER_ROLE},
+	};
+
+	async fn weave(
+		loom: &Loom<MockConfig>,
+		idempotency_key: Option<String>,
+	) -> Result<String, MockConfig> {
+		let (response, _, _) = loom
+			.weave_idempotent(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I open the gate.".to_string(),
+					Some("alice".to_string()),
+				)],
+				idempotency_key,
+			)
+			.await?;
+		Ok(response.content)
+	}
+
+	async fn assistant_messages(loom: &Loom<MockConfig>) -> usize {
+		let tapestry_fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		tapestry_fragment
+			.context_messages
+			.iter()
+			.filter(|m| m.role == ASSISTANT_ROLE.into())
+			.count()
+	}
+
+	#[tokio::test]
+	async fn retried_weave_returns_previous_response() {
+		let loom = Loom::<MockConfig>::new();
+
+		push_response("The gate creaks open.", false);
+		let first = weave(&loom, Some("request-1".to_string())).await.unwrap();
+		push_response("The gate is locked.", false);
+		let retry = weave(&loom, Some("request-1".to_string())).await.unwrap();
+
+		assert_eq!(first, "The gate creaks open.");
+		assert_eq!(retry, first);
+		assert_eq!(assistant_messages(&loom).await, 1);
+	}
+
+	#[tokio::test]
+	async fn key_is_derived_from_messages() {
+		let loom = Loom::<MockConfig>::new();
+
+		let first = weave(&loom, None).await.unwrap();
+		let retry = weave(&loom, None).await.unwrap();
+
+		assert_eq!(retry, first);
+		assert_eq!(assistant_messages(&loom).await, 1);
+	}
+	#[tokio::test]
+	async fn concurrent_retry_waits_for_the_first_weave() {
+		let loom = Loom::<MockConfig>::new();
+		PROMPT_DELAY.with(|delay| *delay.borrow_mut() = Some(Duration::from_millis(10)));
+
+		push_response("The gate creaks open.", false);
+		push_response("The gate is locked.", false);
+		let (first, retry) = tokio::join!(
+			weave(&loom, Some("request-1".to_string())),
+			weave(&loom, Some("request-1".to_string())),
+		);
+
+		assert_eq!(first.unwrap(), "The gate creaks open.");
+		assert_eq!(retry.unwrap(), "The gate creaks open.");
+		assert_eq!(assistant_messages(&loom).await, 1);
+	}
+}
+
+#[cfg(test)]
+mod oversized_messages {
+	use super::*;
+	use crate::{mock::last_prompt, types::USER_ROLE};
+
+	#[tokio::test]
+	async fn message_exceeding_budget_is_refused_without_summarizing() {
+		let loom = Loom::<MockConfig>::new();
+
+		let result = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"word ".repeat(700),
+					None,
+				)],
+			)
+			.await;
+
+		assert!(matches!(result, Err(LoomError::MessagesExceedContext)));
+		assert!(last_prompt().is_none());
+		assert!(loom
+			.chest
+			.get_tapestry_fragment::<_>(MockTapestryId, None)
+			.await
+			.map(|f: Option<TapestryFragment<MockConfig>>| f.is_none())
+			.unwrap());
+	}
+}
+
+#[cfg(test)]
+mod context_tokens {
+	use super::*;
+	use crate::types::USER_ROLE;
+
+	async fn assert_matches_recount(loom: &Loom<MockConfig>) {
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let recount: u16 = fragment
+			.context_messages
+			.iter()
+			.map(|m| MockLlm::count_tokens(&m.content).unwrap())
+			.sum();
+		assert_eq!(fragment.context_tokens, recount);
+	}
+
+	#[tokio::test]
+	async fn stored_count_matches_recount() {
+		let loom = Loom::<MockConfig>::new();
+		for content in ["I light a torch.", "I walk down the stairs.", &"word ".repeat(450)] {
+			loom.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					content.to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+			assert_matches_recount(&loom).await;
+		}
+
+		loom.inject_narration(MockTapestryId, "A cold wind blows.".to_string())
+			.await
+			.unwrap();
+		assert_matches_recount(&loom).await;
+	}
+}
+
+#[cfg(test)]
+mod sampling_parameters {
+	use super::*;
+	use crate::{
+		mock::{last_prompt, SAMPLING, STOP_SEQUENCES},
+		types::{GenrePreset, SamplingParameters, USER_ROLE},
+	};
+
+	const SAMPLING_PARAMETERS: SamplingParameters = SamplingParameters {
+		temperature: 0.2,
+		top_p: Some(0.9),
+		frequency_penalty: 0.0,
+		presence_penalty: 0.1,
+		seed: None,
+	};
+
+	test_config!(SamplingConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const GENRE_PRESET: Option<GenrePreset> = Some(GenrePreset::Comedy);
+		const SAMPLING_PARAMETERS: Option<SamplingParameters> = Some(SAMPLING_PARAMETERS);
+		const MAX_RESPONSE_TOKENS: Option<u64> = Some(42);
+	});
+
+	#[tokio::test]
+	async fn configured_parameters_override_genre_and_cap_response() {
+		Loom::<SamplingConfig>::new()
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<SamplingConfig>::build_context_message(
+					USER_ROLE.into(),
+					"Tell me a joke.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(SAMPLING.with(|s| *s.borrow()), Some(SAMPLING_PARAMETERS));
+		assert_eq!(last_prompt().unwrap().max_tokens, 42);
+	}
+
+	test_config!(SeededConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const SAMPLING_PARAMETERS: Option<SamplingParameters> =
+			Some(SamplingParameters { seed: Some(7), ..SAMPLING_PARAMETERS });
+	});
+
+	#[tokio::test]
+	async fn seed_requires_capability() {
+		let result = Loom::<SeededConfig>::new()
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<SeededConfig>::build_context_message(
+					USER_ROLE.into(),
+					"Tell me a joke.".to_string(),
+					Some("alice".to_string()),
+				)],
+			)
+			.await;
+
+		assert!(matches!(result, Err(LoomError::UnsupportedCapability { capability: "seed", .. })));
+	}
+
+	test_config!(StopConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const STOP_SEQUENCES: &'static [&'static str] = &["\nPlayer:"];
+		const STOP_AT_SPEAKERS: bool = true;
+	});
+
+	#[tokio::test]
+	async fn configured_stop_sequences_precede_speakers() {
+		Loom::<StopConfig>::new()
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<StopConfig>::build_context_message(
+					USER_ROLE.into(),
+					"Tell me a joke.".to_string(),
+					Some("alice".to_string()),
+				)],
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(STOP_SEQUENCES.with(|s| s.borrow().clone()), vec!["\nPlayer:", "alice:"]);
+	}
+}
+
+#[cfg(test)]
+mod streaming {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{
+		mock::{push_response, MockPromptError, STREAM_INTERRUPTION, STREAM_STALL},
+		types::{ASSISTANT_ROLE, USER_ROLE},
+	};
+
+	test_config!(IdleTimeoutConfig {
+		const TOKEN_THRESHOLD_PERCENTILE: BoundedU8<0, 100> = BoundedU8::new(70).unwrap();
+		const MINIMUM_RESPONSE_LENGTH: u64 = 300;
+		const STREAM_IDLE_TIMEOUT: Option<Duration> = Some(Duration::from_millis(10));
+	});
+
+	test_config!(CheckpointConfig {
+		const TOKEN_THRESHOLD_PERCENTILE: BoundedU8<0, 100> = BoundedU8::new(70).unwrap();
+		const MINIMUM_RESPONSE_LENGTH: u64 = 300;
+		const CHECKPOINT_STREAMS: bool = true;
+	});
+
+	test_config!(ValidatedCheckpointConfig {
+		const TOKEN_THRESHOLD_PERCENTILE: BoundedU8<0, 100> = BoundedU8::new(70).unwrap();
+		const MINIMUM_RESPONSE_LENGTH: u64 = 300;
+		const CHECKPOINT_STREAMS: bool = true;
+
+		fn validate_response(content: &str) -> std::result::Result<(), String> {
+			match content.contains("curse") {
+				true => Err("the narrator may not curse".to_string()),
+				false => Ok(()),
+			}
+		}
+	});
+
+	async fn weave_streaming<C: Config<PromptModel = MockLlm, SummaryModel = MockLlm>>(
+		loom: &Loom<C>,
+	) -> (Result<String, C>, Vec<String>) {
+		let mut deltas = Vec::new();
+		let result = loom
+			.weave_streaming(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<C>::build_context_message(
+					USER_ROLE.into(),
+					"I listen closely.".to_string(),
+					None,
+				)],
+				&mut |delta| deltas.push(delta.to_string()),
+			)
+			.await
+			.map(|(response, _, _)| response.content);
+		(result, deltas)
+	}
+
+	async fn last_message<C: Config<Chest = MockChest> + Serialize + DeserializeOwned>(
+		loom: &Loom<C>,
+	) -> ContextMessage<C> {
+		let fragment: TapestryFragment<C> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		fragment.context_messages.last().unwrap().clone()
+	}
+
+	#[tokio::test]
+	async fn deltas_are_forwarded_and_response_persisted() {
+		let loom = Loom::<MockConfig>::new();
+
+		push_response("The wind whispers your name.", false);
+		let (result, deltas) = weave_streaming(&loom).await;
+
+		assert_eq!(deltas, vec!["The ", "wind ", "whispers ", "your ", "name."]);
+		assert_eq!(result.unwrap(), "The wind whispers your name.");
+		assert_eq!(last_message(&loom).await.content, "The wind whispers your name.");
+	}
+
+	#[tokio::test]
+	async fn interrupted_stream_persists_partial_response() {
+		let loom = Loom::<MockConfig>::new();
+
+		push_response("The wind whispers your name.", false);
+		STREAM_INTERRUPTION.with(|interruption| *interruption.borrow_mut() = Some(2));
+		let (result, deltas) = weave_streaming(&loom).await;
+
+		assert_eq!(deltas, vec!["The ", "wind "]);
+		assert!(matches!(result, Err(LoomError::Llm(MockPromptError::StreamInterrupted))));
+		let last_message = last_message(&loom).await;
+		assert_eq!(last_message.role, ASSISTANT_ROLE.into());
+		assert_eq!(last_message.content, "The wind ");
+	}
+
+	#[tokio::test]
+	async fn stalled_stream_returns_partial_response() {
+		let loom = Loom::<IdleTimeoutConfig>::new();
+
+		push_response("The wind whispers your name.", false);
+		STREAM_STALL.with(|stall| *stall.borrow_mut() = Some(2));
+		let (result, deltas) = weave_streaming(&loom).await;
+
+		assert_eq!(deltas, vec!["The ", "wind "]);
+		assert!(
+			matches!(result, Err(LoomError::PartialTimeout(partial)) if partial == "The wind ")
+		);
+		let last_message = last_message(&loom).await;
+		assert_eq!(last_message.role, ASSISTANT_ROLE.into());
+		assert_eq!(last_message.content, "The wind ");
+	}
+
+	/// Drops a weave stalling after two deltas of the `response`, as a crash would.
+	async fn crash_mid_stream(loom: &Loom<CheckpointConfig>, response: &str) {
+		push_response(response, false);
+		STREAM_STALL.with(|stall| *stall.borrow_mut() = Some(2));
+		let weave = tokio::time::timeout(Duration::from_millis(20), weave_streaming(loom));
+		assert!(weave.await.is_err());
+	}
+
+	async fn checkpoint(
+		loom: &Loom<CheckpointConfig>,
+	) -> Option<StreamCheckpoint<CheckpointConfig>> {
+		loom.chest.get_stream_checkpoint(MockTapestryId).await.unwrap()
+	}
+
+	async fn contents(loom: &Loom<CheckpointConfig>) -> Vec<String> {
+		let fragment: TapestryFragment<CheckpointConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		fragment.context_messages.into_iter().map(|m| m.content).collect()
+	}
+
+	#[tokio::test]
+	async fn interrupted_stream_is_recovered() {
+		let loom = Loom::<CheckpointConfig>::new();
+
+		crash_mid_stream(&loom, "The wind whispers your name.").await;
+		let saved = checkpoint(&loom).await.unwrap();
+		assert_eq!(saved.response, "The wind ");
+		assert_eq!(saved.msgs[0].content, "I listen closely.");
+		let fragment: Option<TapestryFragment<CheckpointConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+
+		assert_eq!(loom.recover_stream(MockTapestryId).await.unwrap().unwrap(), "The wind ");
+		assert_eq!(contents(&loom).await, vec!["I listen closely.", "The wind "]);
+		assert!(checkpoint(&loom).await.is_none());
+		assert!(loom.recover_stream(MockTapestryId).await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn next_weave_recovers_interrupted_stream() {
+		let loom = Loom::<CheckpointConfig>::new();
+
+		crash_mid_stream(&loom, "The wind whispers your name.").await;
+		push_response("It fades away.", false);
+		let (result, _) = weave_streaming(&loom).await;
+
+		assert_eq!(result.unwrap(), "It fades away.");
+		assert_eq!(
+			contents(&loom).await,
+			vec!["I listen closely.", "The wind ", "I listen closely.", "It fades away."]
+		);
+		assert!(checkpoint(&loom).await.is_none());
+	}
+
+	#[tokio::test]
+	async fn rejected_stream_is_not_recovered() {
+		let loom = Loom::<ValidatedCheckpointConfig>::new();
+
+		push_response("A curse falls upon you.", false);
+		let (result, _) = weave_streaming(&loom).await;
+		assert!(matches!(result, Err(LoomError::SchemaValidation(_))));
+		let saved: Option<StreamCheckpoint<ValidatedCheckpointConfig>> =
+			loom.chest.get_stream_checkpoint(MockTapestryId).await.unwrap();
+		assert!(saved.is_none());
+
+		push_response("It fades away.", false);
+		let (result, _) = weave_streaming(&loom).await;
+		assert_eq!(result.unwrap(), "It fades away.");
+		let fragment: TapestryFragment<ValidatedCheckpointConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let contents = fragment.context_messages.into_iter().map(|m| m.content).collect::<Vec<_>>();
+		assert_eq!(contents, vec!["I listen closely.", "It fades away."]);
+	}
+
+	#[tokio::test]
+	async fn stream_stalled_before_any_delta_is_not_persisted() {
+		let loom = Loom::<IdleTimeoutConfig>::new();
+
+		push_response("The wind whispers your name.", false);
+		STREAM_STALL.with(|stall| *stall.borrow_mut() = Some(0));
+		let (result, deltas) = weave_streaming(&loom).await;
+
+		assert!(deltas.is_empty());
+		assert!(matches!(
+			result,
+			Err(LoomError::Timeout { timeout }) if timeout == Duration::from_millis(10)
+		));
+		let fragment: Option<TapestryFragment<IdleTimeoutConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+	}
+}
+
+#[cfg(test)]
+mod transient_retries {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{
+		mock::{MockPromptError, PROMPTS, SERVER_ERRORS},
+		types::USER_ROLE,
+	};
+
+	test_config!(RetryConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MAX_TRANSIENT_RETRIES: u8 = 2;
+		const RETRY_BASE_DELAY: Duration = Duration::from_millis(1);
+	});
+
+	async fn weave(server_errors: usize) -> Result<(), RetryConfig> {
+		SERVER_ERRORS.with(|errors| *errors.borrow_mut() = server_errors);
+		Loom::<RetryConfig>::new()
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<RetryConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I cross the bridge.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.map(|_| ())
+	}
+
+	#[tokio::test]
+	async fn transient_failures_are_retried() {
+		weave(2).await.unwrap();
+
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 3);
+	}
+
+	#[tokio::test]
+	async fn retries_are_bounded() {
+		let result = weave(3).await;
+
+		assert!(matches!(result, Err(LoomError::Llm(MockPromptError::ServerError))));
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 3);
+	}
+}
+
+#[cfg(test)]
+mod roles {
+	use async_openai::types::Role;
+
+	use super::*;
+	use crate::types::{InvalidRole, TOOL_ROLE};
+
+	#[test]
+	fn unknown_role_is_an_error() {
+		assert_eq!(
+			WrapperRole::try_from("narrator".to_string()),
+			Err(InvalidRole("narrator".to_string()))
+		);
+		assert_eq!(WrapperRole::try_from(TOOL_ROLE.to_string()), Ok(WrapperRole::Role(Role::Tool)));
+	}
+
+	#[test]
+	fn stored_unknown_role_fails_to_deserialize() {
+		let fragment = serde_json::json!({
+			"context_tokens": 1,
+			"context_messages": [{
+				"role": { "Role": "narrator" },
+				"content": "Once upon a time",
+				"account_id": null,
+				"timestamp": "2024-01-01T00:00:00Z",
+				"_phantom": null,
+			}],
+		});
+
+		assert!(serde_json::from_value::<TapestryFragment<MockConfig>>(fragment).is_err());
+	}
+
+	#[test]
+	fn serialized_as_lowercase_string() {
+		let role = WrapperRole::Role(Role::Assistant);
+
+		assert_eq!(serde_json::to_value(&role).unwrap(), serde_json::json!("assistant"));
+		assert_eq!(
+			serde_json::from_value::<WrapperRole>(serde_json::json!("assistant")).unwrap(),
+			role
+		);
+		assert_eq!(
+			serde_json::from_value::<WrapperRole>(serde_json::json!({ "Role": "assistant" }))
+				.unwrap(),
+			role
+		);
+		assert!(serde_json::from_value::<WrapperRole>(serde_json::json!("narrator")).is_err());
+	}
+
+	#[tokio::test]
+	async fn stored_tool_role_is_exported() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				WrapperRole::Role(Role::Tool),
+				"42".to_string(),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		let jsonl = loom
+			.export_finetune_jsonl(MockTapestryId, "instructions".to_string())
+			.await
+			.unwrap();
+
+		assert!(jsonl.contains(r#"{"content":"42","role":"tool"}"#));
+	}
+}
+
+#[cfg(test)]
+mod usage {
+	use super::*;
+	use crate::{
+		mock::push_response,
+		types::{Usage, USER_ROLE},
+	};
+
+	async fn weave_with_usage(loom: &Loom<MockConfig>) -> (bool, Usage) {
+		let (_, _, was_summary_generated, usage) = loom
+			.weave_with_usage(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I buy a round of ale.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+		(was_summary_generated, usage)
+	}
+
+	#[tokio::test]
+	async fn usage_and_cost_are_returned() {
+		let loom = Loom::<MockConfig>::new();
+
+		push_response("The tavern cheers.", false);
+		let (_, usage) = weave_with_usage(&loom).await;
+
+		assert!(usage.prompt_tokens > 0);
+		assert_eq!(
+			usage.completion_tokens,
+			MockLlm::count_tokens("The tavern cheers.").unwrap() as u64
+		);
+		assert_eq!(usage.cost, (usage.prompt_tokens + usage.completion_tokens) as f64);
+	}
+
+	#[tokio::test]
+	async fn usage_includes_summaries() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"word ".repeat(450),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		push_response("Many words were said.", false);
+		push_response("The tavern cheers.", false);
+		let (was_summary_generated, usage) = weave_with_usage(&loom).await;
+
+		assert!(was_summary_generated);
+		assert!(usage.prompt_tokens > 450);
+		assert_eq!(
+			usage.completion_tokens,
+			(MockLlm::count_tokens("Many words were said.").unwrap() +
+				MockLlm::count_tokens("The tavern cheers.").unwrap()) as u64
+		);
+	}
+}
+
+#[cfg(test)]
+mod players {
+	use super::*;
+	use crate::types::USER_ROLE;
+
+	async fn players(loom: &Loom<MockConfig>) -> Vec<String> {
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		fragment.players
+	}
+
+	async fn weave(loom: &Loom<MockConfig>, account_id: &str, content: String) -> bool {
+		let (_, _, was_summary_generated) = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					content,
+					Some(account_id.to_string()),
+				)],
+			)
+			.await
+			.unwrap();
+		was_summary_generated
+	}
+
+	#[tokio::test]
+	async fn roster_updates_are_idempotent() {
+		let loom = Loom::<MockConfig>::new();
+
+		loom.add_player(MockTapestryId, "alice".to_string()).await.unwrap();
+		loom.add_player(MockTapestryId, "alice".to_string()).await.unwrap();
+		loom.add_player(MockTapestryId, "bob".to_string()).await.unwrap();
+		assert_eq!(players(&loom).await, vec!["alice", "bob"]);
+
+		loom.remove_player(MockTapestryId, "carol".to_string()).await.unwrap();
+		loom.remove_player(MockTapestryId, "alice".to_string()).await.unwrap();
+		assert_eq!(players(&loom).await, vec!["bob"]);
+	}
+
+	#[tokio::test]
+	async fn players_stay_sorted() {
+		let loom = Loom::<MockConfig>::new();
+
+		loom.add_player(MockTapestryId, "carol".to_string()).await.unwrap();
+		loom.add_player(MockTapestryId, "alice".to_string()).await.unwrap();
+		loom.add_player(MockTapestryId, "dave".to_string()).await.unwrap();
+		loom.remove_player(MockTapestryId, "alice".to_string()).await.unwrap();
+		weave(&loom, "bob", "I join the party.".to_string()).await;
+		loom.add_player(MockTapestryId, "alice".to_string()).await.unwrap();
+		assert_eq!(players(&loom).await, vec!["alice", "bob", "carol", "dave"]);
+	}
+
+	#[tokio::test]
+	async fn prompting_players_join_and_survive_summaries() {
+		let loom = Loom::<MockConfig>::new();
+		loom.add_player(MockTapestryId, "alice".to_string()).await.unwrap();
+
+		assert!(!weave(&loom, "bob", "I join the party.".to_string()).await);
+		assert_eq!(players(&loom).await, vec!["alice", "bob"]);
+
+		assert!(weave(&loom, "alice", "word ".repeat(450)).await);
+		assert_eq!(players(&loom).await, vec!["alice", "bob"]);
+	}
+}
+
+#[cfg(test)]
+mod delete_tapestry {
+	use super::*;
+	use crate::{
+		mock::{FailingChestConfig, SAVE_FAILURES},
+		types::USER_ROLE,
+	};
+
+	#[tokio::test]
+	async fn removes_every_instance() {
+		let loom = Loom::<MockConfig>::new();
+		let fragment = TapestryFragment::<MockConfig> {
+			players: vec!["alice".to_string()],
+			..Default::default()
+		};
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment.clone(), false)
+			.await
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		loom.delete_tapestry(MockTapestryId).await.unwrap();
+
+		for instance in [None, Some(1), Some(2)] {
+			let fragment: Option<TapestryFragment<MockConfig>> =
+				loom.chest.get_tapestry_fragment(MockTapestryId, instance).await.unwrap();
+			assert!(fragment.is_none());
+		}
+	}
+
+	#[tokio::test]
+	async fn missing_tapestry_is_a_no_op() {
+		let loom = Loom::<MockConfig>::new();
+
+		loom.delete_tapestry(MockTapestryId).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn clear_keeps_players_in_a_single_instance() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig> {
+			players: vec!["alice".to_string()],
+			..Default::default()
+		};
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"We open the gate.".to_string(),
+				Some("alice".to_string()),
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment.clone(), true)
+			.await
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		assert_eq!(loom.clear(MockTapestryId).await.unwrap(), 1);
+
+		assert_eq!(
+			TapestryChestHandler::<MockConfig>::get_instance_index(&loom.chest, MockTapestryId)
+				.await
+				.unwrap(),
+			Some(1)
+		);
+		let cleared: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(
+			cleared,
+			TapestryFragment { players: vec!["alice".to_string()], ..Default::default() }
+		);
+	}
+	#[tokio::test]
+	async fn clear_keeps_story_metadata() {
+		let loom = Loom::<MockConfig>::new();
+		loom.set_title(MockTapestryId, "The Sunken Keep").await.unwrap();
+
+		loom.clear(MockTapestryId).await.unwrap();
+
+		assert_eq!(loom.story_metadata(MockTapestryId).await.unwrap().title, "The Sunken Keep");
+	}
+
+	#[tokio::test]
+	async fn failed_clear_restores_the_story() {
+		let loom = Loom::<FailingChestConfig>::new();
+		let mut fragment = TapestryFragment::<FailingChestConfig> {
+			players: vec!["alice".to_string()],
+			..Default::default()
+		};
+		fragment
+			.push_message(Loom::<FailingChestConfig>::build_context_message(
+				USER_ROLE.into(),
+				"We open the gate.".to_string(),
+				Some("alice".to_string()),
+			))
+			.unwrap();
+		for _ in 0..2 {
+			loom.chest
+				.save_tapestry_fragment(&MockTapestryId, fragment.clone(), true)
+				.await
+				.unwrap();
+		}
+		TapestryChestHandler::<FailingChestConfig>::save_tapestry_metadata(
+			&loom.chest,
+			MockTapestryId,
+			"campaign".to_string(),
+		)
+		.await
+		.unwrap();
+		loom.set_title(MockTapestryId, "The Sunken Keep").await.unwrap();
+
+		SAVE_FAILURES.with(|failures| *failures.borrow_mut() = 1);
+		assert!(loom.clear(MockTapestryId).await.is_err());
+
+		for instance in [1, 2] {
+			let restored: TapestryFragment<FailingChestConfig> = loom
+				.chest
+				.get_tapestry_fragment(MockTapestryId, Some(instance))
+				.await
+				.unwrap()
+				.unwrap();
+			assert_eq!(restored.context_messages, fragment.context_messages);
+			assert_eq!(restored.players, vec!["alice"]);
+		}
+		let metadata: Option<String> =
+			TapestryChestHandler::<FailingChestConfig>::get_tapestry_metadata(
+				&loom.chest,
+				MockTapestryId,
+			)
+			.await
+			.unwrap();
+		assert_eq!(metadata.as_deref(), Some("campaign"));
+		assert_eq!(loom.story_metadata(MockTapestryId).await.unwrap().title, "The Sunken Keep");
+	}
+}
+
+#[cfg(test)]
+mod full_story {
+	use super::*;
+	use crate::{mock::push_response, types::USER_ROLE};
+
+	#[tokio::test]
+	async fn instances_are_concatenated_without_summaries() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"word ".repeat(450),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		push_response("Many words were said.", false);
+		push_response("The tavern cheers.", false);
+		let (_, instance, was_summary_generated) = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I buy a round of ale.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+		assert!(was_summary_generated);
+		assert_eq!(instance, 2);
+
+		let story = loom.get_full_story(MockTapestryId).await.unwrap();
+		let contents: Vec<_> = story.iter().map(|msg| msg.content.as_str()).collect();
+		assert_eq!(
+			contents,
+			vec!["word ".repeat(450).as_str(), "I buy a round of ale.", "The tavern cheers."]
+		);
+	}
+
+	#[tokio::test]
+	async fn missing_tapestry_has_no_story() {
+		let loom = Loom::<MockConfig>::new();
+
+		assert!(loom.get_full_story(MockTapestryId).await.unwrap().is_empty());
+	}
+}
+
+#[cfg(test)]
+mod tools {
+	use super::*;
+	use crate::{
+		mock::{push_response, push_tool_call, TOOLS},
+		types::{ToolCall, ToolDefinition, ToolReply, ASSISTANT_ROLE, TOOL_ROLE, USER_ROLE},
+	};
+
+	async fn prompt_with_tools(
+		loom: &Loom<MockConfig>,
+		msg: ContextMessage<MockConfig>,
+	) -> ToolReply<MockConfig> {
+		let tools = [ToolDefinition {
+			name: "roll_dice".to_string(),
+			description: "Rolls a number of six sided dice.".to_string(),
+			parameters: serde_json::json!({
+				"type": "object",
+				"properties": { "count": { "type": "integer" } },
+			}),
+		}];
+
+		let (reply, _, _) = loom
+			.prompt_with_tools(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![msg],
+				&tools,
+			)
+			.await
+			.unwrap();
+		reply
+	}
+
+	#[tokio::test]
+	async fn tool_calls_are_returned_and_persisted() {
+		let loom = Loom::<MockConfig>::new();
+		let tool_call = ToolCall {
+			id: "call_1".to_string(),
+			name: "roll_dice".to_string(),
+			arguments: serde_json::json!({ "count": 2 }),
+		};
+
+		push_tool_call(tool_call.clone());
+		let reply = prompt_with_tools(
+			&loom,
+			Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I attack the goblin.".to_string(),
+				None,
+			),
+		)
+		.await;
+
+		assert!(matches!(reply, ToolReply::ToolCall(call) if call == tool_call));
+		assert_eq!(TOOLS.with(|tools| tools.borrow().clone()), vec!["roll_dice"]);
+
+		push_response("You hit the goblin for 7 damage.", false);
+		let reply = prompt_with_tools(
+			&loom,
+			Loom::<MockConfig>::build_context_message(TOOL_ROLE.into(), "7".to_string(), None),
+		)
+		.await;
+
+		assert!(
+			matches!(reply, ToolReply::Text(response) if response.content == "You hit the goblin for 7 damage.")
+		);
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let msgs: Vec<_> = fragment
+			.context_messages
+			.iter()
+			.map(|msg| (String::from(msg.role.clone()), msg.content.clone()))
+			.collect();
+		assert_eq!(
+			msgs,
+			vec![
+				(USER_ROLE.to_string(), "I attack the goblin.".to_string()),
+				(ASSISTANT_ROLE.to_string(), serde_json::to_string(&tool_call).unwrap()),
+				(TOOL_ROLE.to_string(), "7".to_string()),
+				(ASSISTANT_ROLE.to_string(), "You hit the goblin for 7 damage.".to_string()),
+			]
+		);
+	}
+}
+
+#[cfg(test)]
+mod context_strategy {
+	use super::*;
+	use crate::{
+		loom::SUMMARY_PREFIX,
+		mock::{last_prompt, PROMPTS},
+		types::{ContextStrategy, ASSISTANT_ROLE, USER_ROLE},
+	};
+
+	test_config!(SlidingWindowConfig {
+		const TOKEN_THRESHOLD_PERCENTILE: BoundedU8<0, 100> = BoundedU8::new(70).unwrap();
+		const MINIMUM_RESPONSE_LENGTH: u64 = 300;
+		const CONTEXT_STRATEGY: ContextStrategy = ContextStrategy::SlidingWindow { keep_last: 3 };
+	});
+
+	test_config!(TruncateConfig {
+		const TOKEN_THRESHOLD_PERCENTILE: BoundedU8<0, 100> = BoundedU8::new(70).unwrap();
+		const MINIMUM_RESPONSE_LENGTH: u64 = 300;
+		const CONTEXT_STRATEGY: ContextStrategy = ContextStrategy::Truncate;
+	});
+
+	/// Saves a tapestry fragment holding a message repeating each of the `words` `count` times.
+	///
+	/// Three messages of 150 words along with the minimum response length exceed the max prompt
+	/// tokens.
+	async fn save_history<C: Config<Chest = MockChest> + Serialize + DeserializeOwned>(
+		loom: &Loom<C>,
+		words: &[&str],
+		count: usize,
+	) {
+		let msgs = words.iter().map(|word| (USER_ROLE, format!("{} ", word).repeat(count)));
+		save_messages(loom, msgs.collect(), TapestryFragment::default()).await;
+	}
+
+	/// Saves the `fragment` holding the `(role, content)` messages.
+	async fn save_messages<C: Config<Chest = MockChest> + Serialize + DeserializeOwned>(
+		loom: &Loom<C>,
+		msgs: Vec<(&str, String)>,
+		mut fragment: TapestryFragment<C>,
+	) {
+		for (role, content) in msgs {
+			fragment
+				.push_message(Loom::<C>::build_context_message(role.into(), content, None))
+				.unwrap();
+		}
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+	}
+
+	async fn weave<C: Config<PromptModel = MockLlm, SummaryModel = MockLlm>>(
+		loom: &Loom<C>,
+	) -> Result<(u64, bool), C> {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<C>::build_context_message(
+				USER_ROLE.into(),
+				"I light a torch.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.map(|(_, instance, was_summary_generated)| (instance, was_summary_generated))
+	}
+
+	fn prompted_msgs() -> Vec<String> {
+		last_prompt().unwrap().msgs.into_iter().map(|m| m.msg).collect()
+	}
+
+	#[tokio::test]
+	async fn sliding_window_drops_oldest_messages() {
+		let loom = Loom::<SlidingWindowConfig>::new();
+		save_history(&loom, &["one", "two", "three"], 150).await;
+
+		assert_eq!(weave(&loom).await.unwrap(), (1, false));
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 1);
+		assert_eq!(
+			prompted_msgs(),
+			vec![
+				"instructions".to_string(),
+				"two ".repeat(150),
+				"three ".repeat(150),
+				"I light a torch.".to_string(),
+			]
+		);
+
+		let fragment: TapestryFragment<SlidingWindowConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let contents: Vec<_> =
+			fragment.context_messages.iter().map(|m| m.content.clone()).collect();
+		assert_eq!(
+			contents,
+			vec![
+				"two ".repeat(150),
+				"three ".repeat(150),
+				"I light a torch.".to_string(),
+				"TestLlmResponse".to_string(),
+			]
+		);
+		let mut context_tokens = 0;
+		for content in contents {
+			context_tokens += Loom::<SlidingWindowConfig>::count_tokens(content).await.unwrap();
+		}
+		assert_eq!(fragment.context_tokens, context_tokens);
+	}
+
+	#[tokio::test]
+	async fn sliding_window_drops_whole_turns() {
+		let loom = Loom::<SlidingWindowConfig>::new();
+		let msgs = vec![
+			(USER_ROLE, "one ".repeat(150)),
+			(ASSISTANT_ROLE, "first".to_string()),
+			(USER_ROLE, "two ".repeat(150)),
+			(ASSISTANT_ROLE, "second".to_string()),
+			(USER_ROLE, "three ".repeat(150)),
+		];
+		save_messages(&loom, msgs, TapestryFragment::default()).await;
+
+		assert_eq!(weave(&loom).await.unwrap(), (1, false));
+		assert_eq!(
+			prompted_msgs(),
+			vec![
+				"instructions".to_string(),
+				"two ".repeat(150),
+				"second".to_string(),
+				"three ".repeat(150),
+				"I light a torch.".to_string(),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn sliding_window_drops_a_leading_orphaned_response() {
+		let loom = Loom::<SlidingWindowConfig>::new();
+		let msgs = vec![
+			(ASSISTANT_ROLE, "intro ".repeat(150)),
+			(USER_ROLE, "one ".repeat(150)),
+			(ASSISTANT_ROLE, "first".to_string()),
+			(USER_ROLE, "two ".repeat(150)),
+		];
+		save_messages(&loom, msgs, TapestryFragment::default()).await;
+
+		assert_eq!(weave(&loom).await.unwrap(), (1, false));
+		assert_eq!(
+			prompted_msgs(),
+			vec![
+				"instructions".to_string(),
+				"one ".repeat(150),
+				"first".to_string(),
+				"two ".repeat(150),
+				"I light a torch.".to_string(),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn sliding_window_keeps_the_summary_and_metadata() {
+		let loom = Loom::<SlidingWindowConfig>::new();
+		let summary = format!("{}the party entered the cave", SUMMARY_PREFIX);
+		let players = vec!["alice".to_string()];
+		let msgs = vec![
+			(USER_ROLE, summary.clone()),
+			(USER_ROLE, "one ".repeat(150)),
+			(USER_ROLE, "two ".repeat(150)),
+			(USER_ROLE, "three ".repeat(150)),
+		];
+		save_messages(
+			&loom,
+			msgs,
+			TapestryFragment { players: players.clone(), ..Default::default() },
+		)
+		.await;
+
+		assert_eq!(weave(&loom).await.unwrap(), (1, false));
+		assert_eq!(
+			prompted_msgs(),
+			vec![
+				"instructions".to_string(),
+				summary,
+				"two ".repeat(150),
+				"three ".repeat(150),
+				"I light a torch.".to_string(),
+			]
+		);
+
+		let fragment: TapestryFragment<SlidingWindowConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages.len(), 5);
+		assert_eq!(fragment.players, players);
+	}
+
+	#[tokio::test]
+	async fn sliding_window_exceeding_context_fails() {
+		let loom = Loom::<SlidingWindowConfig>::new();
+		save_history(&loom, &["one", "two", "three"], 200).await;
+
+		assert!(matches!(
+			weave(&loom).await,
+			Err(LoomError::SlidingWindowExceedsContext { keep_last: 3 })
+		));
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 0);
+	}
+
+	#[tokio::test]
+	async fn truncate_starts_a_new_instance() {
+		let loom = Loom::<TruncateConfig>::new();
+		save_history(&loom, &["one", "two", "three"], 150).await;
+
+		assert_eq!(weave(&loom).await.unwrap(), (2, false));
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 1);
+		assert_eq!(prompted_msgs(), vec!["instructions", "I light a torch."]);
+
+		let previous: TapestryFragment<TruncateConfig> = loom
+			.chest
+			.get_tapestry_fragment(MockTapestryId, Some(1))
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(previous.context_messages.len(), 3);
+	}
+}
+
+#[cfg(test)]
+mod regenerate {
+	use std::{
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc,
+		},
+		time::Duration,
+	};
+
+	use async_trait::async_trait;
+
+	use super::*;
+	use crate::{
+		mock::{last_prompt, push_response, RATE_LIMITS},
+		types::USER_ROLE,
+		Hooks,
+	};
+
+	async fn regenerate(loom: &Loom<MockConfig>) -> Result<String, MockConfig> {
+		loom.regenerate(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+		)
+		.await
+		.map(|(response, _, _)| response.content)
+	}
+
+	#[tokio::test]
+	async fn last_response_is_replaced() {
+		let loom = Loom::<MockConfig>::new();
+		push_response("The door creaks open.", false);
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I open the door.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+
+		push_response("The door is locked.", false);
+		assert_eq!(regenerate(&loom).await.unwrap(), "The door is locked.");
+
+		let prompted: Vec<_> = last_prompt().unwrap().msgs.into_iter().map(|m| m.msg).collect();
+		assert_eq!(prompted, vec!["instructions", "I open the door."]);
+
+		let regenerated: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let contents: Vec<_> =
+			regenerated.context_messages.iter().map(|m| m.content.as_str()).collect();
+		assert_eq!(contents, vec!["I open the door.", "The door is locked."]);
+		assert_eq!(regenerated.story_length.turns, 1);
+		assert_eq!(
+			regenerated.context_tokens,
+			Loom::<MockConfig>::count_tokens("I open the door.".to_string()).await.unwrap() +
+				Loom::<MockConfig>::count_tokens("The door is locked.".to_string())
+					.await
+					.unwrap()
+		);
+	}
+
+	#[tokio::test]
+	async fn requires_a_trailing_assistant_message() {
+		let loom = Loom::<MockConfig>::new();
+		assert!(matches!(regenerate(&loom).await, Err(LoomError::NothingToRegenerate)));
+
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I open the door.".to_string(),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment.clone(), true)
+			.await
+			.unwrap();
+
+		assert!(matches!(regenerate(&loom).await, Err(LoomError::NothingToRegenerate)));
+		let unchanged: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(unchanged, fragment);
+	}
+	async fn weave<C: Config<PromptModel = MockLlm, SummaryModel = MockLlm>>(loom: &Loom<C>) {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<C>::build_context_message(
+				USER_ROLE.into(),
+				"I open the door.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+	}
+
+	#[tokio::test]
+	async fn failed_regeneration_keeps_the_response() {
+		let loom = Loom::<MockConfig>::new();
+		push_response("The door creaks open.", false);
+		weave(&loom).await;
+		let woven: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+
+		RATE_LIMITS.with(|rate_limits| rate_limits.borrow_mut().push_back(Duration::ZERO));
+		assert!(regenerate(&loom).await.is_err());
+
+		let unchanged: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(unchanged, woven);
+	}
+
+	test_config!(CooldownConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MIN_PROMPT_INTERVAL: Option<Duration> = Some(Duration::from_secs(60));
+	});
+
+	#[derive(Debug, Default)]
+	struct CountingHooks(Arc<AtomicUsize>);
+
+	#[async_trait]
+	impl Hooks<CooldownConfig> for CountingHooks {
+		async fn before_prompt(&self, msg: &mut String) {
+			self.0.fetch_add(1, Ordering::SeqCst);
+			msg.push('!');
+		}
+	}
+
+	#[tokio::test]
+	async fn hooks_and_cooldown_are_not_applied_again() {
+		let hooks = CountingHooks::default();
+		let calls = hooks.0.clone();
+		let loom = Loom::<CooldownConfig>::new().with_hooks(hooks);
+		weave(&loom).await;
+
+		push_response("The door is locked.", false);
+		let (response, _, _) = loom
+			.regenerate(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(response.content, "The door is locked.");
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+		let prompted: Vec<_> = last_prompt().unwrap().msgs.into_iter().map(|m| m.msg).collect();
+		assert_eq!(prompted, vec!["instructions", "I open the door.!"]);
+	}
+}
+
+#[cfg(test)]
+mod dry_run {
+	use super::*;
+	use crate::{
+		mock::PROMPTS,
+		types::{CostEstimate, PromptPreview, USER_ROLE},
+	};
+
+	async fn weave_dry_run(loom: &Loom<MockConfig>) -> PromptPreview<MockConfig> {
+		loom.weave_dry_run(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I draw my sword.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap()
+	}
+
+	fn msgs(preview: &PromptPreview<MockConfig>) -> Vec<String> {
+		preview.msgs.iter().map(|m| m.msg.clone()).collect()
+	}
+
+	#[tokio::test]
+	async fn prompt_is_assembled_without_prompting() {
+		let loom = Loom::<MockConfig>::new();
+
+		let preview = weave_dry_run(&loom).await;
+
+		assert_eq!(msgs(&preview), vec!["instructions", "I draw my sword."]);
+		assert!(!preview.would_summarize);
+		assert_eq!(preview.max_completion_tokens, 700 - preview.prompt_tokens);
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 0);
+		let fragment: Option<TapestryFragment<MockConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+	}
+
+	#[tokio::test]
+	async fn cost_is_estimated_without_prompting() {
+		let loom = Loom::<MockConfig>::new();
+		let preview = weave_dry_run(&loom).await;
+
+		let estimate = loom
+			.estimate_cost(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I draw my sword.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+
+		let prompt_tokens = preview.prompt_tokens as u64;
+		assert_eq!(
+			estimate,
+			CostEstimate {
+				prompt_tokens,
+				max_completion_tokens: preview.max_completion_tokens as u64,
+				min_cost: prompt_tokens as f64,
+				max_cost: 700.0,
+				would_summarize: false,
+			}
+		);
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 0);
+	}
+
+	#[tokio::test]
+	async fn summaries_are_not_generated() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"word ".repeat(450),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment.clone(), true)
+			.await
+			.unwrap();
+
+		let preview = weave_dry_run(&loom).await;
+
+		assert!(preview.would_summarize);
+		assert_eq!(msgs(&preview), vec!["instructions", "I draw my sword."]);
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 0);
+		let unchanged: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(unchanged, fragment);
+	}
+}
+
+#[cfg(test)]
+mod token_word_ratio {
+	use super::*;
+
+	#[test]
+	fn defaults_to_english_average() {
+		assert_eq!(<MockLlm as Llm<MockConfig>>::TOKEN_WORD_RATIO.get(), 75);
+		// 2 sentences of 20 words at 75 words per 100 tokens
+		assert_eq!(<MockLlm as Llm<MockConfig>>::convert_sentences_to_tokens(&MockLlm, 2), 53);
+	}
+}
+
+#[cfg(test)]
+mod moderated_messages {
+	use super::*;
+	use crate::{mock::PROMPTS, types::USER_ROLE};
+
+	test_config!(ModerationConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MODERATE_MESSAGES: bool = true;
+	});
+
+	#[derive(Debug)]
+	struct ModerationHooks;
+
+	#[async_trait]
+	impl Hooks<ModerationConfig> for ModerationHooks {
+		async fn moderate(&self, content: &str) -> Result<Vec<String>, ModerationConfig> {
+			if content.contains("gore") {
+				Ok(vec!["violence".to_string(), "violence/graphic".to_string()])
+			} else {
+				Ok(vec![])
+			}
+		}
+	}
+
+	async fn weave(loom: &Loom<ModerationConfig>, content: &str) -> Result<(), ModerationConfig> {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![
+				Loom::<ModerationConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I enter the arena.".to_string(),
+					None,
+				),
+				Loom::<ModerationConfig>::build_context_message(
+					USER_ROLE.into(),
+					content.to_string(),
+					None,
+				),
+			],
+		)
+		.await
+		.map(|_| ())
+	}
+
+	#[tokio::test]
+	async fn flagged_messages_are_rejected() {
+		let loom = Loom::<ModerationConfig>::new().with_hooks(ModerationHooks);
+
+		let Err(LoomError::Moderated { categories }) = weave(&loom, "Describe the gore.").await
+		else {
+			panic!("Flagged messages should be rejected");
+		};
+		assert_eq!(categories, vec!["violence", "violence/graphic"]);
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 0);
+		let fragment: Option<TapestryFragment<ModerationConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+
+		weave(&loom, "I salute the crowd.").await.unwrap();
+	}
+
+	#[derive(Debug)]
+	struct UnavailableHooks;
+
+	#[async_trait]
+	impl Hooks<ModerationConfig> for UnavailableHooks {
+		async fn moderate(&self, _content: &str) -> Result<Vec<String>, ModerationConfig> {
+			Err(LoomError::UnknownError("Moderation unavailable".to_string()))
+		}
+	}
+
+	#[tokio::test]
+	async fn messages_are_rejected_when_moderation_fails() {
+		let loom = Loom::<ModerationConfig>::new().with_hooks(UnavailableHooks);
+
+		assert!(matches!(
+			weave(&loom, "I salute the crowd.").await,
+			Err(LoomError::UnknownError(e)) if e == "Moderation unavailable"
+		));
+		assert_eq!(PROMPTS.with(|prompts| prompts.borrow().len()), 0);
+		let fragment: Option<TapestryFragment<ModerationConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+	}
+}
+
+#[cfg(test)]
+mod sanitize_name {
+	use super::*;
+	use crate::{mock::last_prompt, types::USER_ROLE};
+
+	#[test]
+	fn names_match_the_openai_format() {
+		assert_eq!(
+			Loom::<MockConfig>::sanitize_name("🐉 DungeonMaster"),
+			Some("DungeonMaster".to_string())
+		);
+		assert_eq!(
+			Loom::<MockConfig>::sanitize_name("  Sir  Galahad the-Pure "),
+			Some("Sir_Galahad_the-Pure".to_string())
+		);
+		assert_eq!(Loom::<MockConfig>::sanitize_name(&"a".repeat(100)), Some("a".repeat(64)));
+		assert_eq!(Loom::<MockConfig>::sanitize_name("🐉✨"), None);
+	}
+
+	#[tokio::test]
+	async fn names_sent_are_sanitized_but_stored_intact() {
+		let loom = Loom::<MockConfig>::new();
+
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I light the torch.".to_string(),
+				Some("🐉 DungeonMaster".to_string()),
+			)],
+		)
+		.await
+		.unwrap();
+
+		let prompt = last_prompt().unwrap();
+		let req = prompt.msgs.iter().find(|m| m.msg == "I light the torch.").unwrap();
+		assert_eq!(req.name.as_deref(), Some("DungeonMaster"));
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages[0].account_id.as_deref(), Some("🐉 DungeonMaster"));
+	}
+}
+
+#[cfg(test)]
+mod summarize {
+	use super::*;
+	use crate::{mock::push_response, types::USER_ROLE};
+
+	async fn summarize(loom: &Loom<MockConfig>) -> Result<(String, u64), MockConfig> {
+		loom.summarize(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+		)
+		.await
+	}
+
+	#[tokio::test]
+	async fn summary_starts_a_new_instance() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig> {
+			players: vec!["alice".to_string()],
+			..Default::default()
+		};
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"We make camp for the night.".to_string(),
+				Some("alice".to_string()),
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		push_response("The party rested.", false);
+		assert_eq!(summarize(&loom).await.unwrap(), ("The party rested.".to_string(), 2));
+
+		let summarized: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(summarized.context_messages.len(), 1);
+		assert!(summarized.context_messages[0].content.ends_with("The party rested."));
+		assert_eq!(summarized.players, vec!["alice"]);
+	}
+
+	#[tokio::test]
+	async fn empty_tapestry_has_nothing_to_summarize() {
+		let loom = Loom::<MockConfig>::new();
+
+		assert!(matches!(summarize(&loom).await, Err(LoomError::NothingToSummarize)));
+	}
+}
+
+#[cfg(test)]
+mod concurrent_weaves {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{mock::PROMPT_DELAY, types::USER_ROLE};
+
+	async fn weave(loom: &Loom<MockConfig>, content: &str) {
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				content.to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+	}
+
+	#[tokio::test]
+	async fn concurrent_messages_are_all_persisted() {
+		let loom = Loom::<MockConfig>::new();
+		PROMPT_DELAY.with(|delay| *delay.borrow_mut() = Some(Duration::from_millis(10)));
+
+		futures::join!(
+			weave(&loom, "I cast a fireball."),
+			weave(&loom, "I raise my shield."),
+			loom.add_player(MockTapestryId, "alice".to_string()),
+		)
+		.2
+		.unwrap();
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let contents: Vec<_> =
+			fragment.context_messages.iter().map(|m| m.content.as_str()).collect();
+		assert_eq!(
+			contents,
+			vec!["I cast a fireball.", "TestLlmResponse", "I raise my shield.", "TestLlmResponse"]
+		);
+		assert_eq!(fragment.players, vec!["alice"]);
+	}
+
+	#[tokio::test]
+	async fn delete_waits_for_in_flight_weave() {
+		let loom = Loom::<MockConfig>::new();
+		PROMPT_DELAY.with(|delay| *delay.borrow_mut() = Some(Duration::from_millis(10)));
+
+		let (_, deleted) = futures::join!(weave(&loom, "I cast a fireball."), async {
+			tokio::time::sleep(Duration::from_millis(5)).await;
+			loom.delete_tapestry(MockTapestryId).await
+		});
+
+		deleted.unwrap();
+		let fragment: Option<TapestryFragment<MockConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+	}
+
+	#[tokio::test]
+	async fn locks_are_removed_once_released() {
+		let loom = Loom::<MockConfig>::new();
+		PROMPT_DELAY.with(|delay| *delay.borrow_mut() = Some(Duration::from_millis(10)));
+
+		let (_, _, count) = futures::join!(
+			weave(&loom, "I cast a fireball."),
+			weave(&loom, "I raise my shield."),
+			async {
+				tokio::time::sleep(Duration::from_millis(5)).await;
+				loom.tapestry_lock_count()
+			},
+		);
+
+		assert_eq!(count, 1);
+		assert_eq!(loom.tapestry_lock_count(), 0);
+	}
+}
+
+#[cfg(test)]
+mod candidates {
+	use super::*;
+	use crate::{
+		mock::push_response,
+		types::{Usage, USER_ROLE},
+	};
+
+	fn msgs() -> Vec<ContextMessage<MockConfig>> {
+		vec![Loom::<MockConfig>::build_context_message(
+			USER_ROLE.into(),
+			"Which path do we take?".to_string(),
+			None,
+		)]
+	}
+
+	async fn weave_candidates(
+		loom: &Loom<MockConfig>,
+		n: u8,
+	) -> Result<(Vec<String>, Usage), MockConfig> {
+		loom.weave_candidates(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			msgs(),
+			n,
+		)
+		.await
+	}
+
+	#[tokio::test]
+	async fn candidates_are_returned_without_persisting() {
+		let loom = Loom::<MockConfig>::new();
+		push_response("The left path.", false);
+		push_response("The right path.", false);
+
+		let (candidates, usage) = weave_candidates(&loom, 2).await.unwrap();
+
+		assert_eq!(candidates, vec!["The left path.", "The right path."]);
+		let candidate_tokens: u64 =
+			candidates.iter().map(|c| u64::from(MockLlm::count_tokens(c).unwrap())).sum();
+		assert_eq!(usage.completion_tokens, candidate_tokens);
+		let fragment: Option<TapestryFragment<MockConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+	}
+
+	#[tokio::test]
+	async fn accepted_candidate_is_persisted_with_msgs() {
+		let loom = Loom::<MockConfig>::new();
+
+		let instance = loom
+			.accept_candidate(MockTapestryId, msgs(), "The left path.".to_string())
+			.await
+			.unwrap();
+
+		assert_eq!(instance, 1);
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let contents: Vec<_> = fragment.context_messages.iter().map(|m| &m.content).collect();
+		assert_eq!(contents, vec!["Which path do we take?", "The left path."]);
+		assert_eq!(fragment.story_length.turns, 1);
+	}
+
+	#[tokio::test]
+	async fn number_of_candidates_is_capped() {
+		let loom = Loom::<MockConfig>::new();
+
+		assert!(matches!(weave_candidates(&loom, 0).await, Err(LoomError::BadConfig(_))));
+		assert!(matches!(
+			weave_candidates(&loom, MockConfig::MAX_CANDIDATES + 1).await,
+			Err(LoomError::BadConfig(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn full_context_has_no_room_for_candidates() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.push_message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"word ".repeat(500),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		assert!(matches!(
+			weave_candidates(&loom, 2).await,
+			Err(LoomError::CandidatesExceedContext)
+		));
+	}
+}
+
+#[cfg(test)]
+mod weave_json {
+	use super::*;
+	use crate::{
+		mock::{last_prompt, push_response},
+		types::USER_ROLE,
+	};
+
+	#[derive(Debug, PartialEq, Deserialize)]
+	struct Scene {
+		location: String,
+		mood: String,
+	}
+
+	test_config!(SentencesConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const RESPONSE_SENTENCES: Option<u64> = Some(2);
+	});
+
+	async fn weave_json<
+		C: Config<PromptModel = MockLlm, SummaryModel = MockLlm, Chest = MockChest>,
+	>(
+		loom: &Loom<C>,
+	) -> Result<(Scene, u64, bool), C> {
+		loom.weave_json(
+			LlmConfig::<C, MockLlm> { model: MockLlm, params: () },
+			LlmConfig::<C, MockLlm> { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<C>::build_context_message(
+				USER_ROLE.into(),
+				"Describe the scene.".to_string(),
+				None,
+			)],
+		)
+		.await
+	}
+
+	#[tokio::test]
+	async fn response_is_parsed_and_persisted_raw() {
+		let loom = Loom::<SentencesConfig>::new();
+		let json = r#"{"location": "tavern", "mood": "tense"}"#;
+		push_response(json, false);
+
+		let (scene, instance, _) = weave_json(&loom).await.unwrap();
+
+		assert_eq!(scene, Scene { location: "tavern".to_string(), mood: "tense".to_string() });
+		assert_eq!(instance, 1);
+		let fragment: TapestryFragment<SentencesConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(fragment.context_messages.last().unwrap().content, json);
+		assert!(!last_prompt()
+			.unwrap()
+			.msgs
+			.iter()
+			.any(|m| m.msg.starts_with("Respond in about")));
+	}
+
+	#[tokio::test]
+	async fn malformed_response_fails_to_parse() {
+		let loom = Loom::<MockConfig>::new();
+		push_response("The tavern is tense.", false);
+
+		assert!(matches!(weave_json(&loom).await, Err(LoomError::ParseFailed(_))));
+	}
+}
+
+#[cfg(test)]
+mod export {
+	use super::*;
+	use crate::{
+		loom::SUMMARY_PREFIX,
+		mock::{FailingChestConfig, SAVE_FAILURES},
+		types::{ExportFormat, ASSISTANT_ROLE, USER_ROLE},
+	};
+
+	fn msg(role: &str, content: &str, account_id: Option<&str>) -> ContextMessage<MockConfig> {
+		ContextMessage::new(
+			role.into(),
+			content.to_string(),
+			account_id.map(str::to_string),
+			"12:00".to_string(),
+		)
+	}
+
+	async fn save_chapters(loom: &Loom<MockConfig>) {
+		let chapters = [
+			vec![
+				msg(USER_ROLE, "I enter the cave.", Some("alice")),
+				msg(ASSISTANT_ROLE, "It is dark.", None),
+			],
+			vec![
+				msg(
+					ASSISTANT_ROLE,
+					&format!("{} Alice entered a dark cave.", SUMMARY_PREFIX),
+					None,
+				),
+				msg(USER_ROLE, "I light a torch.", None),
+			],
+		];
+		for msgs in chapters {
+			let mut fragment = TapestryFragment::<MockConfig>::default();
+			fragment.extend_messages(msgs).unwrap();
+			loom.chest
+				.save_tapestry_fragment(&MockTapestryId, fragment, true)
+				.await
+				.unwrap();
+		}
+	}
+
+	#[tokio::test]
+	async fn markdown_has_chapter_dividers() {
+		let loom = Loom::<MockConfig>::new();
+		save_chapters(&loom).await;
+
+		let markdown = loom
+			.export(MockTapestryId, ExportFormat::Markdown { timestamps: false })
+			.await
+			.unwrap();
+
+		assert_eq!(
+			markdown,
+			"**alice**: I enter the cave.\n\nIt is dark.\n\n— Chapter 2 —\n\n**user**: I light a \
+			 torch."
+		);
+	}
+
+	#[tokio::test]
+	async fn markdown_labels_include_timestamps() {
+		let loom = Loom::<MockConfig>::new();
+		save_chapters(&loom).await;
+
+		let markdown = loom
+			.export(MockTapestryId, ExportFormat::Markdown { timestamps: true })
+			.await
+			.unwrap();
+
+		assert!(markdown.starts_with("**alice** (12:00): I enter the cave."));
+	}
+
+	#[tokio::test]
+	async fn json_holds_every_instance() {
+		let loom = Loom::<MockConfig>::new();
+		save_chapters(&loom).await;
+
+		let json = loom.export(MockTapestryId, ExportFormat::Json).await.unwrap();
+
+		let fragments: Vec<TapestryFragment<MockConfig>> = serde_json::from_str(&json).unwrap();
+		assert_eq!(fragments.len(), 2);
+		assert_eq!(
+			fragments[1],
+			loom.chest
+				.get_tapestry_fragment(MockTapestryId, Some(2))
+				.await
+				.unwrap()
+				.unwrap()
+		);
+	}
+
+	#[tokio::test]
+	async fn json_export_round_trips_through_import() {
+		let loom = Loom::<MockConfig>::new();
+		save_chapters(&loom).await;
+		let json = loom.export(MockTapestryId, ExportFormat::Json).await.unwrap();
+
+		let imported = Loom::<MockConfig>::new();
+		assert_eq!(imported.import(MockTapestryId, &json, false).await.unwrap(), 2);
+
+		assert_eq!(imported.export(MockTapestryId, ExportFormat::Json).await.unwrap(), json);
+	}
+
+	#[tokio::test]
+	async fn import_requires_overwrite_of_existing_tapestry() {
+		let loom = Loom::<MockConfig>::new();
+		save_chapters(&loom).await;
+		let json = loom.export(MockTapestryId, ExportFormat::Json).await.unwrap();
+
+		assert!(matches!(
+			loom.import(MockTapestryId, &json, false).await,
+			Err(LoomError::TapestryAlreadyExists)
+		));
+		assert_eq!(loom.import(MockTapestryId, &json, true).await.unwrap(), 2);
+	}
+
+	#[tokio::test]
+	async fn failed_overwrite_restores_the_story() {
+		let loom = Loom::<MockConfig>::new();
+		save_chapters(&loom).await;
+		let json = loom.export(MockTapestryId, ExportFormat::Json).await.unwrap();
+		let imported = Loom::<FailingChestConfig>::new();
+		imported.import(MockTapestryId, &json, false).await.unwrap();
+		imported.set_title(MockTapestryId, "The Cave").await.unwrap();
+
+		let other = json.replace("I enter the cave.", "I leave the cave.");
+		SAVE_FAILURES.with(|failures| *failures.borrow_mut() = 1);
+		assert!(imported.import(MockTapestryId, &other, true).await.is_err());
+
+		assert_eq!(imported.export(MockTapestryId, ExportFormat::Json).await.unwrap(), json);
+		assert_eq!(imported.story_metadata(MockTapestryId).await.unwrap().title, "The Cave");
+	}
+
+	#[tokio::test]
+	async fn overwrite_replaces_story_metadata() {
+		let loom = Loom::<MockConfig>::new();
+		save_chapters(&loom).await;
+		loom.set_title(MockTapestryId, "The Cave").await.unwrap();
+		let json = loom.export(MockTapestryId, ExportFormat::Json).await.unwrap();
+
+		loom.import(MockTapestryId, &json, true).await.unwrap();
+
+		assert_eq!(
+			TapestryChestHandler::<MockConfig>::get_story_metadata(&loom.chest, MockTapestryId)
+				.await
+				.unwrap(),
+			None
+		);
+	}
+
+	#[tokio::test]
+	async fn import_rejects_unknown_roles() {
+		let loom = Loom::<MockConfig>::new();
+		save_chapters(&loom).await;
+		let json = loom.export(MockTapestryId, ExportFormat::Json).await.unwrap();
+
+		let imported = Loom::<MockConfig>::new();
+		let json = json.replace("\"user\"", "\"narrator\"");
+		assert!(matches!(
+			imported.import(MockTapestryId, &json, false).await,
+			Err(LoomError::ParseFailed(_))
+		));
+	}
+}
+
+#[cfg(test)]
+mod prompt_timeout {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{mock::PROMPT_DELAY, types::USER_ROLE};
+
+	test_config!(TimeoutConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 300;
+		const PROMPT_TIMEOUT: Option<Duration> = Some(Duration::from_millis(10));
+	});
+
+	#[tokio::test]
+	async fn slow_connection_check_times_out() {
+		let loom = Loom::<TimeoutConfig>::new();
+		PROMPT_DELAY.with(|delay| *delay.borrow_mut() = Some(Duration::from_millis(200)));
+
+		assert!(matches!(
+			loom.check_connection(&MockLlm, &MockLlm).await,
+			Err(LoomError::Timeout { .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn slow_prompt_times_out_without_persisting() {
+		let loom = Loom::<TimeoutConfig>::new();
+		PROMPT_DELAY.with(|delay| *delay.borrow_mut() = Some(Duration::from_millis(200)));
+
+		let result = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<TimeoutConfig>::build_context_message(
+					USER_ROLE.into(),
+					"Hello".to_string(),
+					None,
+				)],
+			)
+			.await;
+
+		assert!(matches!(result, Err(LoomError::Timeout { .. })));
+		let fragment: Option<TapestryFragment<TimeoutConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+	}
+}
+
+#[cfg(test)]
+mod empty_response {
+	use super::*;
+	use crate::{mock::push_response, types::USER_ROLE};
+
+	#[tokio::test]
+	async fn response_without_content_fails() {
+		let loom = Loom::<MockConfig>::new();
+		push_response("", false);
+
+		let result = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"Hello".to_string(),
+					None,
+				)],
+			)
+			.await;
+
+		assert!(matches!(result, Err(LoomError::EmptyResponse)));
+		let fragment: Option<TapestryFragment<MockConfig>> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap();
+		assert!(fragment.is_none());
+	}
+}
+
+#[cfg(test)]
+mod edit_messages {
+	use super::*;
+	use crate::types::{ASSISTANT_ROLE, USER_ROLE};
+
+	async fn save_messages(loom: &Loom<MockConfig>) {
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		fragment
+			.extend_messages(vec![
+				Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"I open the chset.".to_string(),
+					Some("alice".to_string()),
+				),
+				Loom::<MockConfig>::build_context_message(
+					ASSISTANT_ROLE.into(),
+					"It is empty.".to_string(),
+					None,
+				),
+			])
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+	}
+
+	async fn contents(loom: &Loom<MockConfig>) -> (Vec<String>, u16) {
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let contents = fragment.context_messages.iter().map(|m| m.content.clone()).collect();
+		(contents, fragment.context_tokens)
+	}
+
+	#[tokio::test]
+	async fn author_edits_message() {
+		let loom = Loom::<MockConfig>::new();
+		save_messages(&loom).await;
+
+		loom.edit_message(MockTapestryId, 0, "alice", "I open the chest.".to_string())
+			.await
+			.unwrap();
+
+		let (contents, context_tokens) = contents(&loom).await;
+		assert_eq!(contents, vec!["I open the chest.", "It is empty."]);
+		assert_eq!(
+			context_tokens,
+			MockLlm::count_tokens("I open the chest.").unwrap() +
+				MockLlm::count_tokens("It is empty.").unwrap()
+		);
+	}
+
+	#[tokio::test]
+	async fn only_author_edits_message() {
+		let loom = Loom::<MockConfig>::new();
+		save_messages(&loom).await;
+
+		let result = loom
+			.edit_message(MockTapestryId, 0, "bob", "I close the chest.".to_string())
+			.await;
+
+		assert!(matches!(result, Err(LoomError::NotMessageAuthor { index: 0 })));
+		assert!(matches!(
+			loom.edit_message(MockTapestryId, 5, "alice", String::new()).await,
+			Err(LoomError::MessageNotFound { index: 5 })
+		));
+	}
+
+	#[tokio::test]
+	async fn deletion_cascades_to_reply() {
+		let loom = Loom::<MockConfig>::new();
+		save_messages(&loom).await;
+
+		loom.delete_message(MockTapestryId, 0, false).await.unwrap();
+		assert_eq!(contents(&loom).await.0, vec!["It is empty."]);
+
+		save_messages(&loom).await;
+		loom.delete_message(MockTapestryId, 0, true).await.unwrap();
+		assert_eq!(contents(&loom).await, (Vec::new(), 0));
+	}
+}
+
+#[cfg(test)]
+mod summary_budget {
+	use super::*;
+	use crate::{
+		mock::{last_prompt, push_response},
+		types::USER_ROLE,
+	};
+
+	test_config!(SummaryConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const SUMMARY_TARGET_TOKENS: Option<u64> = Some(5);
+		const SUMMARY_INSTRUCTIONS: Option<&'static str> = Some("Preserve the plot threads.");
+	});
+
+	async fn summarize(loom: &Loom<SummaryConfig>) -> Result<(String, u64), SummaryConfig> {
+		let mut fragment = TapestryFragment::<SummaryConfig>::default();
+		fragment
+			.push_message(Loom::<SummaryConfig>::build_context_message(
+				USER_ROLE.into(),
+				"We make camp for the night.".to_string(),
+				None,
+			))
+			.unwrap();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		loom.summarize(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+		)
+		.await
+	}
+
+	#[tokio::test]
+	async fn summary_is_prompted_with_instructions_and_target() {
+		let loom = Loom::<SummaryConfig>::new();
+		push_response("The party rested.", false);
+
+		summarize(&loom).await.unwrap();
+
+		let prompt = last_prompt().unwrap();
+		assert_eq!(prompt.max_tokens, 5);
+		assert_eq!(prompt.msgs[0].msg, "Preserve the plot threads.");
+	}
+
+	#[tokio::test]
+	async fn summary_exceeding_target_fails() {
+		let loom = Loom::<SummaryConfig>::new();
+		push_response("The party rested by the fire until the sun rose.", false);
+
+		assert!(matches!(
+			summarize(&loom).await,
+			Err(LoomError::SummaryExceedsBudget { max_tokens: 5, .. })
+		));
+	}
+}
+
+#[cfg(test)]
+mod weave_request {
+	use super::*;
+	use crate::{
+		mock::{last_prompt, push_response},
+		types::USER_ROLE,
+	};
+
+	#[tokio::test]
+	async fn request_is_woven() {
+		let loom = Loom::<MockConfig>::new();
+		push_response("The door creaks open.", false);
+
+		let (response, instance, was_summary_generated) = loom
+			.weave_request(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+			)
+			.instructions("You are a narrator.")
+			.message(Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I push the door.".to_string(),
+				None,
+			))
+			.send()
+			.await
+			.unwrap();
+
+		assert_eq!(response.content, "The door creaks open.");
+		assert_eq!((instance, was_summary_generated), (1, false));
+		let msgs = last_prompt().unwrap().msgs;
+		assert_eq!(msgs[0].msg, "You are a narrator.");
+		assert_eq!(msgs[1].msg, "I push the door.");
+	}
+}
+
+#[cfg(test)]
+mod expiry {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{mock::push_response, types::USER_ROLE};
+
+	test_config!(ExpiringConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const TAPESTRY_TTL: Option<Duration> = Some(Duration::from_secs(3600));
+	});
+
+	#[tokio::test]
+	async fn saving_refreshes_expiry() {
+		let loom = Loom::<ExpiringConfig>::new();
+		push_response("The door creaks open.", false);
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<ExpiringConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I knock on the door.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+
+		let fragment: TapestryFragment<ExpiringConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let expires_at = fragment.expires_at.unwrap();
+		assert!(expires_at > chrono::Utc::now() + chrono::Duration::minutes(59));
+		assert_eq!(loom.purge_expired().await.unwrap(), 0);
+	}
+
+	#[tokio::test]
+	async fn expired_tapestry_is_purged() {
+		let loom = Loom::<MockConfig>::new();
+		let expired = TapestryFragment::<MockConfig> {
+			expires_at: Some(chrono::Utc::now() - chrono::Duration::seconds(1)),
+			..Default::default()
+		};
+		loom.chest.save_tapestry_fragment(&MockTapestryId, expired, true).await.unwrap();
+
+		assert_eq!(loom.purge_expired().await.unwrap(), 1);
+		assert_eq!(
+			TapestryChestHandler::<MockConfig>::get_instance_index(&loom.chest, MockTapestryId)
+				.await
+				.unwrap(),
+			None
+		);
+	}
+
+	#[tokio::test]
+	async fn tapestry_without_ttl_never_expires() {
+		let loom = Loom::<MockConfig>::new();
+		loom.chest
+			.save_tapestry_fragment(
+				&MockTapestryId,
+				TapestryFragment::<MockConfig>::default(),
+				true,
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(loom.purge_expired().await.unwrap(), 0);
+		assert_eq!(
+			TapestryChestHandler::<MockConfig>::get_instance_index(&loom.chest, MockTapestryId)
+				.await
+				.unwrap(),
+			Some(1)
+		);
+	}
+
+	#[test]
+	fn records_without_expiry_deserialize() {
+		let mut value = serde_json::to_value(TapestryFragment::<MockConfig>::default()).unwrap();
+		value.as_object_mut().unwrap().remove("expires_at");
+		let fragment: TapestryFragment<MockConfig> = serde_json::from_value(value).unwrap();
+		assert_eq!(fragment.expires_at, None);
+	}
+}
+
+#[cfg(test)]
+mod hooks {
+	use async_trait::async_trait;
+
+	use super::*;
+	use crate::{
+		mock::{last_prompt, push_response},
+		types::USER_ROLE,
+		Hooks,
+	};
+
+	#[derive(Debug)]
+	struct FooterHooks;
+
+	#[async_trait]
+	impl Hooks<MockConfig> for FooterHooks {
+		async fn before_prompt(&self, msg: &mut String) {
+			*msg = msg.replace("hunter2", "[SECRET]");
+		}
+
+		async fn after_response(&self, content: &mut String) {
+			content.push_str(" -- The Narrator");
+		}
+	}
+
+	#[tokio::test]
+	async fn messages_and_responses_are_transformed() {
+		let loom = Loom::<MockConfig>::new().with_hooks(FooterHooks);
+		push_response("The door creaks open.", false);
+
+		let (response, _, _) = loom
+			.weave(
+				LlmConfig { model: MockLlm, params: () },
+				LlmConfig { model: MockLlm, params: () },
+				MockTapestryId,
+				"instructions".to_string(),
+				vec![Loom::<MockConfig>::build_context_message(
+					USER_ROLE.into(),
+					"The password is hunter2.".to_string(),
+					None,
+				)],
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(response.content, "The door creaks open. -- The Narrator");
+		assert_eq!(last_prompt().unwrap().msgs[1].msg, "The password is [SECRET].");
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let stored: Vec<_> = fragment.context_messages.iter().map(|m| m.content.as_str()).collect();
+		assert_eq!(
+			stored,
+			vec!["The password is [SECRET].", "The door creaks open. -- The Narrator"]
+		);
+	}
+}
+
+#[cfg(test)]
+mod memory_recall {
+	use super::*;
+	use crate::{
+		memory::{InMemoryStore, MemoryStore},
+		mock::{last_prompt, push_response},
+		types::USER_ROLE,
+	};
+
+	test_config!(MemoryConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const MEMORY_RECALL_COUNT: usize = 1;
+	});
+
+	#[derive(Debug)]
+	struct KeywordHooks;
+
+	#[async_trait]
+	impl Hooks<MemoryConfig> for KeywordHooks {
+		async fn embed(&self, content: &str) -> Result<Option<Vec<f32>>, MemoryConfig> {
+			Ok(Some(vec![
+				content.contains("dragon") as u8 as f32,
+				content.contains("tavern") as u8 as f32,
+			]))
+		}
+	}
+
+	async fn weave(loom: &Loom<MemoryConfig>, content: &str, response: &str) {
+		push_response(response, false);
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MemoryConfig>::build_context_message(
+				USER_ROLE.into(),
+				content.to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+	}
+
+	#[tokio::test]
+	async fn relevant_message_is_recalled_once_out_of_context() {
+		let loom = Loom::<MemoryConfig>::new().with_hooks(KeywordHooks);
+		weave(&loom, "I ask about the dragon.", "The dragon sleeps under the mountain.").await;
+		weave(&loom, "I walk to the tavern.", "The tavern is loud.").await;
+
+		// Messages still in the tapestry fragment are not recalled
+		weave(&loom, "Where is the dragon?", "Under the mountain.").await;
+		assert!(!last_prompt().unwrap().msgs.iter().any(|m| m.msg.starts_with("Relevant")));
+
+		summarize_away(&loom).await;
+		weave(&loom, "What about the tavern?", "It is still loud.").await;
+
+		let msgs = last_prompt().unwrap().msgs;
+		assert_eq!(msgs[1].msg, "Relevant earlier events of the story:\nI walk to the tavern.");
+		assert_eq!(msgs[2].msg, "What about the tavern?");
+	}
+
+	/// Simulates the messages of the tapestry being summarized away, leaving only their memories.
+	async fn summarize_away(loom: &Loom<MemoryConfig>) {
+		TapestryChestHandler::<MemoryConfig>::delete_tapestry(&loom.chest, MockTapestryId)
+			.await
+			.unwrap();
+	}
+
+	fn recalled() -> String {
+		last_prompt().unwrap().msgs[1].msg.clone()
+	}
+
+	#[tokio::test]
+	async fn deleted_message_is_no_longer_recalled() {
+		let loom = Loom::<MemoryConfig>::new().with_hooks(KeywordHooks);
+		weave(&loom, "I walk to the tavern.", "The tavern is loud.").await;
+		weave(&loom, "I ask about the dragon.", "The dragon sleeps.").await;
+
+		loom.delete_message(MockTapestryId, 0, true).await.unwrap();
+		summarize_away(&loom).await;
+		weave(&loom, "What about the tavern?", "It is quiet.").await;
+
+		assert!(recalled().starts_with("Relevant"));
+		assert!(!recalled().contains("tavern"));
+	}
+
+	#[tokio::test]
+	async fn edited_message_is_recalled_as_edited() {
+		let loom = Loom::<MemoryConfig>::new().with_hooks(KeywordHooks);
+		push_response("The road is long.", false);
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MemoryConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I walk to the tavern.".to_string(),
+				Some("alice".to_string()),
+			)],
+		)
+		.await
+		.unwrap();
+
+		loom.edit_message(MockTapestryId, 0, "alice", "I walk to the dragon's lair.".to_string())
+			.await
+			.unwrap();
+		summarize_away(&loom).await;
+		weave(&loom, "Where is the dragon?", "In its lair.").await;
+
+		assert_eq!(
+			recalled(),
+			"Relevant earlier events of the story:\nI walk to the dragon's lair."
+		);
+	}
+
+	#[tokio::test]
+	async fn injected_narration_is_recalled() {
+		let loom = Loom::<MemoryConfig>::new().with_hooks(KeywordHooks);
+		loom.inject_narration(MockTapestryId, "The dragon wakes.".to_string())
+			.await
+			.unwrap();
+
+		summarize_away(&loom).await;
+		weave(&loom, "Where is the dragon?", "In its lair.").await;
+
+		assert_eq!(recalled(), "Relevant earlier events of the story:\nThe dragon wakes.");
+	}
+
+	#[tokio::test]
+	async fn store_ranks_by_cosine_similarity() {
+		let store = InMemoryStore::<MockConfig>::default();
+		for (content, embedding) in [("north", vec![1.0, 0.0]), ("east", vec![0.0, 1.0])] {
+			let msg = Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				content.to_string(),
+				None,
+			);
+			store.insert("key", embedding, msg).await.unwrap();
+		}
+
+		let recalled = store.search("key", &[0.2, 0.9], 2).await.unwrap();
+		assert_eq!(
+			recalled.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(),
+			vec!["east", "north"]
+		);
+		assert!(store.search("other", &[0.2, 0.9], 2).await.unwrap().is_empty());
+	}
+}
+
+#[cfg(test)]
+mod continue_story {
+	use super::*;
+	use crate::{
+		mock::{last_prompt, push_response},
+		types::{PromptModelResponse, USER_ROLE},
+	};
+
+	async fn continue_story(
+		loom: &Loom<MockConfig>,
+	) -> Result<(PromptModelResponse<MockConfig>, u64, bool), MockConfig> {
+		loom.continue_story(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+		)
+		.await
+	}
+
+	#[tokio::test]
+	async fn only_the_response_is_appended() {
+		let loom = Loom::<MockConfig>::new();
+		push_response("The door creaks open.", false);
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I open the door.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+
+		push_response("A cold wind blows in.", false);
+		let (response, instance, _) = continue_story(&loom).await.unwrap();
+		assert_eq!((response.content.as_str(), instance), ("A cold wind blows in.", 1));
+
+		let prompted: Vec<_> = last_prompt().unwrap().msgs.into_iter().map(|m| m.msg).collect();
+		assert_eq!(prompted, vec!["instructions", "I open the door.", "The door creaks open."]);
+
+		let fragment: TapestryFragment<MockConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		let contents: Vec<_> =
+			fragment.context_messages.iter().map(|m| m.content.as_str()).collect();
+		assert_eq!(
+			contents,
+			vec!["I open the door.", "The door creaks open.", "A cold wind blows in."]
+		);
+		assert_eq!(fragment.story_length.turns, 2);
+		let mut expected_tokens = 0;
+		for content in contents {
+			expected_tokens += Loom::<MockConfig>::count_tokens(content.to_string()).await.unwrap();
+		}
+		assert_eq!(fragment.context_tokens, expected_tokens);
+	}
+
+	#[tokio::test]
+	async fn empty_story_cannot_be_continued() {
+		let loom = Loom::<MockConfig>::new();
+		assert!(matches!(continue_story(&loom).await, Err(LoomError::NothingToContinue)));
+
+		loom.chest
+			.save_tapestry_fragment(
+				&MockTapestryId,
+				TapestryFragment::<MockConfig>::default(),
+				true,
+			)
+			.await
+			.unwrap();
+		assert!(matches!(continue_story(&loom).await, Err(LoomError::NothingToContinue)));
+		assert!(last_prompt().is_none());
+	}
+}
+
+#[cfg(test)]
+mod story_metadata {
+	use super::*;
+	use crate::{mock::push_response, types::USER_ROLE};
+
+	async fn weave(loom: &Loom<MockConfig>) {
+		push_response("The door creaks open.", false);
+		loom.weave(
+			LlmConfig { model: MockLlm, params: () },
+			LlmConfig { model: MockLlm, params: () },
+			MockTapestryId,
+			"instructions".to_string(),
+			vec![Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I open the door.".to_string(),
+				None,
+			)],
+		)
+		.await
+		.unwrap();
+	}
+
+	#[tokio::test]
+	async fn weaving_bumps_updated_at() {
+		let loom = Loom::<MockConfig>::new();
+		weave(&loom).await;
+		loom.set_title(MockTapestryId, "The Haunted Manor").await.unwrap();
+		let metadata = loom.story_metadata(MockTapestryId).await.unwrap();
+		assert_eq!(metadata.title, "The Haunted Manor");
+
+		weave(&loom).await;
+		let woven_metadata = loom.story_metadata(MockTapestryId).await.unwrap();
+		assert_eq!(woven_metadata.title, "The Haunted Manor");
+		assert_eq!(woven_metadata.created_at, metadata.created_at);
+		assert!(woven_metadata.updated_at > metadata.updated_at);
+
+		loom.delete_tapestry(MockTapestryId).await.unwrap();
+		assert_eq!(loom.story_metadata(MockTapestryId).await.unwrap().title, "");
+	}
+
+	#[tokio::test]
+	async fn narration_bumps_updated_at() {
+		let loom = Loom::<MockConfig>::new();
+		weave(&loom).await;
+		let metadata = loom.story_metadata(MockTapestryId).await.unwrap();
+
+		loom.inject_narration(MockTapestryId, "Thunder rumbles.".to_string())
+			.await
+			.unwrap();
+		let narrated_metadata = loom.story_metadata(MockTapestryId).await.unwrap();
+		assert_eq!(narrated_metadata.created_at, metadata.created_at);
+		assert!(narrated_metadata.updated_at > metadata.updated_at);
+	}
+
+	#[tokio::test]
+	async fn missing_metadata_is_derived() {
+		let loom = Loom::<MockConfig>::new();
+		let mut fragment = TapestryFragment::<MockConfig>::default();
+		for timestamp in ["2024-01-01T10:00:00+00:00", "2024-01-02T10:00:00+00:00"] {
+			let mut msg = Loom::<MockConfig>::build_context_message(
+				USER_ROLE.into(),
+				"I open the door.".to_string(),
+				None,
+			);
+			msg.timestamp = timestamp.to_string();
+			fragment.push_message(msg).unwrap();
+		}
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment, true)
+			.await
+			.unwrap();
+
+		let metadata = loom.story_metadata(MockTapestryId).await.unwrap();
+		assert_eq!(metadata.title, "");
+		assert!(metadata.tags.is_empty());
+		assert_eq!(metadata.created_at.to_rfc3339(), "2024-01-01T10:00:00+00:00");
+		assert_eq!(metadata.updated_at.to_rfc3339(), "2024-01-02T10:00:00+00:00");
+	}
+}
+
+#[cfg(test)]
+mod fragment_compression {
+	use super::*;
+	use crate::{
+		storage::{decode_fragment, encode_fragment},
+		types::USER_ROLE,
+	};
+
+	test_config!(CompressedConfig {
+		const MINIMUM_RESPONSE_LENGTH: u64 = 100;
+		const COMPRESS_FRAGMENTS: bool = true;
+	});
+
+	fn fragment<T: Config>() -> TapestryFragment<T> {
+		let mut fragment = TapestryFragment::<T>::default();
+		for _ in 0..20 {
+			fragment
+				.push_message(Loom::<T>::build_context_message(
+					USER_ROLE.into(),
+					"The rain keeps falling on the old tavern roof.".to_string(),
+					None,
+				))
+				.unwrap();
+		}
+		fragment
+	}
+
+	#[test]
+	fn compressed_fragment_is_smaller() {
+		let fragment = fragment::<CompressedConfig>();
+		let compressed = encode_fragment(&fragment).unwrap();
+		let plain = serde_json::to_vec(&fragment).unwrap();
+
+		assert_eq!(compressed[..2], [0x1f, 0x8b]);
+		assert!(compressed.len() * 5 < plain.len());
+		assert_eq!(
+			decode_fragment::<CompressedConfig>(&compressed).unwrap().context_messages.len(),
+			20
+		);
+	}
+
+	#[tokio::test]
+	async fn legacy_fragment_remains_readable() {
+		let legacy_fragment = fragment::<MockConfig>();
+		let plain = encode_fragment(&legacy_fragment).unwrap();
+		assert_eq!(plain, serde_json::to_vec(&legacy_fragment).unwrap());
+		let decoded: TapestryFragment<CompressedConfig> = decode_fragment(&plain).unwrap();
+		assert_eq!(decoded.context_messages.len(), 20);
+
+		let loom = Loom::<CompressedConfig>::new();
+		loom.chest
+			.save_tapestry_fragment(&MockTapestryId, fragment::<CompressedConfig>(), true)
+			.await
+			.unwrap();
+		let saved: TapestryFragment<CompressedConfig> =
+			loom.chest.get_tapestry_fragment(MockTapestryId, None).await.unwrap().unwrap();
+		assert_eq!(saved.context_messages.len(), 20);
+	}
+}
+
+#[cfg(test)]
+mod check_connection {
+	use super::*;
+	use crate::mock::{MockPromptError, SERVER_ERRORS};
+
+	#[tokio::test]
+	async fn unreachable_model_fails() {
+		let loom = Loom::<MockConfig>::new();
+		loom.check_connection(&MockLlm, &MockLlm).await.unwrap();
+
+		SERVER_ERRORS.with(|errors| *errors.borrow_mut() = 1);
+		assert!(matches!(
+			loom.check_connection(&MockLlm, &MockLlm).await,
+			Err(LoomError::Llm(MockPromptError::ServerError))
+		));
+		loom.check_connection(&MockLlm, &MockLlm).await.unwrap();
+	}
+}
@@ -1,15 +1,20 @@
-use std::collections::VecDeque;
+use std::{
+	collections::{BTreeMap, VecDeque},
+	time::Duration,
+};
 
 use async_openai::types::Role;
 use num_traits::{CheckedAdd, FromPrimitive, SaturatingAdd};
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
-use crate::{Config, Llm};
+use crate::{Config, ContextMessage, Llm};
 
 pub type PromptModelTokens<T> = <<T as Config>::PromptModel as Llm<T>>::Tokens;
 pub type SummaryModelTokens<T> = <<T as Config>::SummaryModel as Llm<T>>::Tokens;
 pub type PromptModelRequest<T> = <<T as Config>::PromptModel as Llm<T>>::Request;
+pub type SummaryModelRequest<T> = <<T as Config>::SummaryModel as Llm<T>>::Request;
+pub type PromptModelResponse<T> = <<T as Config>::PromptModel as Llm<T>>::Response;
 
 /// Base type for all configuration parameters.
 pub type F32 = f32;
@@ -18,31 +23,78 @@ pub const SYSTEM_ROLE: &str = "system";
 pub const ASSISTANT_ROLE: &str = "assistant";
 pub const USER_ROLE: &str = "user";
 pub const FUNCTION_ROLE: &str = "function";
+pub const TOOL_ROLE: &str = "tool";
 
 /// Wrapped [`Role`] for custom implementations.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+///
+/// Serialized as the lowercase role string, e.g. `"user"`. The `{"Role": "user"}` form stored
+/// by earlier versions still deserializes.
+#[derive(Debug, Clone, PartialEq)]
 pub enum WrapperRole {
 	Role(Role),
 }
 
+impl Serialize for WrapperRole {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&String::from(self.clone()))
+	}
+}
+
+impl<'de> Deserialize<'de> for WrapperRole {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum StoredRole {
+			Role(String),
+			Legacy {
+				#[serde(rename = "Role")]
+				role: String,
+			},
+		}
+
+		let (StoredRole::Role(role) | StoredRole::Legacy { role }) =
+			StoredRole::deserialize(deserializer)?;
+		Self::try_from(role).map_err(serde::de::Error::custom)
+	}
+}
+
 impl Default for WrapperRole {
 	fn default() -> Self {
 		Self::Role(Role::User)
 	}
 }
 
+/// Error returned when parsing a role which is none of the [`Role`]s.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+	"Invalid role: {0} \n Valid roles: {SYSTEM_ROLE} | {ASSISTANT_ROLE} | {USER_ROLE} | \
+	 {FUNCTION_ROLE} | {TOOL_ROLE}"
+)]
+pub struct InvalidRole(pub String);
+
+impl TryFrom<String> for WrapperRole {
+	type Error = InvalidRole;
+
+	fn try_from(role: String) -> Result<Self, Self::Error> {
+		match role.as_str() {
+			SYSTEM_ROLE => Ok(Self::Role(Role::System)),
+			ASSISTANT_ROLE => Ok(Self::Role(Role::Assistant)),
+			USER_ROLE => Ok(Self::Role(Role::User)),
+			FUNCTION_ROLE => Ok(Self::Role(Role::Function)),
+			TOOL_ROLE => Ok(Self::Role(Role::Tool)),
+			_ => Err(InvalidRole(role)),
+		}
+	}
+}
+
+/// Meant for the role constants, use [`WrapperRole::try_from`] for roles from untrusted input.
+///
+/// # Panics
+///
+/// If `role` is none of the [`Role`]s.
 impl From<&str> for WrapperRole {
 	fn from(role: &str) -> Self {
-		match role {
-			SYSTEM_ROLE => Self::Role(Role::System),
-			ASSISTANT_ROLE => Self::Role(Role::Assistant),
-			USER_ROLE => Self::Role(Role::User),
-			FUNCTION_ROLE => Self::Role(Role::Function),
-			_ => panic!(
-				"Invalid role: {} \n Valid roles: {} | {} | {} | {}",
-				role, SYSTEM_ROLE, ASSISTANT_ROLE, USER_ROLE, FUNCTION_ROLE
-			),
-		}
+		Self::try_from(role.to_string()).unwrap_or_else(|e| panic!("{}", e))
 	}
 }
 
@@ -53,7 +105,7 @@ impl From<WrapperRole> for String {
 			WrapperRole::Role(Role::Assistant) => ASSISTANT_ROLE.to_string(),
 			WrapperRole::Role(Role::User) => USER_ROLE.to_string(),
 			WrapperRole::Role(Role::Function) => FUNCTION_ROLE.to_string(),
-			WrapperRole::Role(_) => panic!("Invalid role"),
+			WrapperRole::Role(Role::Tool) => TOOL_ROLE.to_string(),
 		}
 	}
 }
@@ -68,10 +120,326 @@ pub enum LoomError<T: Config> {
 	BadConfig(String),
 	#[error("Exceeds max prompt tokens")]
 	MaxCompletionTokensIsZero,
+	#[error("New messages exceed max prompt tokens, even after summarizing")]
+	MessagesExceedContext,
+	#[error("The last {keep_last} messages exceed max prompt tokens")]
+	SlidingWindowExceedsContext { keep_last: usize },
+	#[error("Messages leave no room for candidates without summarizing")]
+	CandidatesExceedContext,
+	#[error("No message at index {index}")]
+	MessageNotFound { index: usize },
+	#[error("Message at index {index} belongs to another account")]
+	NotMessageAuthor { index: usize },
+	#[error("The tapestry already holds data")]
+	TapestryAlreadyExists,
+	#[error("Summary of {tokens} tokens exceeds its budget of {max_tokens} tokens")]
+	SummaryExceedsBudget { tokens: u64, max_tokens: u64 },
+	#[error("The tapestry holds no message to summarize")]
+	NothingToSummarize,
+	#[error("The last message of the tapestry is not an assistant message")]
+	NothingToRegenerate,
+	#[error("The tapestry holds no message to continue")]
+	NothingToContinue,
+	#[error("Messages flagged by moderation: {categories:?}")]
+	Moderated { categories: Vec<String> },
+	#[error("LLM response holds no content")]
+	EmptyResponse,
+	#[error("Prompt timed out after {timeout:?}")]
+	Timeout { timeout: Duration },
+	/// A streamed response timed out after some deltas were received, holding the partial
+	/// response persisted in the tapestry.
+	#[error("Streamed response timed out after a partial response")]
+	PartialTimeout(String),
+	#[error("Tapestry prompted too soon, retry after {retry_after:?}")]
+	Cooldown { retry_after: Duration },
+	#[error("Daily completion token limit reached, retry after {retry_after:?}")]
+	DailyLimitReached { retry_after: Duration },
+	#[error("Story reached its maximum length")]
+	StoryComplete,
+	#[error("Model {model} does not support {capability}")]
+	UnsupportedCapability { model: &'static str, capability: &'static str },
+	#[error("LLM response does not match the expected schema: {0}")]
+	SchemaValidation(String),
+	#[error("Failed to parse LLM response: {0}")]
+	ParseFailed(String),
 	#[error("Unknown error: {0}")]
 	UnknownError(String),
 }
 
+impl<T: Config> LoomError<T> {
+	/// Name of the variant, used as a label of the
+	/// [`Loom::metrics_snapshot`](crate::loom::Loom::metrics_snapshot).
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Self::Llm(_) => "llm",
+			Self::Storage(_) => "storage",
+			Self::BadConfig(_) => "bad_config",
+			Self::MaxCompletionTokensIsZero => "max_completion_tokens_is_zero",
+			Self::MessagesExceedContext => "messages_exceed_context",
+			Self::SlidingWindowExceedsContext { .. } => "sliding_window_exceeds_context",
+			Self::CandidatesExceedContext => "candidates_exceed_context",
+			Self::MessageNotFound { .. } => "message_not_found",
+			Self::NotMessageAuthor { .. } => "not_message_author",
+			Self::TapestryAlreadyExists => "tapestry_already_exists",
+			Self::SummaryExceedsBudget { .. } => "summary_exceeds_budget",
+			Self::NothingToSummarize => "nothing_to_summarize",
+			Self::NothingToRegenerate => "nothing_to_regenerate",
+			Self::NothingToContinue => "nothing_to_continue",
+			Self::Moderated { .. } => "moderated",
+			Self::EmptyResponse => "empty_response",
+			Self::Timeout { .. } => "timeout",
+			Self::PartialTimeout(_) => "partial_timeout",
+			Self::Cooldown { .. } => "cooldown",
+			Self::DailyLimitReached { .. } => "daily_limit_reached",
+			Self::StoryComplete => "story_complete",
+			Self::UnsupportedCapability { .. } => "unsupported_capability",
+			Self::SchemaValidation(_) => "schema_validation",
+			Self::ParseFailed(_) => "parse_failed",
+			Self::UnknownError(_) => "unknown_error",
+		}
+	}
+}
+
+/// Event published to the subscribers of a tapestry by
+/// [`Loom::subscribe`](crate::loom::Loom::subscribe).
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoomEvent<T: Config> {
+	/// A message, either new or the LLM response, was saved to the tapestry.
+	MessageAppended(ContextMessage<T>),
+	/// A summary was generated, starting a new tapestry fragment instance.
+	SummaryGenerated(String),
+	/// Prompting the tapestry failed.
+	Error(String),
+}
+
+/// Tokens consumed and estimated cost of the prompts made by
+/// [`Loom::weave_with_usage`](crate::loom::Loom::weave_with_usage).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+	pub prompt_tokens: u64,
+	pub completion_tokens: u64,
+	/// Sum of the [`Llm::compute_cost`] of each prompt.
+	pub cost: f64,
+}
+
+impl std::ops::AddAssign for Usage {
+	fn add_assign(&mut self, other: Self) {
+		self.prompt_tokens = self.prompt_tokens.saturating_add(other.prompt_tokens);
+		self.completion_tokens = self.completion_tokens.saturating_add(other.completion_tokens);
+		self.cost += other.cost;
+	}
+}
+
+/// Prompt which would be sent to the LLM, as returned by
+/// [`Loom::weave_dry_run`](crate::loom::Loom::weave_dry_run).
+#[derive(Clone)]
+pub struct PromptPreview<T: Config> {
+	/// Request messages, starting with the instructions.
+	pub msgs: Vec<PromptModelRequest<T>>,
+	pub prompt_tokens: PromptModelTokens<T>,
+	pub max_completion_tokens: PromptModelTokens<T>,
+	/// Whether a summary would be generated, in which case it is missing from `msgs`.
+	pub would_summarize: bool,
+}
+
+/// Cost range of the prompt [`Loom::weave`](crate::loom::Loom::weave) would send, as estimated by
+/// [`Loom::estimate_cost`](crate::loom::Loom::estimate_cost).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CostEstimate {
+	pub prompt_tokens: u64,
+	pub max_completion_tokens: u64,
+	/// [`Llm::compute_cost`] of the prompt without any response.
+	pub min_cost: f64,
+	/// [`Llm::compute_cost`] of the prompt and a response of `max_completion_tokens`.
+	pub max_cost: f64,
+	/// Whether a summary would be generated first, the cost of which is not included.
+	pub would_summarize: bool,
+}
+
+/// Format of a story rendered by [`Loom::export`](crate::loom::Loom::export).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExportFormat {
+	/// Messages labeled by account or role, assistant messages being rendered as narrative
+	/// paragraphs, with a chapter divider between tapestry fragment instances.
+	Markdown {
+		/// Whether to add the timestamp of each message to its label.
+		timestamps: bool,
+	},
+	/// Every tapestry fragment instance in order, as a JSON array.
+	Json,
+}
+
+/// A tool the LLM can call, passed to [`Llm::set_tools`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolDefinition {
+	pub name: String,
+	pub description: String,
+	/// JSON schema of the arguments of the tool.
+	pub parameters: serde_json::Value,
+}
+
+/// A call to a [`ToolDefinition`] requested by the LLM, as returned by [`Llm::tool_call`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+	/// Identifier of the call, to be referred to by the message holding its result.
+	pub id: String,
+	pub name: String,
+	pub arguments: serde_json::Value,
+}
+
+/// Response of [`Loom::prompt_with_tools`](crate::loom::Loom::prompt_with_tools).
+pub enum ToolReply<T: Config> {
+	/// The LLM replied with text.
+	Text(PromptModelResponse<T>),
+	/// The LLM requested a tool call, whose result is to be sent back as a [`TOOL_ROLE`] message.
+	ToolCall(ToolCall),
+}
+
+/// Sampling parameters applied to a prompt through [`Llm::set_sampling`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingParameters {
+	pub temperature: F32,
+	/// Nucleus sampling probability mass, left to the LLM default if `None`.
+	pub top_p: Option<F32>,
+	pub frequency_penalty: F32,
+	pub presence_penalty: F32,
+	/// Seed making sampling deterministic, requiring the [`ModelCapabilities::seed`] of the model.
+	pub seed: Option<i64>,
+}
+
+/// How a tapestry fragment exceeding the max prompt tokens makes room for new messages.
+///
+/// See [`Config::CONTEXT_STRATEGY`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContextStrategy {
+	/// Start a new tapestry fragment instance holding a summary of the current one.
+	Summarize,
+	/// Drop the oldest turns of the current tapestry fragment instance until the prompt fits,
+	/// always keeping the most recent `keep_last` messages.
+	///
+	/// A turn runs up to and including the next assistant response, so a message is never sent
+	/// without its reply. A leading summary carried over from a previous instance is kept.
+	SlidingWindow { keep_last: usize },
+	/// Start a new empty tapestry fragment instance, without summarizing the current one.
+	Truncate,
+}
+
+/// Recommended combinations of sampling parameters and instructions for common genres.
+///
+/// See [`Config::GENRE_PRESET`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GenrePreset {
+	/// Temperature `0.7`, frequency penalty `0.3`, presence penalty `0.2`.
+	Mystery,
+	/// Temperature `1.0`, frequency penalty `0.5`, presence penalty `0.6`.
+	Comedy,
+	/// Temperature `0.8`, frequency penalty `0.4`, presence penalty `0.3`.
+	Horror,
+	/// Temperature `0.9`, frequency penalty `0.2`, presence penalty `0.5`.
+	Epic,
+}
+
+impl GenrePreset {
+	/// Sampling parameters recommended for the genre.
+	pub fn sampling(&self) -> SamplingParameters {
+		let (temperature, frequency_penalty, presence_penalty) = match self {
+			Self::Mystery => (0.7, 0.3, 0.2),
+			Self::Comedy => (1.0, 0.5, 0.6),
+			Self::Horror => (0.8, 0.4, 0.3),
+			Self::Epic => (0.9, 0.2, 0.5),
+		};
+		SamplingParameters {
+			temperature,
+			top_p: None,
+			frequency_penalty,
+			presence_penalty,
+			seed: None,
+		}
+	}
+
+	/// Instruction sent to the LLM to set the tone of the genre.
+	pub fn directive(&self) -> &'static str {
+		match self {
+			Self::Mystery =>
+				"Write a mystery: plant subtle clues, keep secrets and build suspense.",
+			Self::Comedy => "Write a comedy: keep the tone light, playful and full of wit.",
+			Self::Horror =>
+				"Write a horror story: build dread slowly and favor the unseen over the explicit.",
+			Self::Epic =>
+				"Write an epic: use grand stakes, vivid landscapes and a sense of destiny.",
+		}
+	}
+}
+
+/// Optional features of an LLM, such as those supported by a model or required by a prompt's
+/// parameters.
+///
+/// See [`Llm::capabilities`] and [`Llm::required_capabilities`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModelCapabilities {
+	/// Image inputs.
+	pub vision: bool,
+	/// Responses constrained to valid JSON.
+	pub json_mode: bool,
+	/// Tool or function calling.
+	pub tools: bool,
+	/// Deterministic sampling through a seed.
+	pub seed: bool,
+	/// Log probabilities of the response tokens.
+	pub logprobs: bool,
+}
+
+impl ModelCapabilities {
+	/// Returns the name of the first capability in `required` which is not part of `self`.
+	pub fn first_missing(&self, required: &ModelCapabilities) -> Option<&'static str> {
+		[
+			(required.vision && !self.vision, "vision"),
+			(required.json_mode && !self.json_mode, "JSON mode"),
+			(required.tools && !self.tools, "tools"),
+			(required.seed && !self.seed, "seed"),
+			(required.logprobs && !self.logprobs, "logprobs"),
+		]
+		.into_iter()
+		.find_map(|(missing, capability)| missing.then_some(capability))
+	}
+}
+
+/// A potential continuity error found by
+/// [`Loom::check_consistency`](crate::loom::Loom::check_consistency).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConsistencyWarning {
+	/// Description of the inconsistency.
+	pub description: String,
+	/// Index of the offending message in [`crate::TapestryFragment::context_messages`].
+	#[serde(default)]
+	pub message_index: Option<usize>,
+}
+
+/// A message flagged by [`Loom::audit`](crate::loom::Loom::audit).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ModerationHit {
+	/// Instance of the [`crate::TapestryFragment`] containing the message.
+	pub instance: u64,
+	/// Index of the message in [`crate::TapestryFragment::context_messages`].
+	pub index: usize,
+	/// Categories the message was flagged for by [`Hooks::moderate`](crate::Hooks::moderate).
+	pub categories: Vec<String>,
+}
+
+/// Attributes of a player character extracted by
+/// [`Loom::extract_character_sheets`](crate::loom::Loom::extract_character_sheets).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CharacterSheet {
+	pub name: String,
+	#[serde(default)]
+	pub traits: Vec<String>,
+	#[serde(default)]
+	pub inventory: Vec<String>,
+	/// Relationship to other characters, keyed by their name.
+	#[serde(default)]
+	pub relationships: BTreeMap<String, String>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum StorageError {
 	#[cfg(feature = "rocksdb")]
@@ -81,6 +449,9 @@ pub enum StorageError {
 	Parsing,
 	#[error("Not found")]
 	NotFound,
+	/// A concurrent write to the same tapestry was committed first, retrying may succeed.
+	#[error("Conflicting concurrent write")]
+	Conflict,
 	#[error("Failed to read instance count")]
 	FailedToReadInstanceCount,
 	#[error("Database error: {0}")]
@@ -100,6 +471,12 @@ pub struct VecPromptMsgsDeque<T: Config, L: Llm<T>> {
 	pub inner: VecDeque<<L as Llm<T>>::Request>,
 }
 
+impl<T: Config, L: Llm<T>> Default for VecPromptMsgsDeque<T, L> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
 impl<T: Config, L: Llm<T>> VecPromptMsgsDeque<T, L> {
 	pub fn new() -> Self {
 		Self { tokens: L::Tokens::from_u8(0).unwrap(), inner: VecDeque::new() }